@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+use memmap::Mmap;
+use tantivy::directory::error::{DeleteError, IOError, LockError, OpenReadError, OpenWriteError};
+use tantivy::directory::{DirectoryLock, Lock, ReadOnlySource, WatchCallback, WatchCallbackList, WatchHandle, INDEX_WRITER_LOCK};
+use tantivy::{Directory, TantivyError};
+use zip::CompressionMethod;
+use crate::only_read_directory::{read_only, BoxedData, ReleaseLockFile};
+
+/// 一个文件的数据在归档里的位置
+struct Entry {
+    offset: usize,
+    /// 在归档里占的字节数, 压缩过的是压缩后的大小
+    size: usize,
+    deflated: Option<Deflated>,
+}
+
+/// deflate 压缩过的文件
+struct Deflated {
+    /// 解压后的大小
+    size: usize,
+    crc: u32,
+    /// 还有 `ReadOnlySource` 在用的解压结果. 每个文件一把锁, 解压时只挡住同一个文件
+    inflated: Mutex<Weak<BoxedData>>,
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+/// 去掉 `./` 这样的前缀. 绝对路径和带 `..` 的路径会跑到索引目录外面, 返回 None
+fn enclosed(path: &Path) -> Option<PathBuf> {
+    let mut enclosed = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => enclosed.push(name),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(enclosed)
+}
+
+fn tar_entries(data: &[u8]) -> io::Result<Vec<(PathBuf, Entry)>> {
+    let mut archive = tar::Archive::new(data);
+    let mut entries = vec![];
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?;
+        let path = enclosed(&path)
+            .ok_or_else(|| invalid(format!("unsafe path {} in archive", path.display())))?;
+        entries.push((path, Entry {
+            offset: entry.raw_file_position() as usize,
+            size: entry.size() as usize,
+            deflated: None,
+        }));
+    }
+    Ok(entries)
+}
+
+fn zip_entries(data: &[u8]) -> io::Result<Vec<(PathBuf, Entry)>> {
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(data))?;
+    let mut entries = vec![];
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        if !file.is_file() {
+            continue;
+        }
+        let path = match file.enclosed_name().and_then(enclosed) {
+            Some(path) => path,
+            None => return Err(invalid(format!("unsafe path {} in archive", file.name()))),
+        };
+        let deflated = match file.compression() {
+            CompressionMethod::Stored => None,
+            CompressionMethod::Deflated => Some(Deflated {
+                size: file.size() as usize,
+                crc: file.crc32(),
+                inflated: Mutex::new(Weak::new()),
+            }),
+            method => return Err(invalid(format!("{} is compressed with {}, only stored and deflated are supported", file.name(), method))),
+        };
+        entries.push((path, Entry {
+            offset: file.data_start() as usize,
+            size: file.compressed_size() as usize,
+            deflated,
+        }));
+    }
+    Ok(entries)
+}
+
+/// 只读打开打包成 tar 或者 zip 的索引, 不用先解包.
+///
+/// 归档本身用 mmap 读, tar 和 zip 里没压缩的文件直接是 mmap 的一段;
+/// zip 里 deflate 压缩的文件第一次 `open_read` 时才解压, 还有 `ReadOnlySource` 在用时复用解压的结果.
+/// gzip 压缩的 tar 只能从头解压, 不支持.
+///
+/// 索引文件可以放在归档的根目录, 也可以放在子目录里 (比如 `cattrace-20261017/meta.json`),
+/// 以层级最浅的 meta.json 所在的目录为索引目录
+#[derive(Clone)]
+pub struct ArchiveDirectory {
+    archive_path: PathBuf,
+    data: Arc<BoxedData>,
+    entries: Arc<HashMap<PathBuf, Entry>>,
+}
+
+impl std::fmt::Debug for ArchiveDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ArchiveDirectory({:?})", self.archive_path)
+    }
+}
+
+impl ArchiveDirectory {
+    pub fn open(archive_path: &Path) -> io::Result<ArchiveDirectory> {
+        let file = File::open(archive_path)?;
+        if file.metadata()?.len() == 0 {
+            return Err(invalid("empty archive"));
+        }
+        // 归档是只读打开的, 假定没人在读的时候改它
+        let data: Arc<BoxedData> = Arc::new(Box::new(unsafe { Mmap::map(&file)? }));
+        let files = if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            zip_entries(&data)?
+        } else if data.get(257..262) == Some(b"ustar") {
+            tar_entries(&data)?
+        } else if data.starts_with(b"\x1f\x8b") {
+            return Err(invalid("gzip compressed archive is not supported, use an uncompressed tar or a zip"));
+        } else {
+            return Err(invalid("not a tar or zip archive"));
+        };
+
+        let root = files.iter()
+            .map(|(path, _)| path)
+            .filter(|path| path.file_name() == Some("meta.json".as_ref()))
+            .min_by_key(|path| path.components().count())
+            .and_then(|path| path.parent())
+            .ok_or_else(|| invalid("no meta.json in archive"))?
+            .to_owned();
+        let mut entries = HashMap::new();
+        for (path, entry) in files {
+            if entry.offset.checked_add(entry.size).is_none_or(|end| end > data.len()) {
+                return Err(invalid(format!("{} is truncated", path.display())));
+            }
+            if let Ok(relative) = path.strip_prefix(&root) {
+                entries.insert(relative.to_owned(), entry);
+            }
+        }
+        Ok(ArchiveDirectory {
+            archive_path: archive_path.to_owned(),
+            data,
+            entries: Arc::new(entries),
+        })
+    }
+
+    /// 解压 `path`, 校验大小和 crc32
+    fn inflate(&self, path: &Path, compressed: &[u8], deflated: &Deflated) -> Result<Arc<BoxedData>, OpenReadError> {
+        let io_error = |e| IOError::with_path(self.archive_path.join(path), e);
+        // 解压时一直持有这个文件的锁, 同一个文件不会被并发解压两次, 别的文件不受影响
+        let mut inflated = deflated.inflated.lock().expect("inflated data");
+        if let Some(data) = inflated.upgrade() {
+            return Ok(data);
+        }
+        let mut data = Vec::with_capacity(deflated.size);
+        DeflateDecoder::new(compressed).read_to_end(&mut data).map_err(io_error)?;
+        let mut check = Crc::new();
+        check.update(&data);
+        if data.len() != deflated.size || check.sum() != deflated.crc {
+            return Err(io_error(invalid("checksum mismatch")).into());
+        }
+        let data = Arc::new(Box::new(data) as BoxedData);
+        *inflated = Arc::downgrade(&data);
+        Ok(data)
+    }
+}
+
+impl Directory for ArchiveDirectory {
+    fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+        let entry = self.entries.get(path).ok_or_else(|| OpenReadError::FileDoesNotExist(path.to_owned()))?;
+        let source = ReadOnlySource::from(self.data.clone()).slice(entry.offset, entry.offset + entry.size);
+        match entry.deflated {
+            None => Ok(source),
+            Some(ref deflated) => Ok(ReadOnlySource::from(self.inflate(path, source.as_slice(), deflated)?)),
+        }
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        Err(IOError::with_path(path.to_owned(), read_only(path)).into())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.entries.contains_key(path)
+    }
+
+    fn open_write(&mut self, path: &Path) -> Result<BufWriter<Box<dyn Write>>, OpenWriteError> {
+        Err(IOError::with_path(path.to_owned(), read_only(path)).into())
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        Ok(self.open_read(path)?.as_slice().to_vec())
+    }
+
+    fn atomic_write(&mut self, path: &Path, _data: &[u8]) -> io::Result<()> {
+        Err(read_only(path))
+    }
+
+    /// 归档不会变, 除了写入锁之外都不用真的加锁
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        if lock.filepath == INDEX_WRITER_LOCK.filepath {
+            return Err(LockError::IOError(read_only(&lock.filepath)));
+        }
+        Ok(DirectoryLock::from(Box::new(ReleaseLockFile(None))))
+    }
+
+    /// 归档不会变, 回调永远不会被调用
+    fn watch(&self, watch_callback: WatchCallback) -> Result<WatchHandle, TantivyError> {
+        Ok(WatchCallbackList::default().subscribe(watch_callback))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::Count;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{Schema, INDEXED, STORED};
+    use tantivy::{doc, Index};
+
+    /// 把 `dir` 里的文件打包到 `prefix` 下, 第一个文件不压缩, 其余的用 deflate
+    fn zip_dir(dir: &Path, prefix: &str, archive: &Path) {
+        let mut writer = zip::ZipWriter::new(File::create(archive).unwrap());
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+        paths.sort();
+        for (i, path) in paths.iter().enumerate() {
+            let method = if i == 0 { CompressionMethod::Stored } else { CompressionMethod::Deflated };
+            let options = zip::write::FileOptions::default().compression_method(method);
+            let name = format!("{}{}", prefix, path.file_name().unwrap().to_str().unwrap());
+            writer.start_file(name, options).unwrap();
+            writer.write_all(&std::fs::read(path).unwrap()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_open_index_in_archive() {
+        let root = std::env::temp_dir().join(format!("archive-directory-{}", std::process::id()));
+        let index_dir = root.join("index");
+        std::fs::create_dir_all(&index_dir).unwrap();
+        let mut schema_builder = Schema::builder();
+        let time = schema_builder.add_u64_field("time", INDEXED | STORED);
+        let index = Index::create_in_dir(&index_dir, schema_builder.build()).unwrap();
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..10u64 {
+            index_writer.add_document(doc!(time => i));
+        }
+        index_writer.commit().unwrap();
+        drop(index_writer);
+
+        let tar_path = root.join("index.tar");
+        let mut builder = tar::Builder::new(File::create(&tar_path).unwrap());
+        builder.append_dir_all("cattrace-20261017", &index_dir).unwrap();
+        builder.into_inner().unwrap();
+        let zip_path = root.join("index.zip");
+        zip_dir(&index_dir, "", &zip_path);
+
+        for path in &[&tar_path, &zip_path] {
+            let mut directory = ArchiveDirectory::open(path).unwrap();
+            assert!(directory.exists(Path::new("meta.json")));
+            assert!(directory.open_write(Path::new("new")).is_err());
+            assert!(directory.acquire_lock(&INDEX_WRITER_LOCK).is_err());
+            let searcher = Index::open(directory).unwrap().reader().unwrap().searcher();
+            assert_eq!(searcher.search(&AllQuery, &Count).unwrap(), 10);
+            assert_eq!(searcher.doc(tantivy::DocAddress(0, 3)).unwrap().get_first(time).unwrap().u64_value(), 3);
+        }
+
+        // tar 里的文件就是归档 mmap 的一段
+        let directory = ArchiveDirectory::open(&tar_path).unwrap();
+        let meta = directory.open_read(Path::new("meta.json")).unwrap();
+        assert!(Arc::ptr_eq(&meta.data, &directory.data));
+        // zip 里压缩过的文件解压一次
+        let directory = ArchiveDirectory::open(&zip_path).unwrap();
+        let first = directory.open_read(Path::new("meta.json")).unwrap();
+        let second = directory.open_read(Path::new("meta.json")).unwrap();
+        assert!(Arc::ptr_eq(&first.data, &second.data));
+        assert_eq!(first.as_slice(), &std::fs::read(index_dir.join("meta.json")).unwrap()[..]);
+        // 并发打开时也只解压一次
+        drop((first, second));
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let directory = directory.clone();
+                std::thread::spawn(move || directory.open_read(Path::new("meta.json")).unwrap())
+            })
+            .collect();
+        let sources: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(sources.iter().all(|source| Arc::ptr_eq(&source.data, &sources[0].data)));
+
+        std::fs::write(root.join("index.tar.gz"), b"\x1f\x8b\x08\x00").unwrap();
+        assert!(ArchiveDirectory::open(&root.join("index.tar.gz")).is_err());
+        let empty = root.join("empty.zip");
+        zip::ZipWriter::new(File::create(&empty).unwrap()).finish().unwrap();
+        assert_eq!(ArchiveDirectory::open(&empty).unwrap_err().to_string(), "no meta.json in archive");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// 只有一个文件的 tar, 路径原样写进 header, 不经过 tar 自己的检查
+    fn tar_with_path(archive: &Path, path: &str) {
+        let mut header = tar::Header::new_ustar();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_size(2);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(File::create(archive).unwrap());
+        builder.append(&header, &b"{}"[..]).unwrap();
+        builder.into_inner().unwrap();
+    }
+
+    #[test]
+    fn test_unsafe_tar_paths() {
+        let root = std::env::temp_dir().join(format!("archive-directory-unsafe-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let archive = root.join("index.tar");
+        for path in &["../meta.json", "/meta.json", "index/../../meta.json"] {
+            tar_with_path(&archive, path);
+            assert_eq!(ArchiveDirectory::open(&archive).unwrap_err().to_string(), format!("unsafe path {} in archive", path));
+        }
+        tar_with_path(&archive, "./index/meta.json");
+        assert!(ArchiveDirectory::open(&archive).unwrap().exists(Path::new("meta.json")));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tantivy::directory::error::{DeleteError, LockError, OpenReadError, OpenWriteError};
+use tantivy::directory::{DirectoryLock, Lock, ReadOnlySource, WatchCallback, WatchHandle};
+use tantivy::{Directory, TantivyError};
+
+#[derive(Default)]
+struct Files {
+    /// 文件的数据和最近一次使用的 tick
+    files: HashMap<PathBuf, (ReadOnlySource, u64)>,
+    /// tick 到文件, 第一个就是最久没用过的
+    lru: BTreeMap<u64, PathBuf>,
+    bytes: usize,
+    tick: u64,
+}
+
+impl Files {
+    fn remove(&mut self, path: &Path) {
+        if let Some((source, used)) = self.files.remove(path) {
+            self.bytes -= source.len();
+            self.lru.remove(&used);
+        }
+    }
+}
+
+struct FileCache {
+    budget: usize,
+    files: Mutex<Files>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl FileCache {
+    fn get(&self, path: &Path) -> Option<ReadOnlySource> {
+        let mut guard = self.files.lock().expect("file cache");
+        let Files { files, lru, tick, .. } = &mut *guard;
+        *tick += 1;
+        let (source, used) = files.get_mut(path)?;
+        lru.remove(used);
+        lru.insert(*tick, path.to_owned());
+        *used = *tick;
+        Some(source.clone())
+    }
+
+    fn insert(&self, path: &Path, source: ReadOnlySource) {
+        let mut files = self.files.lock().expect("file cache");
+        // 并发打开同一个文件时可能各读一次, 只留后放进来的
+        files.remove(path);
+        files.tick += 1;
+        let tick = files.tick;
+        files.bytes += source.len();
+        files.lru.insert(tick, path.to_owned());
+        files.files.insert(path.to_owned(), (source, tick));
+        while files.bytes > self.budget {
+            let (_, oldest) = files.lru.pop_first().expect("cache over budget is not empty");
+            files.remove(&oldest);
+        }
+    }
+
+    /// 文件被删掉或者重写时丢掉它
+    fn invalidate(&self, path: &Path) {
+        self.files.lock().expect("file cache").remove(path);
+    }
+}
+
+/// 文件缓存的命中情况, 按 `open_read` 计数
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// 当前缓存的字节数
+    pub bytes: usize,
+}
+
+/// 包一层任意的 `Directory`, `open_read` 读到的整个文件放进进程内的 LRU 缓存, 缓存总共最多 `budget` 字节.
+/// 适合放在慢盘或者网络文件系统上的索引.
+///
+/// tantivy 0.10 的 `ReadOnlySource` 必须是整个文件的一段连续内存, 所以按整个文件缓存, 不按块.
+/// 没缓存时从底层目录读出来拷一份放进缓存, 之后再打开同一个文件 (比如 reload 时没变的 segment)
+/// 直接共用这份数据, 不再碰底层目录. 比 `budget` 还大的文件直接交给底层目录, 只算 miss.
+/// 其他操作都转给底层目录, 写入和删除时丢掉对应的文件
+#[derive(Clone)]
+pub struct CachingDirectory<D: Directory> {
+    inner: D,
+    cache: Arc<FileCache>,
+}
+
+impl<D: Directory> std::fmt::Debug for CachingDirectory<D> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "CachingDirectory({:?})", self.inner)
+    }
+}
+
+impl<D: Directory> CachingDirectory<D> {
+    pub fn new(inner: D, budget: usize) -> Self {
+        CachingDirectory {
+            inner,
+            cache: Arc::new(FileCache {
+                budget,
+                files: Mutex::new(Files::default()),
+                hits: AtomicU64::new(0),
+                misses: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// 所有 clone 共用一个缓存, 统计也是一起的
+    pub fn stats(&self) -> FileCacheStats {
+        FileCacheStats {
+            hits: self.cache.hits.load(Ordering::Relaxed),
+            misses: self.cache.misses.load(Ordering::Relaxed),
+            bytes: self.cache.files.lock().expect("file cache").bytes,
+        }
+    }
+}
+
+impl<D: Directory + Clone> Directory for CachingDirectory<D> {
+    fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
+        if let Some(source) = self.cache.get(path) {
+            self.cache.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(source);
+        }
+        self.cache.misses.fetch_add(1, Ordering::Relaxed);
+        let source = self.inner.open_read(path)?;
+        if source.len() > self.cache.budget {
+            return Ok(source);
+        }
+        // 底层可能是 mmap, 拷一份才能不再从盘上读
+        let source = ReadOnlySource::from(source.as_slice().to_vec());
+        self.cache.insert(path, source.clone());
+        Ok(source)
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        self.cache.invalidate(path);
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn open_write(&mut self, path: &Path) -> Result<BufWriter<Box<dyn Write>>, OpenWriteError> {
+        self.cache.invalidate(path);
+        self.inner.open_write(path)
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        self.inner.atomic_read(path)
+    }
+
+    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
+        self.cache.invalidate(path);
+        self.inner.atomic_write(path, data)
+    }
+
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        self.inner.acquire_lock(lock)
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> Result<WatchHandle, TantivyError> {
+        self.inner.watch(watch_callback)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::directory::RAMDirectory;
+
+    fn write(directory: &mut CachingDirectory<RAMDirectory>, path: &str, data: &[u8]) {
+        let mut file = directory.open_write(Path::new(path)).unwrap();
+        file.write_all(data).unwrap();
+        file.flush().unwrap();
+    }
+
+    fn read(directory: &CachingDirectory<RAMDirectory>, path: &str) -> Vec<u8> {
+        directory.open_read(Path::new(path)).unwrap().as_slice().to_vec()
+    }
+
+    fn stats(hits: u64, misses: u64, bytes: usize) -> FileCacheStats {
+        FileCacheStats { hits, misses, bytes }
+    }
+
+    #[test]
+    fn test_files_are_cached_and_evicted() {
+        let mut directory = CachingDirectory::new(RAMDirectory::create(), 100);
+        let a: Vec<u8> = (0..60).collect();
+        write(&mut directory, "a", &a);
+        write(&mut directory, "b", &[1; 30]);
+        write(&mut directory, "c", &[2; 30]);
+        write(&mut directory, "huge", &[0; 101]);
+
+        assert_eq!(read(&directory, "a"), a);
+        assert_eq!(directory.stats(), stats(0, 1, 60));
+        // 命中时共用缓存里的数据
+        let first = directory.open_read(Path::new("a")).unwrap();
+        let second = directory.open_read(Path::new("a")).unwrap();
+        assert_eq!(first.as_slice().as_ptr(), second.as_slice().as_ptr());
+        assert_eq!(directory.stats(), stats(2, 1, 60));
+
+        // 比预算大的文件不进缓存
+        assert_eq!(read(&directory, "huge").len(), 101);
+        assert_eq!(directory.stats(), stats(2, 2, 60));
+
+        // 放不下时淘汰最久没用过的 b, 而不是刚用过的 a
+        assert_eq!(read(&directory, "b"), vec![1; 30]);
+        assert_eq!(read(&directory, "a"), a);
+        assert_eq!(read(&directory, "c"), vec![2; 30]);
+        assert_eq!(directory.stats(), stats(3, 4, 90));
+        assert_eq!(read(&directory, "a"), a);
+        assert_eq!(read(&directory, "b"), vec![1; 30]);
+        assert_eq!(directory.stats(), stats(4, 5, 90));
+
+        // 重写的文件读到的是新内容
+        directory.atomic_write(Path::new("b"), b"changed").unwrap();
+        assert_eq!(read(&directory, "b"), b"changed");
+        assert_eq!(directory.stats(), stats(4, 6, 67));
+        assert!(matches!(directory.open_read(Path::new("missing")), Err(OpenReadError::FileDoesNotExist(_))));
+    }
+}
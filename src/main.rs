@@ -1,89 +1,378 @@
-use std::fs::File;
-use tantivy::schema::Schema;
-use tantivy::{Index, Directory, TantivyError, Searcher};
-use crate::only_read_directory::OnlyReadDirectory;
-use tantivy::directory::MmapDirectory;
-use tantivy::query::{TermQuery, Query};
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use serde_json::{json, Map, Value};
 use tantivy::collector::Count;
-use tantivy::schema::*;
-use tantivy::query::*;
-use tantivy::*;
-use tantivy::collector::*;
-use crate::query::CatQuery;
+use tantivy::{DocAddress, Directory, Index, Searcher, TantivyError};
+use crate::archive_directory::ArchiveDirectory;
+use crate::caching_directory::CachingDirectory;
+use crate::only_read_directory::OnlyReadDirectory;
+use crate::query_parser::QueryParseError;
+use crate::search_request::SearchRequest;
+use crate::query_builder::TimeRangePolicy;
+use crate::replay::replay;
 
+mod archive_directory;
+mod caching_directory;
 mod only_read_directory;
 
 mod query_builder;
 mod query_parser;
 mod query;
-fn schema_from_file(schema_path: String) {
-    let file_path:&str = &schema_path;
-   let schema = std::fs::read_to_string(schema_path.clone()).expect(&format!("read {} file error  from file", file_path));
-    serde_json::from_str(&schema).expect(&format!("from {} parse schema expect", file_path))
-}
-
-fn query_count(searcher: &Searcher, query: &dyn Query) -> usize {
-    searcher.search(query, &Count).expect("search")
-}
-fn query_all(searcher: &Searcher, query: &dyn Query, schema: &Schema, sort_field: &str ) -> (Vec<(u64, String)>,usize) {
-    let mut collectors = MultiCollector::new();
-    let top_collector = TopDocs::with_limit(400).order_by_u64_field(schema.get_field(sort_field).expect("????"));
-    let topdocs_handler = collectors.add_collector(top_collector);
-    let count_handler = collectors.add_collector(Count);
-    println!("query: {:?}", query);
-    let mut multifruits = searcher.search(query, &mut collectors).expect("search");
-    let top_docs = topdocs_handler.extract(&mut multifruits);
-    let count = count_handler.extract(&mut multifruits);
-    let mut v = vec![];
-    //通过文档地址查询文档
-    for (feature, doc_address) in top_docs {
-        if let Ok(doc) = searcher.doc(doc_address) {
-            v.push((feature, schema.to_json(&doc).to_string()));
+mod query_dsl;
+mod replay;
+mod search_request;
+
+const USAGE: &str = "usage:
+    search_query search <index> [--query <file>|-] [--size <n>] [--from <n>] [--sort <field>[:asc|desc],...] [--format pretty|json|ndjson]
+    search_query count <index> [--query <file>|-]
+    search_query explain <index> <doc>|<segment>:<doc> [--query <file>|-]
+    search_query schema <index>
+    search_query stats <index>
+    search_query replay <index> <jsonl>|- [--threads <n>] [--block-cache <MiB>]
+    search_query locks <index>
+
+<index> 是索引目录, 也可以是 tar 或者 zip 打包的索引 (不用解包).
+<file> 是 `_search` 的请求体, `-` 从标准输入读, 不指定时匹配所有文档.
+replay (也可以写成 bench) 逐行执行 <jsonl> 里的请求, 每行输出命中数和耗时, 最后一行是耗时的分位数.
+--block-cache 在进程内按 LRU 缓存读过的整个索引文件, 最多占 <MiB>, reload 时没变的 segment 不用再从盘上读, summary 里带上命中数.
+locks 列出锁文件是 absent (没有锁文件), held (有进程拿着) 还是 unlocked (有锁文件但没人拿着, tantivy 不会删锁文件, 这是正常的)";
+
+enum CliError {
+    /// 命令的用法不对, 会带上 `USAGE`
+    Usage(String),
+    /// 参数的值不对
+    Argument(String),
+    Io(String, std::io::Error),
+    Index(TantivyError),
+    Parse(QueryParseError),
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}\n\n{}", message, USAGE),
+            CliError::Argument(message) => write!(f, "{}", message),
+            CliError::Io(path, e) => write!(f, "{}: {}", path, e),
+            CliError::Index(e) => write!(f, "{}", e),
+            CliError::Parse(e) => write!(f, "parse request: {}", e),
+        }
+    }
+}
+
+impl From<TantivyError> for CliError {
+    fn from(e: TantivyError) -> Self {
+        CliError::Index(e)
+    }
+}
+
+impl From<QueryParseError> for CliError {
+    fn from(e: QueryParseError) -> Self {
+        CliError::Parse(e)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Pretty,
+    Json,
+    /// 每个 hit 一行, 最后一行是 total 和 took
+    NdJson,
+}
+
+/// 命令行里除了子命令和位置参数之外的选项
+#[derive(Default)]
+struct Options {
+    query: Option<String>,
+    size: Option<usize>,
+    from: Option<usize>,
+    sort: Option<String>,
+    format: Option<Format>,
+    threads: Option<usize>,
+    /// 块缓存的大小, 单位 MiB
+    block_cache: Option<usize>,
+}
+
+impl Options {
+    fn parse(args: &mut Vec<String>, allowed: &[&str]) -> Result<Options, CliError> {
+        let mut options = Options::default();
+        let mut positional = vec![];
+        let mut iter = args.drain(..);
+        while let Some(arg) = iter.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => {
+                    positional.push(arg);
+                    continue;
+                }
+            };
+            if !allowed.contains(&name.as_str()) {
+                return Err(CliError::Usage(format!("unknown option --{}", name)));
+            }
+            let value = iter.next().ok_or_else(|| CliError::Usage(format!("--{} needs a value", name)))?;
+            match name.as_str() {
+                "query" => options.query = Some(value),
+                "size" => options.size = Some(number(&name, &value)?),
+                "from" => options.from = Some(number(&name, &value)?),
+                "sort" => options.sort = Some(value),
+                "threads" => options.threads = Some(number(&name, &value)?),
+                "block-cache" => options.block_cache = Some(number(&name, &value)?),
+                _ => options.format = Some(match value.as_str() {
+                    "pretty" => Format::Pretty,
+                    "json" => Format::Json,
+                    "ndjson" => Format::NdJson,
+                    _ => return Err(CliError::Argument(format!("unknown format `{}`", value))),
+                }),
+            }
+        }
+        drop(iter);
+        *args = positional;
+        Ok(options)
+    }
+
+    /// 读出请求体, 再用命令行里的 size/from/sort 覆盖
+    fn body(&self) -> Result<String, CliError> {
+        let body = match self.query.as_deref() {
+            None => "{}".to_string(),
+            Some("-") => {
+                let mut body = String::new();
+                std::io::stdin().read_to_string(&mut body).map_err(|e| CliError::Io("read stdin".to_string(), e))?;
+                body
+            }
+            Some(path) => std::fs::read_to_string(path).map_err(|e| CliError::Io(format!("read {}", path), e))?,
+        };
+        if self.size.is_none() && self.from.is_none() && self.sort.is_none() {
+            return Ok(body);
+        }
+        // 请求体本身的错误留给 SearchRequest::parse 报, 它会带上出错的位置
+        let mut params = match serde_json::from_str::<Value>(&body) {
+            Ok(Value::Object(params)) => params,
+            _ => return Ok(body),
+        };
+        if let Some(size) = self.size {
+            params.insert("size".to_string(), json!(size));
+        }
+        if let Some(from) = self.from {
+            params.insert("from".to_string(), json!(from));
+        }
+        if let Some(ref sort) = self.sort {
+            params.insert("sort".to_string(), sort_param(sort));
+        }
+        Ok(Value::Object(params).to_string())
+    }
+}
+
+/// 输出被关掉 (比如接了 `head`) 时不算错误
+fn print<T: fmt::Display>(output: T) -> Result<(), CliError> {
+    let mut stdout = std::io::stdout().lock();
+    match writeln!(stdout, "{}", output) {
+        Err(ref e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => result.map_err(|e| CliError::Io("write stdout".to_string(), e)),
+    }
+}
+
+fn number(name: &str, value: &str) -> Result<usize, CliError> {
+    value.parse().map_err(|_| CliError::Argument(format!("--{} expects a non-negative integer, got `{}`", name, value)))
+}
+
+/// `time:desc,_score` 写成 `[{"time": "desc"}, "_score"]`
+fn sort_param(sort: &str) -> Value {
+    sort.split(',')
+        .filter(|s| !s.is_empty())
+        .map(|s| match s.split_once(':') {
+            Some((field, order)) => {
+                let mut param = Map::new();
+                param.insert(field.to_string(), Value::from(order));
+                Value::Object(param)
+            }
+            None => Value::from(s),
+        })
+        .collect()
+}
+
+fn open_directory(path: &str) -> Result<OnlyReadDirectory, CliError> {
+    let path = PathBuf::from(path);
+    if !path.join("meta.json").is_file() {
+        return Err(CliError::Argument(format!("{} is not an index directory (no meta.json) or archive", path.display())));
+    }
+    Ok(OnlyReadDirectory::new(path, true))
+}
+
+fn open_archive(path: &Path) -> Result<ArchiveDirectory, CliError> {
+    ArchiveDirectory::open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::InvalidData => CliError::Argument(format!("{}: {}", path.display(), e)),
+        _ => CliError::Io(format!("open {}", path.display()), e),
+    })
+}
+
+fn parse_request(index: &Index, options: &Options) -> Result<SearchRequest, CliError> {
+    Ok(SearchRequest::parse(&options.body()?, index, Rc::new(TimeRangePolicy::default()), None)?)
+}
+
+fn search(index: &Index, options: &Options) -> Result<(), CliError> {
+    let request = parse_request(index, options)?;
+    let response = request.execute(&index.reader()?.searcher())?;
+    let response = response.to_json();
+    match options.format.unwrap_or(Format::Pretty) {
+        Format::Pretty => print(serde_json::to_string_pretty(&response).expect("json"))?,
+        Format::Json => print(response)?,
+        Format::NdJson => {
+            if let Some(hits) = response["hits"]["hits"].as_array() {
+                for hit in hits {
+                    print(hit)?;
+                }
+            }
+            print(json!({ "took": response["took"], "total": response["hits"]["total"] }))?;
+        }
+    }
+    Ok(())
+}
+
+fn count(index: &Index, options: &Options) -> Result<(), CliError> {
+    let request = parse_request(index, options)?;
+    print(index.reader()?.searcher().search(request.query.as_ref(), &Count)?)?;
+    Ok(())
+}
+
+/// `<doc>` 是第 0 个 segment 里的文档, 也可以写成 `<segment>:<doc>`
+fn doc_address(doc: &str, searcher: &Searcher) -> Result<DocAddress, CliError> {
+    let invalid = || CliError::Argument(format!("invalid doc `{}`", doc));
+    let (segment, doc) = match doc.split_once(':') {
+        Some((segment, doc)) => (segment.parse().map_err(|_| invalid())?, doc.parse().map_err(|_| invalid())?),
+        None => (0, doc.parse().map_err(|_| invalid())?),
+    };
+    match searcher.segment_readers().get(segment as usize) {
+        Some(reader) if doc < reader.max_doc() => Ok(DocAddress(segment, doc)),
+        Some(reader) => Err(CliError::Argument(format!("segment {} has {} docs, no doc {}", segment, reader.max_doc(), doc))),
+        None => Err(CliError::Argument(format!("index has {} segments, no segment {}", searcher.segment_readers().len(), segment))),
+    }
+}
+
+fn explain(index: &Index, doc: &str, options: &Options) -> Result<(), CliError> {
+    let request = parse_request(index, options)?;
+    let searcher = index.reader()?.searcher();
+    let explanation = request.query.explain(&searcher, doc_address(doc, &searcher)?)?;
+    print(explanation.to_pretty_json())?;
+    Ok(())
+}
+
+fn schema(index: &Index) -> Result<(), CliError> {
+    print(serde_json::to_string_pretty(&index.schema()).expect("json"))?;
+    Ok(())
+}
+
+fn stats(index: &Index) -> Result<(), CliError> {
+    let searcher = index.reader()?.searcher();
+    let segments: Vec<Value> = searcher.segment_readers().iter()
+        .map(|reader| json!({
+            "id": reader.segment_id().short_uuid_string(),
+            "max_doc": reader.max_doc(),
+            "docs": reader.num_docs(),
+            "deleted": reader.num_deleted_docs(),
+        }))
+        .collect();
+    let sum = |key: &str| segments.iter().filter_map(|s| s[key].as_u64()).sum::<u64>();
+    let stats = json!({
+        "segments": segments.len(),
+        "max_doc": sum("max_doc"),
+        "docs": sum("docs"),
+        "deleted": sum("deleted"),
+        "segment_stats": segments,
+    });
+    print(serde_json::to_string_pretty(&stats).expect("json"))?;
+    Ok(())
+}
+
+fn replay_file<D: Directory + Clone>(index: &Index, path: &str, options: &Options, cache: Option<&CachingDirectory<D>>) -> Result<(), CliError> {
+    let reader = index.reader()?;
+    let threads = options.threads.unwrap_or(1);
+    let report = if path == "-" {
+        replay(index, &reader, std::io::BufReader::new(std::io::stdin()), threads)
+    } else {
+        let file = std::fs::File::open(path).map_err(|e| CliError::Io(format!("read {}", path), e))?;
+        replay(index, &reader, std::io::BufReader::new(file), threads)
+    };
+    for outcome in &report.outcomes {
+        print(outcome.to_json())?;
+    }
+    let mut summary = report.summary();
+    if let Some(cache) = cache {
+        let stats = cache.stats();
+        summary["block_cache"] = json!({ "hits": stats.hits, "misses": stats.misses, "bytes": stats.bytes });
+    }
+    print(json!({ "summary": summary }))
+}
+
+fn locks(directory: &OnlyReadDirectory) -> Result<(), CliError> {
+    let states = directory.lock_states().map_err(|e| CliError::Io("check locks".to_string(), e))?;
+    let locks: Vec<Value> = states.iter()
+        .map(|(path, state)| json!({ "file": path.display().to_string(), "state": state.name() }))
+        .collect();
+    print(serde_json::to_string_pretty(&locks).expect("json"))
+}
+
+fn run(mut args: Vec<String>) -> Result<(), CliError> {
+    if args.is_empty() {
+        return Err(CliError::Usage("missing command".to_string()));
+    }
+    let command = args.remove(0);
+    let allowed: &[&str] = match command.as_str() {
+        "search" => &["query", "size", "from", "sort", "format"],
+        "count" | "explain" => &["query"],
+        "schema" | "stats" | "locks" => &[],
+        "replay" | "bench" => &["threads", "block-cache"],
+        "help" | "--help" | "-h" => {
+            print(USAGE)?;
+            return Ok(());
         }
+        _ => return Err(CliError::Usage(format!("unknown command `{}`", command))),
+    };
+    let options = Options::parse(&mut args, allowed)?;
+    let expected = match command.as_str() {
+        "explain" | "replay" | "bench" => 2,
+        _ => 1,
+    };
+    if args.len() != expected {
+        return Err(CliError::Usage(format!("`{}` takes {} argument(s), got {}", command, expected, args.len())));
+    }
+    let path = Path::new(&args[0]);
+    if path.is_file() {
+        if command == "locks" {
+            return Err(CliError::Argument(format!("{} is an archive, it has no lock files", path.display())));
+        }
+        return run_index(&command, open_archive(path)?, &args, &options);
+    }
+    let directory = open_directory(&args[0])?;
+    if command == "locks" {
+        return locks(&directory);
+    }
+    run_index(&command, directory, &args, &options)
+}
+
+/// 打开索引之后的命令
+fn run_index<D: Directory + Clone>(command: &str, directory: D, args: &[String], options: &Options) -> Result<(), CliError> {
+    let cache = options.block_cache.map(|mib| CachingDirectory::new(directory.clone(), mib.saturating_mul(1024 * 1024)));
+    let index = match cache {
+        Some(ref cache) => Index::open(cache.clone())?,
+        None => Index::open(directory)?,
+    };
+    match command {
+        "search" => search(&index, options),
+        "count" => count(&index, options),
+        "explain" => explain(&index, &args[1], options),
+        "schema" => schema(&index),
+        "replay" | "bench" => replay_file(&index, &args[1], options, cache.as_ref()),
+        _ => stats(&index),
+    }
+}
+
+fn main() {
+    if let Err(e) = run(std::env::args().skip(1).collect()) {
+        eprintln!("error: {}", e);
+        std::process::exit(match e {
+            CliError::Usage(_) | CliError::Argument(_) => 2,
+            _ => 1,
+        });
     }
-    (v, count)
-}
-fn read_dir(dir_name : &String) {
-    let path = std::path::PathBuf::from(dir_name);
-    let dir = OnlyReadDirectory::new(path);
-//    let dir = MmapDirectory::open(path).expect("open error");
-    let index = Index::open(dir).expect("open dir error");
-    let schema = index.schema();
-    let reader = index.reader().expect("reader");
-    let query =
-        std::fs::read_to_string("./query.json").expect("error parsing config from file");
-    let query = query_parser::parse(query, schema.clone(), 10000);
-//    let query = r#"{
-//	"query": {
-//		"bool": {
-//			"filter": [{
-//				"term": {
-//					"status": {
-//						"value": "0",
-//						"boost": 1
-//					}
-//				}
-//			}, {
-//				"term": {
-//					"timeUnit": {
-//						"value": 20000,
-//						"boost": 1
-//					}
-//				}
-//			}],
-//			"adjust_pure_negative": true,
-//			"boost": 1
-//		}
-//	}
-//}"#;
-//    let query = query_parser::parse(query.to_string(), schema.clone());
-//    let query = CatQuery::new(query, schema.get_field("time").expect("field time"), 78356886, 78366880, 100000);
-    let searcher = reader.searcher();
-    let time = std::time::SystemTime::now();
-    let result = query_all(&searcher, &query, &schema, "time");
-    println!("{:?}, time:{:?}", result.1, std::time::SystemTime::now().duration_since(time).expect("time"));
-}
-
-fn  main() {
-    read_dir(&"./cattrace-20190830/0".to_string());
 }
@@ -1,107 +1,131 @@
 use std::path::{PathBuf, Path};
-use tantivy::directory::{WatchHandle, WatchCallback, ReadOnlySource, MmapDirectory, DirectoryLock, Lock};
+use tantivy::directory::{WatchHandle, WatchCallback, ReadOnlySource, DirectoryLock, Lock, INDEX_WRITER_LOCK, META_LOCK};
 use tantivy::{TantivyError, Directory};
 use tantivy::directory::error::{OpenReadError, DeleteError, OpenWriteError, IOError, LockError};
 use std::io::{BufWriter, Write, Read};
-use std::fs::{File, OpenOptions};
+use std::fs::File;
 use std::io;
-use tantivy::directory::error::OpenDirectoryError::IoError;
+use std::collections::HashMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use fs2::FileExt;
-use std::sync::Arc;
-use std::sync::RwLock;
+use memmap::Mmap;
+use std::sync::{Arc, Mutex, Weak};
+use tantivy::directory::WatchCallbackList;
+
+/// 多久检查一次 meta.json
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+pub(crate) type BoxedData = Box<dyn std::ops::Deref<Target = [u8]> + Send + Sync + 'static>;
+type MmapCache = Mutex<HashMap<PathBuf, Weak<BoxedData>>>;
+
+/// 只读打开一个索引目录, 文件用 mmap 读, 不会修改目录里的任何文件.
+///
+/// 开启 mmap 缓存时, 同一个文件还有 `ReadOnlySource` 在用的话会复用同一个 mmap.
+/// 要在进程内缓存读过的文件, 在外面包一层 `CachingDirectory`
 #[derive(Clone)]
 pub struct OnlyReadDirectory {
     root_path: PathBuf,
-    #[cfg(test)]
-    test: MmapDirectory,
-    watch_router: Arc<RwLock<tantivy::directory::WatchCallbackList>>,
+    mmap_cache: Option<Arc<MmapCache>>,
+    watch_router: Arc<WatchCallbackList>,
+    /// 第一次 watch 时才启动, 所有 clone 都 drop 之后停掉
+    watcher: Arc<Mutex<Option<MetaWatcher>>>,
 }
 impl std::fmt::Debug for OnlyReadDirectory {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "OnlyReadDirectory")
+        write!(f, "OnlyReadDirectory({:?})", self.root_path)
+    }
+}
+
+/// 写操作统一返回的错误
+pub(crate) fn read_only(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, format!("{:?} is in a read only directory", path))
+}
+
+/// 空文件不能 mmap, 返回 None
+fn open_mmap(full_path: &Path) -> Result<Option<Mmap>, OpenReadError> {
+    let file = File::open(full_path).map_err(|e| {
+        if e.kind() == io::ErrorKind::NotFound {
+            OpenReadError::FileDoesNotExist(full_path.to_owned())
+        } else {
+            OpenReadError::IOError(IOError::with_path(full_path.to_owned(), e))
+        }
+    })?;
+    let meta_data = file.metadata().map_err(|e| IOError::with_path(full_path.to_owned(), e))?;
+    if meta_data.len() == 0 {
+        return Ok(None);
+    }
+    // 目录是只读的, 不会有人在 mmap 期间改这个文件
+    unsafe {
+        Mmap::map(&file)
+            .map(Some)
+            .map_err(|e| IOError::with_path(full_path.to_owned(), e).into())
     }
 }
 
 impl OnlyReadDirectory {
-    pub fn new(root_path: PathBuf) -> Self {
+    pub fn new(root_path: PathBuf, mmap_cache: bool) -> Self {
         OnlyReadDirectory {
-            root_path: root_path.clone(),
-            #[cfg(test)]
-            test: MmapDirectory::open(root_path).expect("open dir"),
-            watch_router: Arc::new(RwLock::new(Default::default()))
+            root_path,
+            mmap_cache: if mmap_cache { Some(Arc::new(Mutex::new(HashMap::new()))) } else { None },
+            watch_router: Arc::new(Default::default()),
+            watcher: Arc::new(Mutex::new(None)),
         }
     }
     pub fn resolve_path(&self, relative_path: &Path) -> PathBuf {
         self.root_path.join(relative_path)
     }
-    fn sync_directory(&self) -> Result<(), io::Error> {
-        let mut open_opts = OpenOptions::new();
-        open_opts.read(true);
-        #[cfg(windows)]
-            {
-                panic!("并没有支持window")
-            }
-        let fd = open_opts.open(&self.root_path)?;
-        fd.sync_all()?;
-        Ok(())
-    }
-}
-struct SafeFileWriter(File);
-impl SafeFileWriter {
-    fn new(file:File) -> SafeFileWriter {
-        SafeFileWriter(file)
-    }
-}
-impl Write for SafeFileWriter {
-    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.0.write(buf)
+    /// 写入锁和 meta 锁的状态. 试着拿一下排它锁, 拿到了马上释放.
+    /// 锁文件只说明有写入方打开过这个索引, tantivy 用完锁不会删文件, 所以没人拿着的锁文件是正常的
+    pub fn lock_states(&self) -> Result<Vec<(PathBuf, LockState)>, io::Error> {
+        [&*INDEX_WRITER_LOCK, &*META_LOCK].iter()
+            .map(|lock| {
+                let state = match File::open(self.resolve_path(&lock.filepath)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => LockState::Absent,
+                    Err(e) => return Err(e),
+                    Ok(file) => match file.try_lock_exclusive() {
+                        Ok(()) => LockState::Unlocked,
+                        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => LockState::Held,
+                        Err(e) => return Err(e),
+                    },
+                };
+                Ok((lock.filepath.clone(), state))
+            })
+            .collect()
     }
-
-    fn flush(&mut self) -> std::io::Result<()> {
-        self.0.flush()?;
-        self.0.sync_all()
+    fn mmap(&self, full_path: &Path) -> Result<Option<Arc<BoxedData>>, OpenReadError> {
+        let cache = match self.mmap_cache {
+            Some(ref cache) => cache,
+            None => return Ok(open_mmap(full_path)?.map(|mmap| Arc::new(Box::new(mmap) as BoxedData))),
+        };
+        let mut cache = cache.lock().expect("mmap cache");
+        if let Some(data) = cache.get(full_path).and_then(Weak::upgrade) {
+            return Ok(Some(data));
+        }
+        // 顺便清掉已经没人用的 mmap
+        cache.retain(|_, data| data.strong_count() > 0);
+        let data = match open_mmap(full_path)? {
+            Some(mmap) => Arc::new(Box::new(mmap) as BoxedData),
+            None => return Ok(None),
+        };
+        cache.insert(full_path.to_owned(), Arc::downgrade(&data));
+        Ok(Some(data))
     }
 }
 impl Directory for OnlyReadDirectory {
     fn open_read(&self, path: &Path) -> Result<ReadOnlySource, OpenReadError> {
         let full_path = self.resolve_path(path);
-        let mut file = File::open(full_path.clone()).map_err(|e| {
-            if e.kind() == io::ErrorKind::NotFound {
-                OpenReadError::FileDoesNotExist(full_path.to_owned())
-            } else {
-                OpenReadError::IOError(IOError::with_path(full_path.to_owned(), e))
-            }
-        })?;
-        let meta_data= file.metadata().map_err(|e| {
-            IOError::with_path(full_path.to_owned(), e)
-        })?;
-        if meta_data.len() == 0 {
-            return Ok(ReadOnlySource::empty());
-        }
-        let len = meta_data.len();
-        let mut data= (0..len)
-            .map(|_| 0)
-            .collect::<Vec<u8>>();
-        let result = file.read(&mut data).expect("result");
-        #[cfg(test)]
-        assert_eq!(self.test.open_read(path).expect("a").data.len(), data.len()); 
-        Ok(ReadOnlySource::new(data))
-
+        Ok(match self.mmap(&full_path)? {
+            Some(data) => ReadOnlySource::from(data),
+            None => ReadOnlySource::empty(),
+        })
     }
 
     fn delete(&self, path: &Path) -> Result<(), DeleteError> {
-        let full_path = self.resolve_path(path);
-        match std::fs::remove_file(&full_path) {
-            Ok(_) => self.sync_directory().map_err(|e| IOError::with_path(path.to_owned(), e).into()),
-            Err(e) => {
-                if e.kind() == io::ErrorKind::NotFound {
-                    Err(DeleteError::FileDoesNotExist(path.to_owned()))
-                } else {
-                    Err(IOError::with_path(path.to_owned(), e).into())
-                }
-            }
-
-        }
+        Err(IOError::with_path(path.to_owned(), read_only(path)).into())
     }
 
     fn exists(&self, path: &Path) -> bool {
@@ -109,20 +133,8 @@ impl Directory for OnlyReadDirectory {
         full_path.exists()
     }
 
-    fn open_write(&mut self, path: &Path) -> Result<BufWriter<Box<Write>>, OpenWriteError> {
-        let full_path = self.resolve_path(path);
-        let open_res = OpenOptions::new().write(true).create_new(true).open(full_path);
-        let mut file = open_res.map_err(|err| {
-            if err.kind() == io::ErrorKind::AlreadyExists {
-                OpenWriteError::FileAlreadyExists(path.to_owned())
-            } else {
-                IOError::with_path(path.to_owned(), err).into()
-            }
-        })?;
-        file.flush().map_err(|e| IOError::with_path(path.to_owned(), e))?;
-        self.sync_directory().map_err(|e| IOError::with_path(path.to_owned(), e))?;
-        let writer = SafeFileWriter::new(file);
-        Ok(BufWriter::new(Box::new(writer)))
+    fn open_write(&mut self, path: &Path) -> Result<BufWriter<Box<dyn Write>>, OpenWriteError> {
+        Err(IOError::with_path(path.to_owned(), read_only(path)).into())
     }
 
     fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
@@ -141,40 +153,237 @@ impl Directory for OnlyReadDirectory {
                 }
             }
         }
-//        unimplemented!()
     }
 
-    fn atomic_write(&mut self, path: &Path, data: &[u8]) -> std::io::Result<()> {
-        let full_path = self.resolve_path(path);
-        let meta_file = atomicwrites::AtomicFile::new(full_path, atomicwrites::AllowOverwrite);
-        meta_file.write(|f| f.write_all(data))?;
-        Ok(())
+    fn atomic_write(&mut self, path: &Path, _data: &[u8]) -> std::io::Result<()> {
+        Err(read_only(path))
     }
+    /// 只读的一方用共享锁, 不会挡住别的读的一方. 写入锁只能由写入方拿.
+    ///
+    /// 锁文件不存在时返回错误, 不替写入方创建, 也不悄悄地不加锁: 不加锁的话写入方
+    /// 可能在这边打开 segment 的过程中删掉文件. tantivy 写过的索引都有锁文件, 缺了的话可以手动建一个空文件
     fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        if lock.filepath == INDEX_WRITER_LOCK.filepath {
+            return Err(LockError::IOError(read_only(&lock.filepath)));
+        }
         let full_path = self.resolve_path(&lock.filepath);
-        let file: File = OpenOptions::new().write(true).create(true).open(&full_path).map_err(LockError::IOError)?;
+        let file = match File::open(&full_path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let message = format!("lock file {:?} does not exist and a read only directory can not create it", full_path);
+                return Err(LockError::IOError(io::Error::new(io::ErrorKind::NotFound, message)));
+            }
+            Err(e) => return Err(LockError::IOError(e)),
+        };
         if lock.is_blocking {
-            file.lock_exclusive().map_err(LockError::IOError)?
+            file.lock_shared().map_err(LockError::IOError)?
         } else {
-            file.try_lock_exclusive().map_err(|_| LockError::LockBusy)?
+            file.try_lock_shared().map_err(|_| LockError::LockBusy)?
         }
-        Ok(DirectoryLock::from(Box::new(ReleaseLockFile {
-            path: lock.filepath.clone(),
-            _file: file
-        })))
+        Ok(DirectoryLock::from(Box::new(ReleaseLockFile(Some(file)))))
     }
 
     fn watch(&self, watch_callback: WatchCallback) -> Result<WatchHandle, TantivyError> {
-        Ok(self.watch_router.write().unwrap().subscribe(watch_callback))
+        let mut watcher = self.watcher.lock().expect("meta watcher");
+        if watcher.is_none() {
+            *watcher = Some(MetaWatcher::spawn(self.resolve_path(Path::new("meta.json")), self.watch_router.clone())?);
+        }
+        Ok(self.watch_router.subscribe(watch_callback))
+    }
+}
+
+/// 文件内容的哈希, 文件不存在或者读不了时为 None
+fn content_hash(path: &Path) -> Option<u64> {
+    let data = std::fs::read(path).ok()?;
+    let mut hasher = DefaultHasher::new();
+    data.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// 轮询 meta.json, 内容变了就通知所有 watch 的回调.
+///
+/// 写入方提交时会整体替换 meta.json, 轮询对 nfs 上的索引也有效
+struct MetaWatcher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetaWatcher {
+    fn spawn(path: PathBuf, router: Arc<WatchCallbackList>) -> Result<MetaWatcher, TantivyError> {
+        let (stop, stopped) = channel::<()>();
+        let mut last = content_hash(&path);
+        let thread = std::thread::Builder::new()
+            .name("meta-file-poll-thread".to_string())
+            .spawn(move || {
+                // sender 被 drop 时 recv_timeout 立即返回 Disconnected
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(POLL_INTERVAL) {
+                    let hash = content_hash(&path);
+                    if hash.is_some() && hash != last {
+                        last = hash;
+                        router.broadcast();
+                    }
+                }
+            })
+            .map_err(|e| IOError::with_path(PathBuf::from("meta.json"), e))?;
+        Ok(MetaWatcher {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
     }
 }
 
-struct ReleaseLockFile {
-    _file: File,
-    path: PathBuf
+impl Drop for MetaWatcher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
 }
+
+/// 关闭文件时释放锁
+pub(crate) struct ReleaseLockFile(pub(crate) Option<File>);
 impl Drop for ReleaseLockFile {
     fn drop(&mut self) {
-        
+        if let Some(file) = self.0.take() {
+            let _ = file.unlock();
+        }
     }
-}
\ No newline at end of file
+}
+
+/// 锁文件的状态
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LockState {
+    /// 没有锁文件
+    Absent,
+    /// 有进程拿着锁
+    Held,
+    /// 有锁文件但现在没人拿着, 写入方用完锁之后就是这样
+    Unlocked,
+}
+
+impl LockState {
+    pub fn name(self) -> &'static str {
+        match self {
+            LockState::Absent => "absent",
+            LockState::Held => "held",
+            LockState::Unlocked => "unlocked",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_mmap_and_rejects_writes() {
+        let root = std::env::temp_dir().join(format!("only-read-directory-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("data"), b"segment data").unwrap();
+        std::fs::write(root.join("empty"), b"").unwrap();
+        let mut directory = OnlyReadDirectory::new(root.clone(), true);
+
+        let first = directory.open_read(Path::new("data")).unwrap();
+        assert_eq!(first.as_slice(), b"segment data");
+        let second = directory.open_read(Path::new("data")).unwrap();
+        assert!(Arc::ptr_eq(&first.data, &second.data));
+        assert_eq!(directory.open_read(Path::new("empty")).unwrap().as_slice(), b"");
+        assert!(matches!(directory.open_read(Path::new("missing")), Err(OpenReadError::FileDoesNotExist(_))));
+
+        assert!(directory.open_write(Path::new("new")).is_err());
+        assert!(directory.atomic_write(Path::new("data"), b"changed").is_err());
+        assert!(directory.delete(Path::new("data")).is_err());
+        assert!(!root.join("new").exists());
+        assert_eq!(std::fs::read(root.join("data")).unwrap(), b"segment data");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_locks() {
+        let root = std::env::temp_dir().join(format!("only-read-directory-locks-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let directory = OnlyReadDirectory::new(root.clone(), false);
+        assert!(directory.acquire_lock(&INDEX_WRITER_LOCK).is_err());
+        // 没有锁文件时报错, 不创建
+        match directory.acquire_lock(&META_LOCK) {
+            Err(LockError::IOError(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            _ => panic!("locked without a lock file"),
+        }
+        assert!(!root.join(&META_LOCK.filepath).exists());
+        assert_eq!(directory.lock_states().unwrap(), vec![
+            (INDEX_WRITER_LOCK.filepath.clone(), LockState::Absent),
+            (META_LOCK.filepath.clone(), LockState::Absent),
+        ]);
+
+        // 加的是共享锁, 写入方拿不到排它锁
+        std::fs::write(root.join(&META_LOCK.filepath), b"").unwrap();
+        let lock = directory.acquire_lock(&META_LOCK).unwrap();
+        let meta_lock = File::open(root.join(&META_LOCK.filepath)).unwrap();
+        assert!(meta_lock.try_lock_exclusive().is_err());
+        assert_eq!(directory.lock_states().unwrap()[1].1, LockState::Held);
+        drop(lock);
+        meta_lock.try_lock_exclusive().unwrap();
+        meta_lock.unlock().unwrap();
+        assert_eq!(directory.lock_states().unwrap()[1].1, LockState::Unlocked);
+
+        std::fs::write(root.join(&INDEX_WRITER_LOCK.filepath), b"").unwrap();
+        let first = directory.acquire_lock(&META_LOCK).unwrap();
+        let second = directory.acquire_lock(&META_LOCK).unwrap();
+        assert_eq!(directory.lock_states().unwrap()[0].1, LockState::Unlocked);
+        assert_eq!(directory.lock_states().unwrap()[1].1, LockState::Held);
+        drop(first);
+        drop(second);
+        assert_eq!(directory.lock_states().unwrap()[1].1, LockState::Unlocked);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// 等 `done` 成立, 最多等 5 秒
+    fn wait_until<F: Fn() -> bool>(done: F) -> bool {
+        for _ in 0..50 {
+            if done() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        false
+    }
+
+    #[test]
+    fn test_reload_on_external_commit() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tantivy::schema::{Schema, INDEXED};
+        use tantivy::{doc, Index, ReloadPolicy};
+
+        let root = std::env::temp_dir().join(format!("only-read-directory-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut schema_builder = Schema::builder();
+        let time = schema_builder.add_u64_field("time", INDEXED);
+        let index = Index::create_in_dir(&root, schema_builder.build()).unwrap();
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        index_writer.add_document(doc!(time => 1u64));
+        index_writer.commit().unwrap();
+
+        let directory = OnlyReadDirectory::new(root.clone(), true);
+        let broadcasts = Arc::new(AtomicUsize::new(0));
+        let counter = broadcasts.clone();
+        let _handle = directory.watch(Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); })).unwrap();
+        let reader = Index::open(directory).unwrap().reader_builder().reload_policy(ReloadPolicy::OnCommit).try_into().unwrap();
+        assert_eq!(reader.searcher().num_docs(), 1);
+
+        index_writer.add_document(doc!(time => 2u64));
+        index_writer.commit().unwrap();
+        assert!(wait_until(|| reader.searcher().num_docs() == 2));
+        assert!(wait_until(|| broadcasts.load(Ordering::SeqCst) == 1));
+
+        // 内容没变的写入不通知
+        let meta = std::fs::read(root.join("meta.json")).unwrap();
+        std::fs::write(root.join("meta.json"), &meta).unwrap();
+        std::thread::sleep(POLL_INTERVAL * 3);
+        assert_eq!(broadcasts.load(Ordering::SeqCst), 1);
+
+        drop(reader);
+        drop(index_writer);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use tantivy::fastfield::FastFieldReader;
+use tantivy::schema::Field;
+use tantivy::{DocId, SegmentReader};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use super::filter_cache::FilterCache;
+
+/// 每块的文档数
+pub const BLOCK_SIZE: DocId = 1024;
+
+/// 一个块和范围的关系
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockMatch {
+    /// 块内没有文档落在范围内
+    None,
+    /// 块内所有文档都落在范围内
+    All,
+    /// 需要逐篇读 fast field
+    Some,
+}
+
+/// 按 doc id 每 `BLOCK_SIZE` 篇文档分一块, 记录块内 fast field 的最小值和最大值.
+///
+/// segment 是只读的, 有 `FilterCache` 时同一个 segment 的同一个字段只算一次.
+pub struct BlockMinMax {
+    blocks: Vec<(u64, u64)>,
+    max_doc: DocId,
+}
+
+impl BlockMinMax {
+    fn build(fast_field: &FastFieldReader<u64>, max_doc: DocId) -> BlockMinMax {
+        let mut blocks = Vec::with_capacity(max_doc.div_ceil(BLOCK_SIZE) as usize);
+        let mut values = vec![0u64; BLOCK_SIZE as usize];
+        let mut start = 0;
+        while start < max_doc {
+            let len = BLOCK_SIZE.min(max_doc - start) as usize;
+            fast_field.get_range(start, &mut values[..len]);
+            let min = values[..len].iter().cloned().min().unwrap_or(0);
+            let max = values[..len].iter().cloned().max().unwrap_or(0);
+            blocks.push((min, max));
+            start += BLOCK_SIZE;
+        }
+        BlockMinMax { blocks, max_doc }
+    }
+
+    pub fn for_segment(reader: &SegmentReader, field: Field, fast_field: &FastFieldReader<u64>, cache: Option<&FilterCache>) -> Arc<BlockMinMax> {
+        let build = || BlockMinMax::build(fast_field, reader.max_doc());
+        match cache {
+            Some(cache) => cache.blocks(reader, field, build),
+            None => Arc::new(build()),
+        }
+    }
+
+    /// `doc` 所在块和 `values` 的关系
+    pub fn block_match(&self, doc: DocId, values: &RangeInclusive<u64>) -> BlockMatch {
+        let (min, max) = self.blocks[(doc / BLOCK_SIZE) as usize];
+        if max < *values.start() || min > *values.end() {
+            BlockMatch::None
+        } else if values.contains(&min) && values.contains(&max) {
+            BlockMatch::All
+        } else {
+            BlockMatch::Some
+        }
+    }
+
+    /// `doc` 所在块之后的第一篇文档
+    pub fn block_end(&self, doc: DocId) -> DocId {
+        (doc / BLOCK_SIZE + 1).saturating_mul(BLOCK_SIZE).min(self.max_doc)
+    }
+
+    /// 和 `values` 有交集的块里的文档数, 以及其中需要逐篇判断的文档数
+    pub fn overlap(&self, values: &RangeInclusive<u64>) -> (u64, u64) {
+        let mut overlap_docs = 0u64;
+        let mut partial_docs = 0u64;
+        for i in 0..self.blocks.len() {
+            let doc = i as DocId * BLOCK_SIZE;
+            let len = u64::from(self.block_end(doc) - doc);
+            match self.block_match(doc, values) {
+                BlockMatch::None => {}
+                BlockMatch::All => overlap_docs += len,
+                BlockMatch::Some => {
+                    overlap_docs += len;
+                    partial_docs += len;
+                }
+            }
+        }
+        (overlap_docs, partial_docs)
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::schema::{Schema, FAST};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_segments_merged_away_are_evicted() {
+        let mut schema_builder = Schema::builder();
+        let time = schema_builder.add_u64_field("time", FAST);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..2 * BLOCK_SIZE as u64 {
+            index_writer.add_document(doc!(time => i));
+            if i == BLOCK_SIZE as u64 {
+                index_writer.commit().unwrap();
+            }
+        }
+        index_writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        assert_eq!(searcher.segment_readers().len(), 2);
+        let cache = FilterCache::default();
+        let blocks = |segment: &SegmentReader| {
+            BlockMinMax::for_segment(segment, time, &segment.fast_fields().u64(time).unwrap(), Some(&cache))
+        };
+        let old: Vec<_> = searcher.segment_readers().iter().map(blocks).collect();
+        for (segment, cached) in searcher.segment_readers().iter().zip(&old) {
+            assert!(Arc::ptr_eq(cached, &blocks(segment)));
+        }
+
+        // reload 之后的 searcher 里只有合并出来的新 segment
+        let merged = Index::create_in_ram(index.schema());
+        let mut merged_writer = merged.writer_with_num_threads(1, 10_000_000).unwrap();
+        merged_writer.add_document(doc!(time => 0u64));
+        merged_writer.commit().unwrap();
+        let merged_searcher = merged.reader().unwrap().searcher();
+        let kept = blocks(merged_searcher.segment_reader(0));
+        cache.retain(&merged_searcher);
+        assert!(Arc::ptr_eq(&kept, &blocks(merged_searcher.segment_reader(0))));
+        for (segment, cached) in searcher.segment_readers().iter().zip(&old) {
+            assert!(!Arc::ptr_eq(cached, &blocks(segment)));
+        }
+    }
+}
@@ -1,226 +1,454 @@
-use tantivy::query::{Query, Weight, Scorer, Explanation, BooleanQuery, RangeQuery, BooleanWeight, BitSetDocSet, Intersection, ConstScorer, TermScorer, VecDocSet};
-use tantivy::{Searcher, TantivyError, SegmentReader, DocSet, Term, InvertedIndexReader, DocId, SkipResult, BitSet};
-use tantivy::schema::{Field, IndexRecordOption};
-use tantivy::postings::SegmentPostings;
-use std::sync::{Arc, RwLock};
-use tantivy::directory::DirectoryClone;
-use serde_json::de::ParserNumber::U64;
-use std::borrow::BorrowMut;
-use std::rc::Rc;
-use std::time::SystemTime;
+use tantivy::query::{Query, Weight, Scorer, Explanation, BooleanQuery, BitSetDocSet, Intersection, ConstScorer, VecDocSet};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::{Searcher, TantivyError, SegmentReader, DocSet, Term, DocId, SkipResult, BitSet, DocAddress, Score, SegmentLocalId};
+use tantivy::{i64_to_u64, f64_to_u64, u64_to_i64, u64_to_f64};
+use tantivy::chrono::{TimeZone, Utc};
+use tantivy::schema::{Field, IndexRecordOption, Schema, Type};
+use tantivy::fastfield::FastFieldReader;
 use tantivy::termdict::{TermDictionary, TermStreamer};
-use std::collections::{Bound, BTreeMap, HashMap, BinaryHeap};
-use std::fmt;
+use std::cmp::Reverse;
+use std::collections::Bound;
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering};
+use super::block_min_max::{BlockMinMax, BlockMatch};
+use super::filter_cache::{collect_bitset, FilterCache, FilterKey};
 
+/// 按字段排序的方向
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// 命中数统计, 跨 segment 累加
+#[derive(Debug, Default)]
+struct TotalHits {
+    count: AtomicUsize,
+    exact: AtomicBool,
+    searched: AtomicBool,
+}
+
+impl TotalHits {
+    fn reset(&self) {
+        self.count.store(0, AtomicOrdering::SeqCst);
+        self.exact.store(true, AtomicOrdering::SeqCst);
+        self.searched.store(false, AtomicOrdering::SeqCst);
+    }
+
+    fn add(&self, count: usize, exact: bool) {
+        self.count.fetch_add(count, AtomicOrdering::SeqCst);
+        if !exact {
+            self.exact.store(false, AtomicOrdering::SeqCst);
+        }
+        self.searched.store(true, AtomicOrdering::SeqCst);
+    }
+}
+
+/// `CatQuery` 的一个范围条件.
+///
+/// `field` 可以是 u64, i64, f64 或 date 字段, 边界都用 term 里保序的 u64 编码表示,
+/// 和 `RangeQuery::new_i64_bounds`/`new_f64_bounds` 一样.
+/// `score` 是命中时加到分数上的常数, 和 `RangeQuery` 一样 must 里的范围是 1, filter 里的是 0
+#[derive(Clone, Debug)]
+pub struct CatRange {
+    pub field: Field,
+    pub value_type: Type,
+    pub left: Bound<u64>,
+    pub right: Bound<u64>,
+    pub score: Score,
+}
+
+/// 在几个范围条件的交集内执行 `query`.
+///
+/// 分数是 `query` 的分数加上各个范围的 `score`, 和把范围作为 bool 的子句时一样.
+/// 第一个范围是主范围: `limit` 大于 0 时只收集按主范围字段排在 `order` 顺序前面的 `limit` 篇文档,
+/// 和第 `limit` 篇值相同的文档也会一起收集; 这时命中数由 `total_hits` 给出,
+/// 只精确统计到 `track_total_hits` 为止. clone 出来的查询共享同一份命中数.
 #[derive(Clone, Debug)]
 pub struct CatQuery {
     query: BooleanQuery,
-    field: Field,
-    left: u64,
-    right: u64,
-    limit: usize
+    ranges: Vec<CatRange>,
+    limit: usize,
+    order: SortOrder,
+    track_total_hits: usize,
+    total_hits: Arc<TotalHits>,
+    cache: Option<Arc<FilterCache>>,
 }
 impl CatQuery {
-    pub fn new(query: BooleanQuery, field: Field, left: u64, right: u64, limit: usize) -> Self {
+    /// `range` 是主范围
+    pub fn new(query: BooleanQuery, range: CatRange, limit: usize) -> Self {
         CatQuery {
             query,
-            field,
-            left,
-            right,
-            limit
+            ranges: vec![range],
+            limit,
+            order: SortOrder::Desc,
+            track_total_hits: usize::MAX,
+            total_hits: Arc::new(TotalHits::default()),
+            cache: None,
+        }
+    }
+    pub fn add_range(&mut self, range: CatRange) {
+        self.ranges.push(range);
+    }
+    /// 把 `field` 上的范围换成主范围, 没有这个字段的范围时返回 false
+    pub fn order_by_range(&mut self, field: Field) -> bool {
+        match self.ranges.iter().position(|r| r.field == field) {
+            Some(i) => {
+                self.ranges.swap(0, i);
+                true
+            }
+            None => false,
         }
     }
     pub fn set_limit(&mut self, limit: usize) {
         self.limit = limit;
     }
+    pub fn set_order(&mut self, order: SortOrder) {
+        self.order = order;
+    }
+    pub fn set_track_total_hits(&mut self, track_total_hits: usize) {
+        self.track_total_hits = track_total_hits;
+    }
+    /// 没有设置时每次都现算, 不生成只用一次的 bitset
+    pub fn set_filter_cache(&mut self, cache: Option<Arc<FilterCache>>) {
+        self.cache = cache;
+    }
+    pub fn query(&self) -> &BooleanQuery {
+        &self.query
+    }
+    pub fn ranges(&self) -> &[CatRange] {
+        &self.ranges
+    }
+    /// 主范围的字段
+    pub fn field(&self) -> Field {
+        self.ranges[0].field
+    }
+    pub fn value_type(&self) -> Type {
+        self.ranges[0].value_type
+    }
+    /// 和这个查询配对的 collector, 按 `order` 收集前 `limit` 篇; 没有设置 limit 时为 None
+    pub fn top_docs(&self) -> Option<CatTopDocs> {
+        if self.limit == 0 {
+            return None;
+        }
+        Some(CatTopDocs {
+            field: self.field(),
+            value_type: self.value_type(),
+            limit: self.limit,
+            order: self.order,
+        })
+    }
+    /// 上一次搜索的命中数和它是否精确; 没有设置 limit 或者还没搜索过时为 None,
+    /// 这时命中数就是 scorer 返回的文档数
+    pub fn total_hits(&self) -> Option<(usize, bool)> {
+        if self.limit == 0 || !self.total_hits.searched.load(AtomicOrdering::SeqCst) {
+            return None;
+        }
+        let count = self.total_hits.count.load(AtomicOrdering::SeqCst);
+        if !self.total_hits.exact.load(AtomicOrdering::SeqCst) || count > self.track_total_hits {
+            Some((self.track_total_hits, false))
+        } else {
+            Some((count, true))
+        }
+    }
 }
 
 impl Query for CatQuery {
-    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<Weight>, TantivyError> {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>, TantivyError> {
+        self.total_hits.reset();
+        let filter_key = self.cache.as_ref().and_then(|cache| {
+            let key = FilterKey::query(searcher.schema(), &self.query)?;
+            cache.record(&key);
+            Some(key)
+        });
         Ok(Box::new(CatWeight {
             weight: self.query.weight(searcher, scoring_enabled)?,
-            field: self.field,
-            left: self.left,
-            right: self.right,
-            limit: self.limit
+            filter_key,
+            scoring_enabled,
+            ranges: self.ranges.clone(),
+            limit: self.limit,
+            order: self.order,
+            track_total_hits: self.track_total_hits,
+            total_hits: self.total_hits.clone(),
+            cache: self.cache.clone(),
         }))
     }
 }
 
-struct CatWeight {
-    weight: Box<dyn Weight>,
-    field: Field,
-    left: u64,
-    right: u64,
-    limit: usize
-}
-//impl DocSet for Rc<dyn DocSet> {
-//    fn advance(&mut self) -> bool {
-//        self.borrow_mut().advance()
-//    }
-//
-//    fn doc(&self) -> u32 {
-//        unimplemented!()
-//    }
-//
-//    fn size_hint(&self) -> u32 {
-//        unimplemented!()
-//    }
-//
-//    fn get_name(&mut self) -> &'static str {
-//        unimplemented!()
-//    }
-//}
-#[derive(Clone)]
-struct ArcVecDocSet {
-    vec_doc_set:Rc<RwLock<VecDocSet>>,
-}
-impl DocSet for ArcVecDocSet {
-    fn advance(&mut self) -> bool {
-        self.vec_doc_set.write().expect("").advance()
-    }
-
-    fn doc(&self) -> u32 {
-        self.vec_doc_set.read().expect("").doc()
+/// 把 term 里的编码还原成可读的值
+fn display_value(value_type: Type, value: u64) -> String {
+    match value_type {
+        Type::I64 => u64_to_i64(value).to_string(),
+        Type::F64 => u64_to_f64(value).to_string(),
+        Type::Date => match Utc.timestamp_opt(u64_to_i64(value), 0).single() {
+            Some(date) => date.to_rfc3339(),
+            None => u64_to_i64(value).to_string(),
+        },
+        _ => value.to_string(),
     }
+}
 
-    fn size_hint(&self) -> u32 {
-        self.vec_doc_set.read().expect("").size_hint()
+/// 读出来的值和 term 一样是保序的 u64 编码; date 没有 fast field
+fn fast_field_reader(reader: &SegmentReader, field: Field, value_type: Type) -> Option<FastFieldReader<u64>> {
+    let fast_fields = reader.fast_fields();
+    match value_type {
+        Type::U64 => fast_fields.u64(field),
+        Type::I64 => fast_fields.i64(field).map(FastFieldReader::into_u64_reader),
+        Type::F64 => fast_fields.f64(field).map(FastFieldReader::into_u64_reader),
+        _ => None,
     }
+}
 
-    fn get_name(&mut self) -> &'static str {
-        self.vec_doc_set.write().expect("").get_name()
-
+/// 没有值的文档在 fast field 里读出来的编码
+fn missing_value(value_type: Type) -> u64 {
+    match value_type {
+        Type::I64 => i64_to_u64(0),
+        Type::F64 => f64_to_u64(0.0),
+        _ => 0,
     }
 }
-//fn intersection_all(left: &mut DocSet, right: &mut Vec<DocSet>) -> bool {
-//
-//    return false;
-//}
-fn intersection_cache(left: &mut DocSet, right: &mut DocSet, cache: &mut BitSet) -> bool {
-    if !right.advance() {
-        return false;
-    }
-    let mut candidate = right.doc();
 
-    if cache.contains(candidate) {
-        return true;
-    }
+/// 解码一个 posting 并写进 bitset 的代价
+const POSTING_COST: u64 = 2;
+/// 随机读一次 fast field 的代价
+const FAST_FIELD_COST: u64 = 2;
+/// 顺序扫 fast field 时每篇文档的代价
+const SCAN_COST: u64 = 1;
 
+/// 每个 segment 上 `CatWeight` 的执行方式
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Strategy {
+    /// 把范围内所有 term 的 postings 并成 bitset, 再和过滤条件求交
+    RangeBitSet,
+    /// 遍历过滤条件命中的文档, 逐个查 fast field 看是否落在范围内
+    FilterFastField,
+    /// 顺序扫 fast field 得到范围内的文档, 再和过滤条件求交
+    RangeScan,
+}
 
-    loop {
-        match left.skip_next(candidate) {
-            SkipResult::Reached => {
-                return true;
-            }
-            SkipResult::OverStep => {
-                candidate = left.doc();
-                cache.insert(candidate);
-            }
-            SkipResult::End => {
-                return false;
-            }
-        }
-        match right.skip_next(candidate) {
-            SkipResult::Reached => {
-                return true;
-            }
-            SkipResult::OverStep => {
-                candidate = right.doc();
-            }
-            SkipResult::End => {
-                return false;
-            }
+impl Strategy {
+    fn name(self) -> &'static str {
+        match self {
+            Strategy::RangeBitSet => "range bitset",
+            Strategy::FilterFastField => "filter with fast field",
+            Strategy::RangeScan => "range scan",
         }
     }
+}
 
+/// 一个 segment 上的代价估计, 单位都是"处理一篇文档"
+struct Plan {
+    strategy: Strategy,
+    /// 范围内 term 的 doc_freq 之和, 超过其他方式的代价后就不再往下累加了
+    range_docs: u64,
+    /// 过滤条件的 size_hint
+    filter_docs: u64,
+    max_doc: u64,
+    /// 和范围有交集的块里的文档数, 以及其中需要逐篇读 fast field 的文档数
+    blocks: Option<(u64, u64)>,
+    fast_field: Option<FastFieldRange>,
 }
-fn intersection(left: &mut DocSet, right: &mut DocSet) -> bool {
-    if !left.advance() {
-        return false;
-    }
-    let mut candidate = left.doc();
-    loop {
-        match right.skip_next(candidate) {
-            SkipResult::Reached => {
-                return true;
-            }
-            SkipResult::OverStep => {
-                candidate = right.doc();
-            }
-            SkipResult::End => {
-                return false;
-            }
-        }
-        match left.skip_next(candidate) {
-            SkipResult::Reached => {
-                return true;
-            }
-            SkipResult::OverStep => {
-                candidate = left.doc();
-            }
-            SkipResult::End => {
-                return false;
-            }
+
+impl Plan {
+    fn describe(&self) -> String {
+        let mut description = format!("CatQuery, {} (range docs {}, filter docs {}, max doc {}",
+                                      self.strategy.name(), self.range_docs, self.filter_docs, self.max_doc);
+        if let Some((overlap_docs, partial_docs)) = self.blocks {
+            description += &format!(", block docs {}, partial block docs {}", overlap_docs, partial_docs);
         }
+        description + ")"
     }
 }
 
+struct CatWeight {
+    weight: Box<dyn Weight>,
+    filter_key: Option<FilterKey>,
+    scoring_enabled: bool,
+    ranges: Vec<CatRange>,
+    limit: usize,
+    order: SortOrder,
+    track_total_hits: usize,
+    total_hits: Arc<TotalHits>,
+    cache: Option<Arc<FilterCache>>,
+}
 
-
-impl CatWeight {
+impl CatRange {
     fn term_range<'a>(&self, term_dict: &'a TermDictionary) -> TermStreamer<'a> {
         use std::collections::Bound::*;
+        let term_val = |val: &u64| Term::from_field_u64(self.field, *val).value_bytes().to_owned();
         let mut term_stream_builder = term_dict.range();
-        term_stream_builder = match Bound::Included(Term::from_field_u64(self.field, self.left).value_bytes().to_owned()) {
-            Included(ref term_val) => term_stream_builder.ge(term_val),
-            Excluded(ref term_val) => term_stream_builder.gt(term_val),
+        term_stream_builder = match self.left {
+            Included(ref val) => term_stream_builder.ge(term_val(val)),
+            Excluded(ref val) => term_stream_builder.gt(term_val(val)),
             Unbounded => term_stream_builder,
         };
-        term_stream_builder = match Bound::Included(Term::from_field_u64(self.field, self.right).value_bytes().to_owned()) {
-            Included(ref term_val) => term_stream_builder.le(term_val),
-            Excluded(ref term_val) => term_stream_builder.lt(term_val),
+        term_stream_builder = match self.right {
+            Included(ref val) => term_stream_builder.le(term_val(val)),
+            Excluded(ref val) => term_stream_builder.lt(term_val(val)),
             Unbounded => term_stream_builder,
         };
         term_stream_builder.into_stream()
     }
-    fn scorer1(&self, reader: &SegmentReader) -> Result<Box<Scorer>, TantivyError> {
+    /// 范围内所有的值, 给读 fast field 的方式做判断用
+    fn values(&self) -> RangeInclusive<u64> {
+        use std::collections::Bound::*;
+        let empty = RangeInclusive::new(1, 0);
+        let start = match self.left {
+            Included(val) => val,
+            Excluded(val) => match val.checked_add(1) {
+                Some(val) => val,
+                None => return empty,
+            },
+            Unbounded => 0,
+        };
+        let end = match self.right {
+            Included(val) => val,
+            Excluded(val) => match val.checked_sub(1) {
+                Some(val) => val,
+                None => return empty,
+            },
+            Unbounded => u64::MAX,
+        };
+        start..=end
+    }
+    /// 字段不是 fast field 时返回 None.
+    /// 没有值的文档在 fast field 里读出来是 0, 范围包含 0 的编码时也不能用 fast field
+    fn fast_field(&self, reader: &SegmentReader, cache: Option<&FilterCache>) -> Option<FastFieldRange> {
+        let values = self.values();
+        if values.contains(&missing_value(self.value_type)) {
+            return None;
+        }
+        let fast_field = fast_field_reader(reader, self.field, self.value_type)?;
+        let blocks = BlockMinMax::for_segment(reader, self.field, &fast_field, cache);
+        Some(FastFieldRange {
+            fast_field,
+            blocks,
+            values,
+        })
+    }
+
+    /// 用 term 字典里的 doc_freq 估计范围内的文档数, 最多估到 max_doc
+    fn estimate_docs(&self, reader: &SegmentReader) -> u64 {
+        let max_doc = u64::from(reader.max_doc());
         let inverted_index = reader.inverted_index(self.field);
-//        let fieldnorm_reader = reader.get_fieldnorms_reader(field);
-        let mut scorer = self.weight.scorer(reader)?;
-        let mut doc_vec = vec![];
-        let mut v = vec![];
-//        println!("{:?}", scorer.size_hint());
-        scorer.for_each(&mut |doc, score| {
-            v.push(doc);
-        });
-        let mut num = 0;
-        for i in self.left..self.right {
-            let term = Term::from_field_u64(self.field, i);
-            if let Some(mut right) = inverted_index.read_postings(&term, IndexRecordOption::Basic) {
-                let array :Vec<Box<dyn DocSet>> = vec![Box::new(VecDocSet::from(v.clone())), Box::new(right)];
-                let mut intersection_scorer = Intersection::new(array);
-                let start_time = SystemTime::now();
-                while intersection_scorer.advance() {
-                    doc_vec.push(intersection_scorer.doc());
-                    num = num + 1;
-                    if num >= self.limit {
-                        return Ok(Box::new(ConstScorer::new(VecDocSet::from(doc_vec))));
-                    }
-                }
-                println!("run time {:?}",SystemTime::now().duration_since(start_time).expect("??"));
+        let mut term_range = self.term_range(inverted_index.terms());
+        let mut range_docs = 0u64;
+        while range_docs < max_doc && term_range.advance() {
+            range_docs += u64::from(term_range.value().doc_freq);
+        }
+        range_docs.min(max_doc)
+    }
 
+    /// `doc` 落在范围内时返回它的值.
+    ///
+    /// 有 fast field 时直接读, 读出来是 0 的编码时再查一次这个值的 postings, 区分没有值的文档;
+    /// 没有 fast field 时逐个 term 在 postings 里跳到 `doc`
+    fn matched_value(&self, reader: &SegmentReader, doc: DocId) -> Option<u64> {
+        let values = self.values();
+        let inverted_index = reader.inverted_index(self.field);
+        if let Some(fast_field) = fast_field_reader(reader, self.field, self.value_type) {
+            let value = fast_field.get(doc);
+            if !values.contains(&value) {
+                return None;
+            }
+            if value == missing_value(self.value_type) {
+                let mut postings = inverted_index.read_postings(&Term::from_field_u64(self.field, value), IndexRecordOption::Basic)?;
+                if postings.skip_next(doc) != SkipResult::Reached {
+                    return None;
+                }
+            }
+            return Some(value);
+        }
+        let mut term_range = self.term_range(inverted_index.terms());
+        while term_range.advance() {
+            let mut postings = inverted_index.read_postings_from_terminfo(term_range.value(), IndexRecordOption::Basic);
+            if postings.skip_next(doc) == SkipResult::Reached {
+                let mut value = [0u8; 8];
+                value.copy_from_slice(term_range.key());
+                return Some(u64::from_be_bytes(value));
             }
         }
-        Ok(Box::new(ConstScorer::new(VecDocSet::from(doc_vec))))
+        None
+    }
+
+    /// 形如 `time in [150, +inf)`
+    fn describe(&self, schema: &Schema) -> String {
+        let value = |v: u64| display_value(self.value_type, v);
+        let left = match self.left {
+            Bound::Included(v) => format!("[{}", value(v)),
+            Bound::Excluded(v) => format!("({}", value(v)),
+            Bound::Unbounded => "(-inf".to_string(),
+        };
+        let right = match self.right {
+            Bound::Included(v) => format!("{}]", value(v)),
+            Bound::Excluded(v) => format!("{})", value(v)),
+            Bound::Unbounded => "+inf)".to_string(),
+        };
+        format!("{} in {}, {}", schema.get_field_name(self.field), left, right)
     }
-    fn scorer2(&self, reader: &SegmentReader) -> Result<Box<Scorer>, TantivyError> {
+
+    fn plan(&self, reader: &SegmentReader, filter_docs: u32, cache: Option<&FilterCache>) -> Plan {
+        let max_doc = u64::from(reader.max_doc());
+        let filter_docs = u64::from(filter_docs);
+        let fast_field = self.fast_field(reader, cache);
+        let blocks = fast_field.as_ref().map(|f| f.blocks.overlap(&f.values));
+        // 求交时由两边里小的那个驱动
+        let bitset_cost = |range_docs: u64| POSTING_COST * range_docs + range_docs.min(filter_docs);
+        // 过滤条件命中的文档按均匀分布估计, 落在没有交集的块里的直接跳过,
+        // 落在整块都在范围内的块里的不用读 fast field
+        let (filter_cost, scan_cost) = match blocks {
+            Some((overlap_docs, partial_docs)) => {
+                let num_blocks = fast_field.as_ref().map(|f| f.blocks.num_blocks() as u64).unwrap_or(0);
+                let ratio = |docs: u64| (filter_docs * docs).checked_div(max_doc).unwrap_or(0);
+                (ratio(overlap_docs) + FAST_FIELD_COST * ratio(partial_docs),
+                 SCAN_COST * partial_docs + num_blocks + overlap_docs.min(filter_docs))
+            }
+            None => (u64::MAX, u64::MAX),
+        };
+        // 不走 bitset 时的最低代价, 范围内的文档数估到比它还贵就够了
+        let budget = filter_cost.min(scan_cost);
         let inverted_index = reader.inverted_index(self.field);
-        let max_doc = reader.max_doc();
-        let mut doc_bitset = BitSet::with_max_value(max_doc);
+        let mut term_range = self.term_range(inverted_index.terms());
+        let mut range_docs = 0u64;
+        while term_range.advance() {
+            range_docs += u64::from(term_range.value().doc_freq);
+            if range_docs >= max_doc || bitset_cost(range_docs) > budget {
+                break;
+            }
+        }
+        let strategy = if bitset_cost(range_docs) <= budget {
+            Strategy::RangeBitSet
+        } else if filter_cost <= scan_cost {
+            Strategy::FilterFastField
+        } else {
+            Strategy::RangeScan
+        };
+        Plan {
+            strategy,
+            range_docs,
+            filter_docs,
+            max_doc,
+            blocks,
+            fast_field,
+        }
+    }
 
-        let term_dict = inverted_index.terms();
-        let mut term_range = self.term_range(term_dict);
+    /// 同一个 segment 上相同的范围直接用缓存的 bitset
+    fn range_bitset(&self, reader: &SegmentReader, cache: Option<&FilterCache>) -> Result<BitSetDocSet, TantivyError> {
+        let cache = match cache {
+            Some(cache) => cache,
+            None => return Ok(BitSetDocSet::from(self.build_range_bitset(reader))),
+        };
+        let key = FilterKey::Range(self.field, self.left, self.right);
+        let bitset = cache.bitset(reader, key, || Ok(self.build_range_bitset(reader)))?;
+        Ok(BitSetDocSet::from(BitSet::clone(&bitset)))
+    }
+
+    fn build_range_bitset(&self, reader: &SegmentReader) -> BitSet {
+        let inverted_index = reader.inverted_index(self.field);
+        let mut doc_bitset = BitSet::with_max_value(reader.max_doc());
+        let mut term_range = self.term_range(inverted_index.terms());
         while term_range.advance() {
             let term_info = term_range.value();
             let mut block_segment_postings = inverted_index
@@ -231,159 +459,730 @@ impl CatWeight {
                 }
             }
         }
-        let doc_bitset = BitSetDocSet::from(doc_bitset);
-        let mut scorer = self.weight.scorer(reader)?;
-        let array :Vec<Box<dyn DocSet>> = vec![Box::new(doc_bitset), Box::new(scorer)];
-        Ok(Box::new(ConstScorer::new(Intersection::new(array))))
+        doc_bitset
     }
-    fn scorer3(&self, reader: &SegmentReader) ->  Result<Box<Scorer>, TantivyError> {
-        let inverted_index = reader.inverted_index(self.field);
+
+    /// 这个范围驱动, `scorer` 是过滤条件和其余范围
+    fn scorer_with(&self, reader: &SegmentReader, strategy: Strategy, plan: Plan, scorer: Box<dyn Scorer>, cache: Option<&FilterCache>) -> Result<Box<dyn Scorer>, TantivyError> {
+        Ok(match (strategy, plan.fast_field) {
+            (Strategy::FilterFastField, Some(range)) => Box::new(FastFieldFilter {
+                docset: scorer,
+                range,
+                max_doc: reader.max_doc(),
+            }),
+            (Strategy::RangeScan, Some(range)) => {
+                let scan = FastFieldScan {
+                    range,
+                    doc: 0,
+                    next: 0,
+                    max_doc: reader.max_doc(),
+                    size_hint: plan.range_docs as u32,
+                };
+                intersect(Box::new(scan), scorer)
+            }
+            _ => intersect(Box::new(self.range_bitset(reader, cache)?), scorer),
+        })
+    }
+}
+
+impl CatWeight {
+    /// 各个范围在这个 segment 上估计的文档数; 只有一个范围时不用估
+    fn range_score(&self) -> Score {
+        self.ranges.iter().map(|r| r.score).sum()
+    }
+
+    fn estimate_docs(&self, reader: &SegmentReader) -> Vec<u64> {
+        if self.ranges.len() == 1 {
+            return vec![0];
+        }
+        self.ranges.iter().map(|r| r.estimate_docs(reader)).collect()
+    }
+
+    /// 不打分时内层查询只用来过滤, 能转成 dsl 并且常用时整个缓存成 bitset, 否则直接用它的 scorer
+    fn inner_scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>, TantivyError> {
+        let (cache, key) = match (&self.cache, &self.filter_key) {
+            (Some(cache), Some(key)) if !self.scoring_enabled => (cache, key),
+            _ => return self.weight.scorer(reader),
+        };
+        let bitset = cache.cached_bitset(reader, key, || {
+            Ok(collect_bitset(self.weight.scorer(reader)?, reader.max_doc()))
+        })?;
+        match bitset {
+            Some(bitset) => Ok(Box::new(ConstScorer::new(BitSetDocSet::from(BitSet::clone(&bitset))))),
+            None => self.weight.scorer(reader),
+        }
+    }
+
+    /// 除 `driver` 之外的范围按估计的文档数从少到多套在内层查询上, 最先排除最多文档的范围最先检查
+    fn filter(&self, reader: &SegmentReader, driver: usize, range_docs: &[u64]) -> Result<Box<dyn Scorer>, TantivyError> {
+        let mut others: Vec<usize> = (0..self.ranges.len()).filter(|&i| i != driver).collect();
+        others.sort_by_key(|&i| range_docs[i]);
+        let mut scorer = self.inner_scorer(reader)?;
+        for i in others {
+            let range = &self.ranges[i];
+            scorer = match range.fast_field(reader, self.cache.as_deref()) {
+                Some(fast_field) => Box::new(FastFieldFilter {
+                    docset: scorer,
+                    range: fast_field,
+                    max_doc: reader.max_doc(),
+                }),
+                None => intersect(Box::new(range.range_bitset(reader, self.cache.as_deref())?), scorer),
+            };
+        }
+        Ok(scorer)
+    }
+
+    /// 按 `order` 逐个 term 走范围, 收集前 `limit` 篇; 凑够之后走完当前 term, 值相同的文档也收进来.
+    /// 之后只计数, 数过 `track_total_hits` 就停下. 返回按 `order` 排好的文档, 命中数和它是否精确
+    fn top_docs(&self, reader: &SegmentReader, mut scorer: Box<dyn Scorer>) -> (OrderedScorer, usize, bool) {
         let max_doc = reader.max_doc();
-        let mut doc_bitset = BitSet::with_max_value(max_doc);
-        for i in self.left..self.right {
-            let term = Term::from_field_u64(self.field, i);
-            if let Some(mut right) = inverted_index.read_postings(&term, IndexRecordOption::Basic) {
-                while right.advance() {
-                    doc_bitset.insert(right.doc());
+        let mut filter = BitSet::with_max_value(max_doc);
+        scorer.for_each(&mut |doc, _| filter.insert(doc));
+        let range = &self.ranges[0];
+        let inverted_index = reader.inverted_index(range.field);
+        let mut term_range = range.term_range(inverted_index.terms());
+        let mut term_infos = vec![];
+        while term_range.advance() {
+            term_infos.push(term_range.value().clone());
+        }
+        if self.order == SortOrder::Desc {
+            term_infos.reverse();
+        }
+        let mut docs = vec![];
+        let mut collected = 0;
+        let mut count = 0;
+        let mut exact = true;
+        for term_info in &term_infos {
+            if collected >= self.limit && count > self.track_total_hits {
+                exact = false;
+                break;
+            }
+            let collecting = collected < self.limit;
+            let mut block_segment_postings = inverted_index
+                .read_block_postings_from_terminfo(term_info, IndexRecordOption::Basic);
+            while block_segment_postings.advance() {
+                for &doc in block_segment_postings.docs() {
+                    if !filter.contains(doc) || reader.is_deleted(doc) {
+                        continue;
+                    }
+                    count += 1;
+                    if collecting {
+                        docs.push(doc);
+                        collected += 1;
+                    }
                 }
             }
         }
-        let doc_bitset = BitSetDocSet::from(doc_bitset);
-        let mut scorer = self.weight.scorer(reader)?;
-        let array :Vec<Box<dyn DocSet>> = vec![Box::new(doc_bitset), Box::new(scorer)];
-        Ok(Box::new(ConstScorer::new(Intersection::new(array))))
+        (OrderedScorer::new(docs), count, exact)
     }
-    //3s
-    fn scorer4(&self, reader: &SegmentReader) -> Result<Box<Scorer>, TantivyError> {
-        let mut scorer = self.weight.scorer(reader)?;
-        let inverted_index = reader.inverted_index(self.field);
-        let max_doc = reader.max_doc();
+}
 
-        let mut doc_bitset = BitSet::with_max_value(max_doc);
-        let mut btree_map = BTreeMap::new();
-//        println!("{:?}", scorer.size_hint());
-        scorer.for_each(&mut |doc, score| {
-            btree_map.insert(doc, score);
-        });
-        let mut k = 0;
-        for i in self.left..self.right {
-            let term = Term::from_field_u64(self.field, i);
-            if let Some(mut right) = inverted_index.read_postings(&term, IndexRecordOption::Basic) {
-                while right.advance() {
-                    let doc_id = right.doc();
-                    if btree_map.contains_key(&doc_id) {
-                        doc_bitset.insert(doc_id);
-                        if k >= self.limit && self.limit != 0 {
-                            let doc_bitset = BitSetDocSet::from(doc_bitset);
-                            return Ok(Box::new(ConstScorer::new(doc_bitset)));
-                        }
+impl Weight for CatWeight {
+    fn scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>, TantivyError> {
+        let range_docs = self.estimate_docs(reader);
+        if self.limit > 0 {
+            let (docs, count, exact) = self.top_docs(reader, self.filter(reader, 0, &range_docs)?);
+            self.total_hits.add(count, exact);
+            return Ok(Box::new(docs));
+        }
+        // 文档最少的范围驱动
+        let driver = (0..self.ranges.len()).min_by_key(|&i| range_docs[i]).unwrap_or(0);
+        let scorer = self.filter(reader, driver, &range_docs)?;
+        let range = &self.ranges[driver];
+        let cache = self.cache.as_deref();
+        let plan = range.plan(reader, scorer.size_hint(), cache);
+        let scorer = range.scorer_with(reader, plan.strategy, plan, scorer, cache)?;
+        let range_score = self.range_score();
+        if !self.scoring_enabled || range_score == 0.0 {
+            return Ok(scorer);
+        }
+        Ok(Box::new(RangeScorer { scorer, range_score }))
+    }
+
+    /// 只解释 `doc` 是否满足内层查询和各个范围, 不管它在不在前 `limit` 篇里.
+    /// 范围的值用 fast field 或者 postings 查, 不扫整个 segment
+    fn explain(&self, reader: &SegmentReader, doc: u32) -> Result<Explanation, TantivyError> {
+        let description = if self.limit > 0 {
+            format!("CatQuery, top {} {:?}", self.limit, self.order)
+        } else {
+            let range_docs = self.estimate_docs(reader);
+            let driver = (0..self.ranges.len()).min_by_key(|&i| range_docs[i]).unwrap_or(0);
+            let filter_docs = self.inner_scorer(reader)?.size_hint();
+            self.ranges[driver].plan(reader, filter_docs, self.cache.as_deref()).describe()
+        };
+        let inner = self.weight.explain(reader, doc)?;
+        // 设置了 limit 时按范围字段排序, 分数和 `OrderedScorer` 一样是 1
+        let score = if self.limit > 0 { 1.0 } else { inner.value() + self.range_score() };
+        let mut explanation = Explanation::new(description, score);
+        explanation.add_detail(inner);
+        let schema = reader.schema();
+        for range in &self.ranges {
+            let value = range.matched_value(reader, doc).ok_or_else(|| {
+                TantivyError::InvalidArgument(format!("Document #({}) does not match {}", doc, range.describe(schema)))
+            })?;
+            let description = format!("{}, value {}", range.describe(schema), display_value(range.value_type, value));
+            explanation.add_detail(Explanation::new(description, range.score));
+        }
+        Ok(explanation)
+    }
+}
+
+/// 设置了 limit 时 `CatWeight` 的 scorer.
+///
+/// `for_each` 按范围字段的顺序给出文档, 配合 `CatTopDocs` 不用排序就能拿到前 `limit` 篇;
+/// `advance` 仍然按文档号递增, 可以和别的 `DocSet` 一起用
+struct OrderedScorer {
+    ordered: Vec<DocId>,
+    sorted: VecDocSet,
+}
+
+impl OrderedScorer {
+    fn new(ordered: Vec<DocId>) -> OrderedScorer {
+        let mut sorted = ordered.clone();
+        sorted.sort_unstable();
+        OrderedScorer {
+            ordered,
+            sorted: VecDocSet::from(sorted),
+        }
+    }
+}
+
+impl DocSet for OrderedScorer {
+    fn advance(&mut self) -> bool {
+        self.sorted.advance()
+    }
+
+    fn doc(&self) -> DocId {
+        self.sorted.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.ordered.len() as u32
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "OrderedScorer"
+    }
+}
+
+impl Scorer for OrderedScorer {
+    fn score(&mut self) -> Score {
+        1.0
+    }
+
+    fn for_each(&mut self, callback: &mut dyn FnMut(DocId, Score)) {
+        for &doc in &self.ordered {
+            callback(doc, 1.0);
+        }
+    }
+}
+
+/// 和设置了 limit 的 `CatQuery` 配对使用的 collector, 由 `CatQuery::top_docs` 创建.
+///
+/// 每个 segment 的文档已经按范围字段排好序, 只留前 `limit` 篇, 合并时也只排这几篇.
+/// 结果是范围字段的值 (term 里的编码) 和文档地址
+pub struct CatTopDocs {
+    field: Field,
+    value_type: Type,
+    limit: usize,
+    order: SortOrder,
+}
+
+impl Collector for CatTopDocs {
+    type Fruit = Vec<(u64, DocAddress)>;
+    type Child = CatTopDocsSegment;
+
+    fn for_segment(&self, segment_local_id: SegmentLocalId, reader: &SegmentReader) -> Result<CatTopDocsSegment, TantivyError> {
+        let fast_field = fast_field_reader(reader, self.field, self.value_type).ok_or_else(|| {
+            let name = reader.schema().get_field_name(self.field).to_string();
+            TantivyError::SchemaError(format!("Field {:?} is not a fast field", name))
+        })?;
+        Ok(CatTopDocsSegment {
+            fast_field,
+            segment_local_id,
+            limit: self.limit,
+            docs: Vec::with_capacity(self.limit),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Vec<(u64, DocAddress)>>) -> Result<Vec<(u64, DocAddress)>, TantivyError> {
+        let mut docs: Vec<(u64, DocAddress)> = segment_fruits.into_iter().flatten().collect();
+        match self.order {
+            SortOrder::Asc => docs.sort_by_key(|&(value, _)| value),
+            SortOrder::Desc => docs.sort_by_key(|&(value, _)| Reverse(value)),
+        }
+        docs.truncate(self.limit);
+        Ok(docs)
+    }
+}
+
+pub struct CatTopDocsSegment {
+    fast_field: FastFieldReader<u64>,
+    segment_local_id: SegmentLocalId,
+    limit: usize,
+    docs: Vec<(u64, DocAddress)>,
+}
+
+impl SegmentCollector for CatTopDocsSegment {
+    type Fruit = Vec<(u64, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, _score: Score) {
+        if self.docs.len() < self.limit {
+            self.docs.push((self.fast_field.get(doc), DocAddress(self.segment_local_id, doc)));
+        }
+    }
+
+    fn harvest(self) -> Vec<(u64, DocAddress)> {
+        self.docs
+    }
+}
+
+/// 判断文档的 fast field 值是否落在 `values` 内, 先看所在块的 min/max
+struct FastFieldRange {
+    fast_field: FastFieldReader<u64>,
+    blocks: Arc<BlockMinMax>,
+    values: RangeInclusive<u64>,
+}
+
+impl FastFieldRange {
+    fn block_match(&self, doc: DocId) -> BlockMatch {
+        self.blocks.block_match(doc, &self.values)
+    }
+
+    fn contains(&self, doc: DocId) -> bool {
+        self.values.contains(&self.fast_field.get(doc))
+    }
+}
+
+/// 过滤条件命中的文档里, fast field 值落在范围内的
+struct FastFieldFilter {
+    docset: Box<dyn Scorer>,
+    range: FastFieldRange,
+    max_doc: DocId,
+}
+
+impl DocSet for FastFieldFilter {
+    fn advance(&mut self) -> bool {
+        if !self.docset.advance() {
+            return false;
+        }
+        loop {
+            let doc = self.docset.doc();
+            match self.range.block_match(doc) {
+                BlockMatch::All => return true,
+                BlockMatch::Some => {
+                    if self.range.contains(doc) {
+                        return true;
+                    }
+                    if !self.docset.advance() {
+                        return false;
                     }
+                }
+                // 整块跳过, 过滤条件的 postings 也借 skip list 跳过去
+                BlockMatch::None => {
+                    let block_end = self.range.blocks.block_end(doc);
+                    if block_end >= self.max_doc || self.docset.skip_next(block_end) == SkipResult::End {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    fn doc(&self) -> DocId {
+        self.docset.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.docset.size_hint()
+    }
 
+    fn get_name(&mut self) -> &'static str {
+        "FastFieldFilter"
+    }
+}
+
+impl Scorer for FastFieldFilter {
+    fn score(&mut self) -> Score {
+        self.docset.score()
+    }
+}
+
+/// 只用 `range` 过滤, 分数是 `scorer` 的
+fn intersect(range: Box<dyn DocSet>, scorer: Box<dyn Scorer>) -> Box<dyn Scorer> {
+    let mut range = ConstScorer::new(range);
+    range.set_score(0.0);
+    let range: Box<dyn Scorer> = Box::new(range);
+    Box::new(Intersection::new(vec![range, scorer]))
+}
+
+/// 在 `scorer` 的分数上加上各个范围的分数
+struct RangeScorer {
+    scorer: Box<dyn Scorer>,
+    range_score: Score,
+}
+
+impl DocSet for RangeScorer {
+    fn advance(&mut self) -> bool {
+        self.scorer.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        self.scorer.skip_next(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.scorer.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorer.size_hint()
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "RangeScorer"
+    }
+}
+
+impl Scorer for RangeScorer {
+    fn score(&mut self) -> Score {
+        self.scorer.score() + self.range_score
+    }
+}
+
+/// 按 doc id 顺序扫 fast field, 值落在范围内的文档
+struct FastFieldScan {
+    range: FastFieldRange,
+    doc: DocId,
+    /// 下一篇要检查的文档
+    next: DocId,
+    max_doc: DocId,
+    size_hint: u32,
+}
+
+impl DocSet for FastFieldScan {
+    fn advance(&mut self) -> bool {
+        while self.next < self.max_doc {
+            let doc = self.next;
+            let matched = match self.range.block_match(doc) {
+                BlockMatch::None => {
+                    self.next = self.range.blocks.block_end(doc);
+                    continue;
                 }
+                BlockMatch::All => true,
+                BlockMatch::Some => self.range.contains(doc),
+            };
+            self.next = doc + 1;
+            if matched {
+                self.doc = doc;
+                return true;
             }
         }
-        let doc_bitset = BitSetDocSet::from(doc_bitset);
-        Ok(Box::new(ConstScorer::new(doc_bitset)))
+        false
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        self.next = self.next.max(target);
+        if !self.advance() {
+            return SkipResult::End;
+        }
+        if self.doc == target {
+            SkipResult::Reached
+        } else {
+            SkipResult::OverStep
+        }
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.size_hint
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "FastFieldScan"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::query::{AllQuery, Occur, RangeQuery, TermQuery};
+    use tantivy::schema::{Schema, INDEXED, FAST};
+    use tantivy::{doc, Index};
+
+    fn index() -> (Index, Field, Field, Field) {
+        let mut schema_builder = Schema::builder();
+        let time = schema_builder.add_u64_field("time", INDEXED | FAST);
+        let slow_time = schema_builder.add_u64_field("slow_time", INDEXED);
+        let status = schema_builder.add_u64_field("status", INDEXED);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..5000u64 {
+            index_writer.add_document(doc!(time => 100 + i / 3, slow_time => 100 + i / 3, status => i % 7));
+        }
+        index_writer.add_document(doc!(status => 0u64));
+        index_writer.commit().unwrap();
+        (index, time, slow_time, status)
+    }
+
+    fn range(field: Field, left: Bound<u64>, right: Bound<u64>) -> CatRange {
+        CatRange { field, value_type: Type::U64, left, right, score: 0.0 }
+    }
+
+    fn filter(status: Field, value: Option<u64>) -> BooleanQuery {
+        let query: Box<dyn Query> = match value {
+            Some(value) => Box::new(TermQuery::new(Term::from_field_u64(status, value), IndexRecordOption::Basic)),
+            None => Box::new(AllQuery),
+        };
+        BooleanQuery::from(vec![(Occur::Must, query)])
     }
-    fn scorer5(&self, reader: &SegmentReader) -> Result<Box<Scorer>, TantivyError> {
-        let mut scorer = self.weight.scorer(reader)?;
-        let inverted_index = reader.inverted_index(self.field);
-        let max_doc = reader.max_doc();
 
-        let mut doc_bitmap = BitSet::with_max_value(max_doc);
+    fn docs(scorer: &mut dyn Scorer) -> Vec<DocId> {
+        let mut docs = vec![];
         while scorer.advance() {
-            doc_bitmap.insert(scorer.doc());
-        }
-        let mut doc_bitset = BitSet::with_max_value(max_doc);
-        let mut k = 0;
-        for i in self.left..self.right {
-            let term = Term::from_field_u64(self.field, i);
-            if let Some(mut right) = inverted_index.read_postings(&term, IndexRecordOption::Basic) {
-                while right.advance() {
-                    if doc_bitmap.contains(right.doc()) {
-                       doc_bitset.insert(right.doc());
-                        k= k + 1;
-                        if k >= self.limit && self.limit != 0{
-                            return Ok(Box::new(ConstScorer::new(BitSetDocSet::from(doc_bitset))));
-                        }
+            docs.push(scorer.doc());
+        }
+        docs
+    }
+
+    #[test]
+    fn test_strategies_match_range_query() {
+        let (index, time, slow_time, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let ranges = [
+            (Bound::Included(150), Bound::Excluded(200)),
+            (Bound::Excluded(150), Bound::Included(200)),
+            (Bound::Unbounded, Bound::Included(120)),
+            (Bound::Included(400), Bound::Unbounded),
+            (Bound::Included(0), Bound::Included(200)),
+            (Bound::Included(200), Bound::Included(150)),
+            (Bound::Included(441), Bound::Included(782)),
+            (Bound::Excluded(1000), Bound::Excluded(1400)),
+        ];
+        for field in [time, slow_time] {
+            for value in [None, Some(3)] {
+                for &(left, right) in &ranges {
+                    let expected = RangeQuery::new_u64_bounds(field, left, right);
+                    let expected = BooleanQuery::from(vec![
+                        (Occur::Must, Box::new(filter(status, value)) as Box<dyn Query>),
+                        (Occur::Must, Box::new(expected) as Box<dyn Query>),
+                    ]);
+                    let expected = docs(expected.weight(&searcher, false).unwrap().scorer(reader).unwrap().as_mut());
+                    let weight = filter(status, value).weight(&searcher, false).unwrap();
+                    let range = range(field, left, right);
+                    for &strategy in &[Strategy::RangeBitSet, Strategy::FilterFastField, Strategy::RangeScan] {
+                        let scorer = weight.scorer(reader).unwrap();
+                        let plan = range.plan(reader, scorer.size_hint(), None);
+                        let mut scorer = range.scorer_with(reader, strategy, plan, scorer, None).unwrap();
+                        assert_eq!(docs(scorer.as_mut()), expected, "{:?} {:?} {:?}", strategy, left, right);
                     }
                 }
             }
         }
-        Ok(Box::new(ConstScorer::new(BitSetDocSet::from(doc_bitset))))
     }
-    fn scorer6(&self, reader: &SegmentReader) ->  Result<Box<Scorer>, TantivyError> {
-        let inverted_index = reader.inverted_index(self.field);
-        let max_doc = reader.max_doc();
-        let mut doc_bitset = BitSet::with_max_value(max_doc);
-        for i in self.left..self.right {
-            let term = Term::from_field_u64(self.field, i);
-            if let Some(mut right) = inverted_index.read_postings(&term, IndexRecordOption::Basic) {
-                while right.advance() {
-                    doc_bitset.insert(right.doc());
-                }
-            }
+
+    #[test]
+    fn test_i64_and_f64_fields() {
+        let mut schema_builder = Schema::builder();
+        let delta = schema_builder.add_i64_field("delta", INDEXED | FAST);
+        let latency = schema_builder.add_f64_field("latency", INDEXED | FAST);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..3000i64 {
+            index_writer.add_document(doc!(delta => i - 1500, latency => (i - 1500) as f64 / 8.0));
         }
-        let mut scorer = self.weight.scorer(reader)?;
-        let mut doc_map = BitSet::with_max_value(max_doc);
-        let mut right = BitSetDocSet::from(doc_bitset);
-        let mut k = 0;
-        while intersection(&mut scorer, &mut right) {
-            doc_map.insert(scorer.doc());
-            k = k + 1;
-            if self.limit > 0 && k >= self.limit {
-                return Ok(Box::new(ConstScorer::new( BitSetDocSet::from(doc_map))));
+        index_writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let cases = vec![
+            (delta, Type::I64, RangeQuery::new_i64_bounds(delta, Bound::Included(-1200), Bound::Excluded(-100)),
+             Bound::Included(i64_to_u64(-1200)), Bound::Excluded(i64_to_u64(-100))),
+            (delta, Type::I64, RangeQuery::new_i64_bounds(delta, Bound::Excluded(-10), Bound::Unbounded),
+             Bound::Excluded(i64_to_u64(-10)), Bound::Unbounded),
+            (latency, Type::F64, RangeQuery::new_f64_bounds(latency, Bound::Included(-100.5), Bound::Included(-3.0)),
+             Bound::Included(f64_to_u64(-100.5)), Bound::Included(f64_to_u64(-3.0))),
+            (latency, Type::F64, RangeQuery::new_f64_bounds(latency, Bound::Included(12.25), Bound::Unbounded),
+             Bound::Included(f64_to_u64(12.25)), Bound::Unbounded),
+        ];
+        for (field, value_type, expected, left, right) in cases {
+            let expected = docs(expected.weight(&searcher, false).unwrap().scorer(reader).unwrap().as_mut());
+            assert!(!expected.is_empty());
+            let weight = BooleanQuery::from(vec![(Occur::Must, Box::new(AllQuery) as Box<dyn Query>)]).weight(&searcher, false).unwrap();
+            let range = CatRange { field, value_type, left, right, score: 0.0 };
+            for &strategy in &[Strategy::RangeBitSet, Strategy::FilterFastField, Strategy::RangeScan] {
+                let scorer = weight.scorer(reader).unwrap();
+                let plan = range.plan(reader, scorer.size_hint(), None);
+                let mut scorer = range.scorer_with(reader, strategy, plan, scorer, None).unwrap();
+                assert_eq!(docs(scorer.as_mut()), expected, "{:?} {:?} {:?}", strategy, left, right);
             }
         }
-        Ok(Box::new(ConstScorer::new( BitSetDocSet::from(doc_map))))
     }
-    fn scorer7(&self, reader: &SegmentReader) ->  Result<Box<Scorer>, TantivyError> {
-        let inverted_index = reader.inverted_index(self.field);
-        let max_doc = reader.max_doc();
-        let mut doc_bitset = BitSet::with_max_value(max_doc);
 
-        let term_dict = inverted_index.terms();
-        let mut term_range = self.term_range(term_dict);
-        while term_range.advance() {
-            let term_info = term_range.value();
-            let mut block_segment_postings = inverted_index
-                .read_block_postings_from_terminfo(term_info, IndexRecordOption::Basic);
-            while block_segment_postings.advance() {
-                for &doc in block_segment_postings.docs() {
-                    doc_bitset.insert(doc);
+    #[test]
+    fn test_multiple_ranges() {
+        let (index, time, slow_time, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let cases = [
+            ((Bound::Included(150), Bound::Excluded(1200)), (Bound::Included(400), Bound::Included(410))),
+            ((Bound::Included(1000), Bound::Included(1010)), (Bound::Unbounded, Bound::Excluded(1500))),
+            ((Bound::Included(100), Bound::Unbounded), (Bound::Included(300), Bound::Unbounded)),
+            ((Bound::Included(700), Bound::Unbounded), (Bound::Unbounded, Bound::Excluded(600))),
+        ];
+        for value in [None, Some(3)] {
+            for &((time_left, time_right), (slow_left, slow_right)) in &cases {
+                let expected = BooleanQuery::from(vec![
+                    (Occur::Must, Box::new(filter(status, value)) as Box<dyn Query>),
+                    (Occur::Must, Box::new(RangeQuery::new_u64_bounds(time, time_left, time_right)) as Box<dyn Query>),
+                    (Occur::Must, Box::new(RangeQuery::new_u64_bounds(slow_time, slow_left, slow_right)) as Box<dyn Query>),
+                ]);
+                let expected = docs(expected.weight(&searcher, false).unwrap().scorer(reader).unwrap().as_mut());
+                // 两个范围谁在前面结果都一样, fast field 和 bitset 两种套法都要走到
+                for &swap in &[false, true] {
+                    let (time_range, slow_range) = (
+                        range(time, time_left, time_right),
+                        range(slow_time, slow_left, slow_right),
+                    );
+                    let (first, second) = if swap { (slow_range, time_range) } else { (time_range, slow_range) };
+                    let mut query = CatQuery::new(filter(status, value), first, 0);
+                    query.add_range(second);
+                    let weight = query.weight(&searcher, false).unwrap();
+                    assert_eq!(docs(weight.scorer(reader).unwrap().as_mut()), expected, "{:?} {:?} {:?} {}", value, (time_left, time_right), (slow_left, slow_right), swap);
                 }
             }
         }
-        let mut scorer = self.weight.scorer(reader)?;
-        let mut doc_map = BitSet::with_max_value(max_doc);
-        let mut right = BitSetDocSet::from(doc_bitset);
-        let mut k = 0;
-        while intersection(&mut scorer, &mut right) {
-            doc_map.insert(scorer.doc());
-            k = k + 1;
-            if self.limit > 0 && k >= self.limit {
-                return Ok(Box::new(ConstScorer::new( BitSetDocSet::from(doc_map))));
-            }
-        }
-        Ok(Box::new(ConstScorer::new(BitSetDocSet::from(doc_map))))
+
+        let mut query = CatQuery::new(filter(status, None), range(time, Bound::Included(150), Bound::Unbounded), 3);
+        query.add_range(range(slow_time, Bound::Unbounded, Bound::Excluded(400)));
+        let weight = query.weight(&searcher, false).unwrap();
+        assert_eq!(docs(weight.scorer(reader).unwrap().as_mut()), vec![897, 898, 899]);
+        assert_eq!(query.total_hits(), Some((750, true)));
+        assert!(query.order_by_range(slow_time));
+        assert_eq!(query.field(), slow_time);
+        assert!(!query.order_by_range(status));
     }
 
-}
+    #[test]
+    fn test_plan() {
+        let (index, time, slow_time, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let strategy = |field: Field, value: Option<u64>, left: Bound<u64>, right: Bound<u64>| {
+            let scorer = filter(status, value).weight(&searcher, false).unwrap().scorer(reader).unwrap();
+            range(field, left, right).plan(reader, scorer.size_hint(), None).strategy
+        };
+        assert_eq!(strategy(time, None, Bound::Included(110), Bound::Included(120)), Strategy::RangeBitSet);
+        assert_eq!(strategy(time, None, Bound::Included(110), Bound::Unbounded), Strategy::RangeScan);
+        assert_eq!(strategy(time, Some(3), Bound::Included(110), Bound::Unbounded), Strategy::FilterFastField);
+        assert_eq!(strategy(slow_time, Some(3), Bound::Included(110), Bound::Unbounded), Strategy::RangeBitSet);
+        assert_eq!(strategy(time, Some(3), Bound::Included(0), Bound::Unbounded), Strategy::RangeBitSet);
+    }
 
+    #[test]
+    fn test_block_min_max() {
+        let (index, time, _, _) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let fast_field = reader.fast_fields().u64(time).unwrap();
+        let blocks = BlockMinMax::for_segment(reader, time, &fast_field, None);
+        let values = 441..=782;
+        assert_eq!(blocks.num_blocks(), 5);
+        assert_eq!(blocks.block_match(0, &values), BlockMatch::Some);
+        assert_eq!(blocks.block_match(1024, &values), BlockMatch::All);
+        assert_eq!(blocks.block_match(2047, &values), BlockMatch::All);
+        assert_eq!(blocks.block_match(3072, &values), BlockMatch::None);
+        assert_eq!(blocks.block_end(3072), 4096);
+        assert_eq!(blocks.block_end(4096), 5001);
+        // 最后一块里有篇没有 time 的文档, 读出来是 0
+        assert_eq!(blocks.overlap(&values), (3977, 2953));
+    }
 
-impl Weight for CatWeight {
-    fn scorer(&self, reader: &SegmentReader) -> Result<Box<Scorer>, TantivyError> {
-        self.scorer7(reader)
+    #[test]
+    fn test_limit() {
+        let (index, time, _, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let mut query = CatQuery::new(filter(status, None), range(time, Bound::Included(150), Bound::Unbounded), 10);
+        assert_eq!(query.total_hits(), None);
+        // 第 10 篇的值是 1763, 和它相同的 4989 也要收进来
+        let weight = query.weight(&searcher, false).unwrap();
+        assert_eq!(docs(weight.scorer(reader).unwrap().as_mut()), (4989..5000).collect::<Vec<DocId>>());
+        assert_eq!(query.total_hits(), Some((4850, true)));
+
+        query.set_order(SortOrder::Asc);
+        query.set_track_total_hits(100);
+        let weight = query.weight(&searcher, false).unwrap();
+        assert_eq!(docs(weight.scorer(reader).unwrap().as_mut()), (150..162).collect::<Vec<DocId>>());
+        assert_eq!(query.total_hits(), Some((100, false)));
+
+        let mut query = CatQuery::new(filter(status, Some(3)), range(time, Bound::Included(150), Bound::Unbounded), 0);
+        query.set_limit(5);
+        query.set_track_total_hits(10000);
+        let weight = query.weight(&searcher, false).unwrap();
+        assert_eq!(docs(weight.scorer(reader).unwrap().as_mut()), vec![4966, 4973, 4980, 4987, 4994]);
+        assert_eq!(query.total_hits(), Some((693, true)));
     }
 
+    #[test]
+    fn test_top_docs_collector() {
+        let (index, time, _, status) = index();
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        index_writer.add_document(doc!(time => 1763u64, status => 3u64));
+        index_writer.commit().unwrap();
+        let reader = index.reader().unwrap();
+        reader.reload().unwrap();
+        let searcher = reader.searcher();
+        assert_eq!(searcher.segment_readers().len(), 2);
+        let mut query = CatQuery::new(filter(status, Some(3)), range(time, Bound::Included(150), Bound::Unbounded), 4);
+        let weight = query.weight(&searcher, false).unwrap();
+        let mut ordered = vec![];
+        weight.scorer(searcher.segment_reader(0)).unwrap().for_each(&mut |doc, _| ordered.push(doc));
+        assert_eq!(ordered, vec![4994, 4987, 4980, 4973]);
 
-    fn explain(&self, reader: &SegmentReader, doc: u32) -> Result<Explanation, TantivyError> {
-        let mut scorer = self.scorer(reader)?;
-        if scorer.skip_next(doc) != SkipResult::Reached {
-            return Err( TantivyError::InvalidArgument(format!("Document #({}) does not match", doc)));
-        }
-        Ok(Explanation::new("CatQuery", 1.0f32))
+        let values = |top_docs: Vec<(u64, DocAddress)>| top_docs.into_iter().map(|(value, _)| value).collect::<Vec<_>>();
+        let top_docs = searcher.search(&query, &query.top_docs().unwrap()).unwrap();
+        assert_eq!(values(top_docs), vec![1764, 1763, 1762, 1760]);
+        query.set_order(SortOrder::Asc);
+        let top_docs = searcher.search(&query, &query.top_docs().unwrap()).unwrap();
+        assert_eq!(top_docs[0], (150, DocAddress(0, 150)));
+        assert_eq!(values(top_docs), vec![150, 152, 154, 157]);
+        query.set_limit(0);
+        assert!(query.top_docs().is_none());
     }
-}
 
+    #[test]
+    fn test_explain() {
+        let (index, time, slow_time, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let reader = searcher.segment_reader(0);
+        let mut query = CatQuery::new(filter(status, Some(3)), range(time, Bound::Included(110), Bound::Unbounded), 0);
+        query.add_range(range(slow_time, Bound::Unbounded, Bound::Excluded(400)));
+        let weight = query.weight(&searcher, true).unwrap();
+        let explanation = weight.explain(reader, 31).unwrap().to_pretty_json();
+        assert!(explanation.contains("range bitset (range docs 900"), "{}", explanation);
+        assert!(explanation.contains("time in [110, +inf), value 110"), "{}", explanation);
+        assert!(explanation.contains("slow_time in (-inf, 400), value 110"), "{}", explanation);
+        assert!(explanation.contains("TermQuery"), "{}", explanation);
+        // 内层查询不满足, 范围不满足, 没有值的文档
+        assert!(weight.explain(reader, 30).is_err());
+        assert!(weight.explain(reader, 3).is_err());
+        assert!(weight.explain(reader, 1802).is_err());
 
+        // 没有值的文档在 fast field 里读出来是 0, 要查 postings 才知道它不在范围内
+        let query = CatQuery::new(filter(status, Some(2)), range(time, Bound::Unbounded, Bound::Included(120)), 5);
+        let weight = query.weight(&searcher, false).unwrap();
+        assert!(weight.explain(reader, 5000).is_err());
+        // 不在前 5 篇里也能解释
+        let explanation = weight.explain(reader, 2).unwrap().to_pretty_json();
+        assert!(explanation.contains("top 5 Desc"), "{}", explanation);
+        assert!(explanation.contains("time in (-inf, 120], value 100"), "{}", explanation);
+    }
+}
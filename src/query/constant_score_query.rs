@@ -0,0 +1,98 @@
+use tantivy::query::{Query, Weight, Scorer, Explanation, ConstScorer, BitSetDocSet};
+use tantivy::{Searcher, TantivyError, SegmentReader, DocId, DocSet, SkipResult, Score, BitSet};
+use std::sync::Arc;
+use super::filter_cache::{collect_bitset, FilterCache, FilterKey};
+
+/// 匹配 `filter` 的文档, 分数都是 `score`.
+/// 有缓存时常用的 filter 按 segment 缓存命中的文档, 否则每次都现算
+#[derive(Debug)]
+pub struct ConstantScoreQuery {
+    filter: Box<dyn Query>,
+    score: Score,
+    cache: Option<Arc<FilterCache>>,
+}
+
+impl Clone for ConstantScoreQuery {
+    fn clone(&self) -> Self {
+        ConstantScoreQuery {
+            filter: self.filter.box_clone(),
+            score: self.score,
+            cache: self.cache.clone(),
+        }
+    }
+}
+
+impl ConstantScoreQuery {
+    pub fn new(filter: Box<dyn Query>, score: Score) -> Self {
+        ConstantScoreQuery {
+            filter,
+            score,
+            cache: None,
+        }
+    }
+
+    pub fn set_filter_cache(&mut self, cache: Option<Arc<FilterCache>>) {
+        self.cache = cache;
+    }
+
+    pub fn filter(&self) -> &dyn Query {
+        self.filter.as_ref()
+    }
+
+    pub fn score(&self) -> Score {
+        self.score
+    }
+}
+
+impl Query for ConstantScoreQuery {
+    fn weight(&self, searcher: &Searcher, _scoring_enabled: bool) -> Result<Box<dyn Weight>, TantivyError> {
+        let filter_key = self.cache.as_ref().and_then(|cache| {
+            let key = FilterKey::query(searcher.schema(), self.filter.as_ref())?;
+            cache.record(&key);
+            Some(key)
+        });
+        Ok(Box::new(ConstantScoreWeight {
+            weight: self.filter.weight(searcher, false)?,
+            filter_key,
+            score: self.score,
+            cache: self.cache.clone(),
+        }))
+    }
+}
+
+struct ConstantScoreWeight {
+    weight: Box<dyn Weight>,
+    filter_key: Option<FilterKey>,
+    score: Score,
+    cache: Option<Arc<FilterCache>>,
+}
+
+impl Weight for ConstantScoreWeight {
+    fn scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>, TantivyError> {
+        let bitset = match (&self.cache, &self.filter_key) {
+            (Some(cache), Some(key)) => cache.cached_bitset(reader, key, || {
+                Ok(collect_bitset(self.weight.scorer(reader)?, reader.max_doc()))
+            })?,
+            _ => None,
+        };
+        let docset: Box<dyn DocSet> = match bitset {
+            Some(bitset) => Box::new(BitSetDocSet::from(BitSet::clone(&bitset))),
+            None => Box::new(self.weight.scorer(reader)?),
+        };
+        let mut scorer = ConstScorer::new(docset);
+        scorer.set_score(self.score);
+        Ok(Box::new(scorer))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation, TantivyError> {
+        let mut scorer = self.scorer(reader)?;
+        if scorer.skip_next(doc) != SkipResult::Reached {
+            return Err(TantivyError::InvalidArgument(format!("Document #({}) does not match", doc)));
+        }
+        Ok(Explanation::new("ConstantScore", self.score))
+    }
+
+    fn count(&self, reader: &SegmentReader) -> Result<u32, TantivyError> {
+        self.weight.count(reader)
+    }
+}
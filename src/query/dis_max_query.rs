@@ -0,0 +1,139 @@
+use tantivy::query::{Query, Weight, Scorer, Explanation};
+use tantivy::{Searcher, TantivyError, SegmentReader, DocSet, DocId, SkipResult, Score};
+
+/// 匹配任意一个子查询的文档, 分数是命中的子查询里最高的分数, 加上其余命中的分数乘 `tie_breaker`
+#[derive(Debug)]
+pub struct DisMaxQuery {
+    disjuncts: Vec<Box<dyn Query>>,
+    tie_breaker: Score,
+}
+
+impl Clone for DisMaxQuery {
+    fn clone(&self) -> Self {
+        DisMaxQuery {
+            disjuncts: self.disjuncts.iter().map(|q| q.box_clone()).collect(),
+            tie_breaker: self.tie_breaker,
+        }
+    }
+}
+
+impl DisMaxQuery {
+    pub fn new(disjuncts: Vec<Box<dyn Query>>, tie_breaker: Score) -> Self {
+        DisMaxQuery {
+            disjuncts,
+            tie_breaker,
+        }
+    }
+
+    pub fn disjuncts(&self) -> &[Box<dyn Query>] {
+        &self.disjuncts
+    }
+
+    pub fn tie_breaker(&self) -> Score {
+        self.tie_breaker
+    }
+}
+
+impl Query for DisMaxQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>, TantivyError> {
+        let weights = self.disjuncts.iter()
+            .map(|q| q.weight(searcher, scoring_enabled))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Box::new(DisMaxWeight {
+            weights,
+            tie_breaker: self.tie_breaker,
+        }))
+    }
+}
+
+struct DisMaxWeight {
+    weights: Vec<Box<dyn Weight>>,
+    tie_breaker: Score,
+}
+
+impl Weight for DisMaxWeight {
+    fn scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>, TantivyError> {
+        let mut scorers = vec![];
+        for weight in &self.weights {
+            let mut scorer = weight.scorer(reader)?;
+            if scorer.advance() {
+                scorers.push(scorer);
+            }
+        }
+        Ok(Box::new(DisMaxScorer {
+            scorers,
+            tie_breaker: self.tie_breaker,
+            doc: 0,
+            score: 0f32,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation, TantivyError> {
+        let mut scorer = self.scorer(reader)?;
+        if scorer.skip_next(doc) != SkipResult::Reached {
+            return Err(TantivyError::InvalidArgument(format!("Document #({}) does not match", doc)));
+        }
+        let mut explanation = Explanation::new(format!("DisMax, max plus {} times others of ...", self.tie_breaker), scorer.score());
+        for weight in &self.weights {
+            if let Ok(child) = weight.explain(reader, doc) {
+                explanation.add_detail(child);
+            }
+        }
+        Ok(explanation)
+    }
+}
+
+/// `scorers` 里的每个 scorer 都停在还没输出过的文档上
+struct DisMaxScorer {
+    scorers: Vec<Box<dyn Scorer>>,
+    tie_breaker: Score,
+    doc: DocId,
+    score: Score,
+}
+
+impl DocSet for DisMaxScorer {
+    fn advance(&mut self) -> bool {
+        let candidate = match self.scorers.iter().map(|s| s.doc()).min() {
+            Some(candidate) => candidate,
+            None => return false,
+        };
+        let mut max = 0f32;
+        let mut sum = 0f32;
+        let mut i = 0;
+        while i < self.scorers.len() {
+            if self.scorers[i].doc() != candidate {
+                i += 1;
+                continue;
+            }
+            let score = self.scorers[i].score();
+            max = max.max(score);
+            sum += score;
+            if self.scorers[i].advance() {
+                i += 1;
+            } else {
+                self.scorers.swap_remove(i);
+            }
+        }
+        self.doc = candidate;
+        self.score = max + self.tie_breaker * (sum - max);
+        true
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorers.iter().map(|s| s.size_hint()).max().unwrap_or(0)
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "DisMaxScorer"
+    }
+}
+
+impl Scorer for DisMaxScorer {
+    fn score(&mut self) -> Score {
+        self.score
+    }
+}
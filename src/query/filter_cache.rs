@@ -0,0 +1,340 @@
+use tantivy::query::{Query, Scorer};
+use tantivy::schema::{Field, Schema};
+use tantivy::directory::WatchHandle;
+use tantivy::{BitSet, Directory, Index, IndexReader, Searcher, SegmentId, SegmentReader, TantivyError};
+use serde_json::Value;
+use std::collections::{Bound, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use crate::query_dsl::ToDsl;
+use super::block_min_max::BlockMinMax;
+
+/// 所有缓存的 bitset 加起来最多占用的字节数
+const CAPACITY: usize = 64 * 1024 * 1024;
+/// 记录最近多少次过滤条件的使用
+const HISTORY: usize = 256;
+/// 最近用过几次的过滤条件才缓存成 bitset
+const MIN_USES: usize = 2;
+
+/// 缓存的是哪个过滤条件
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FilterKey {
+    /// 数值字段上的范围, 边界是 term 里的编码
+    Range(Field, Bound<u64>, Bound<u64>),
+    /// 过滤查询规范化之后的 dsl
+    Query(String),
+}
+
+impl FilterKey {
+    /// 查询转不成 dsl 时不缓存. bool 里同一种子句的顺序不影响结果, 排好序后作为 key
+    pub fn query(schema: &Schema, query: &dyn Query) -> Option<FilterKey> {
+        let mut dsl = query.to_dsl(schema).ok()?;
+        canonicalize(&mut dsl);
+        Some(FilterKey::Query(dsl.to_string()))
+    }
+}
+
+fn canonicalize(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                canonicalize(value);
+                if let ("must" | "filter" | "should" | "must_not", Value::Array(clauses)) = (key.as_str(), value) {
+                    clauses.sort_by_cached_key(|clause| clause.to_string());
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(canonicalize),
+        _ => {}
+    }
+}
+
+struct Entry {
+    bitset: Arc<BitSet>,
+    bytes: usize,
+    used: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    entries: HashMap<(SegmentId, FilterKey), Entry>,
+    bytes: usize,
+    tick: u64,
+    /// 最近创建 weight 时用到的过滤条件, 最新的在后面
+    history: VecDeque<FilterKey>,
+    blocks: HashMap<(SegmentId, Field), Arc<BlockMinMax>>,
+}
+
+/// 一个索引的过滤缓存, 按 segment 缓存常用的过滤条件命中的文档和 fast field 每块的 min/max.
+///
+/// 和 `IndexReader` 放在一起, 用 `invalidate_on_reload` 在 reload 之后清掉不在新 searcher 里的 segment.
+/// bitset 里不去掉删除的文档, segment 不变时一直有效; 超过 `CAPACITY` 时淘汰最久没用过的.
+/// 只用过一次的过滤条件不缓存, 直接用 scorer, 省得为一次性的查询生成 bitset
+#[derive(Default)]
+pub struct FilterCache {
+    entries: Mutex<Entries>,
+}
+
+impl std::fmt::Debug for FilterCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FilterCache")
+    }
+}
+
+impl FilterCache {
+    /// 创建 weight 时记一次 `key` 的使用
+    pub fn record(&self, key: &FilterKey) {
+        let mut entries = self.entries.lock().expect("filter cache");
+        if entries.history.len() == HISTORY {
+            entries.history.pop_front();
+        }
+        entries.history.push_back(key.clone());
+    }
+
+    /// 没有缓存时用 `build` 生成并放进缓存, 给不管缓不缓存都要生成 bitset 的调用方用
+    pub fn bitset<F>(&self, reader: &SegmentReader, key: FilterKey, build: F) -> Result<Arc<BitSet>, TantivyError>
+    where
+        F: FnOnce() -> Result<BitSet, TantivyError>,
+    {
+        let key = (reader.segment_id(), key);
+        if let Some(bitset) = self.get(&key) {
+            return Ok(bitset);
+        }
+        // 生成 bitset 时不持有锁, 并发的相同查询可能各算一次
+        let bitset = Arc::new(build()?);
+        self.insert(key, bitset.clone());
+        Ok(bitset)
+    }
+
+    /// 有缓存, 或者 `key` 最近用过不止一次时用 `build` 生成并放进缓存;
+    /// 否则返回 None, 调用方直接用 scorer, 不生成 bitset
+    pub fn cached_bitset<F>(&self, reader: &SegmentReader, key: &FilterKey, build: F) -> Result<Option<Arc<BitSet>>, TantivyError>
+    where
+        F: FnOnce() -> Result<BitSet, TantivyError>,
+    {
+        let key = (reader.segment_id(), key.clone());
+        if let Some(bitset) = self.get(&key) {
+            return Ok(Some(bitset));
+        }
+        let uses = self.entries.lock().expect("filter cache").history.iter().filter(|k| **k == key.1).count();
+        if uses < MIN_USES || bitset_bytes(reader.max_doc()) > CAPACITY {
+            return Ok(None);
+        }
+        let bitset = Arc::new(build()?);
+        self.insert(key, bitset.clone());
+        Ok(Some(bitset))
+    }
+
+    fn get(&self, key: &(SegmentId, FilterKey)) -> Option<Arc<BitSet>> {
+        let mut entries = self.entries.lock().expect("filter cache");
+        entries.tick += 1;
+        let tick = entries.tick;
+        let entry = entries.entries.get_mut(key)?;
+        entry.used = tick;
+        Some(entry.bitset.clone())
+    }
+
+    fn insert(&self, key: (SegmentId, FilterKey), bitset: Arc<BitSet>) {
+        let bytes = bitset_bytes(bitset.max_value());
+        if bytes > CAPACITY {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("filter cache");
+        entries.tick += 1;
+        let used = entries.tick;
+        if let Some(old) = entries.entries.insert(key, Entry { bitset, bytes, used }) {
+            entries.bytes -= old.bytes;
+        }
+        entries.bytes += bytes;
+        while entries.bytes > CAPACITY {
+            let oldest = entries.entries.iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone())
+                .expect("cache over capacity is not empty");
+            let entry = entries.entries.remove(&oldest).expect("oldest entry");
+            entries.bytes -= entry.bytes;
+        }
+    }
+
+    /// segment 是只读的, 同一个 segment 的同一个字段只算一次
+    pub fn blocks<F>(&self, reader: &SegmentReader, field: Field, build: F) -> Arc<BlockMinMax>
+    where
+        F: FnOnce() -> BlockMinMax,
+    {
+        let key = (reader.segment_id(), field);
+        if let Some(blocks) = self.entries.lock().expect("filter cache").blocks.get(&key) {
+            return blocks.clone();
+        }
+        let blocks = Arc::new(build());
+        self.entries.lock().expect("filter cache").blocks.insert(key, blocks.clone());
+        blocks
+    }
+
+    /// 只保留 `searcher` 里还在的 segment
+    pub fn retain(&self, searcher: &Searcher) {
+        let segments: HashSet<SegmentId> = searcher.segment_readers().iter().map(|r| r.segment_id()).collect();
+        let mut entries = self.entries.lock().expect("filter cache");
+        let mut removed = 0;
+        entries.entries.retain(|(segment, _), entry| {
+            let keep = segments.contains(segment);
+            if !keep {
+                removed += entry.bytes;
+            }
+            keep
+        });
+        entries.bytes -= removed;
+        entries.blocks.retain(|(segment, _), _| segments.contains(segment));
+    }
+
+    /// 索引有新的 commit 时 reload `reader`, 再清掉不在新 searcher 里的 segment. 丢掉返回的 handle 后不再清理.
+    ///
+    /// 还在执行的旧 searcher 之后可能再放进被合并掉的 segment, 它们会在下一次 reload 时清掉
+    pub fn invalidate_on_reload(self: &Arc<Self>, index: &Index, reader: &IndexReader) -> Result<WatchHandle, TantivyError> {
+        let cache = Arc::clone(self);
+        let reader = reader.clone();
+        // reader 自己也在监听同一个事件, 先后顺序不确定, 这里自己 reload 一次
+        index.directory().watch(Box::new(move || {
+            if reader.reload().is_ok() {
+                cache.retain(&reader.searcher());
+            }
+        }))
+    }
+}
+
+fn bitset_bytes(max_doc: u32) -> usize {
+    (max_doc as usize).div_ceil(64) * 8
+}
+
+/// 把 `scorer` 的所有文档放进 bitset
+pub fn collect_bitset(mut scorer: Box<dyn Scorer>, max_doc: u32) -> BitSet {
+    let mut bitset = BitSet::with_max_value(max_doc);
+    scorer.for_each(&mut |doc, _| bitset.insert(doc));
+    bitset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::collector::Count;
+    use tantivy::query::{BooleanQuery, Occur, TermQuery};
+    use tantivy::schema::{IndexRecordOption, INDEXED};
+    use tantivy::{doc, ReloadPolicy, Term};
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+    use crate::query_builder::TimeRangePolicy;
+    use crate::query_parser::parse;
+
+    fn term(field: Field, value: u64) -> Box<dyn Query> {
+        Box::new(TermQuery::new(Term::from_field_u64(field, value), IndexRecordOption::Basic))
+    }
+
+    #[test]
+    fn test_query_key_ignores_clause_order() {
+        let mut schema_builder = Schema::builder();
+        let status = schema_builder.add_u64_field("status", INDEXED);
+        let schema = schema_builder.build();
+        let a = BooleanQuery::from(vec![(Occur::Must, term(status, 1)), (Occur::Must, term(status, 2)), (Occur::MustNot, term(status, 3))]);
+        let b = BooleanQuery::from(vec![(Occur::MustNot, term(status, 3)), (Occur::Must, term(status, 2)), (Occur::Must, term(status, 1))]);
+        let c = BooleanQuery::from(vec![(Occur::Must, term(status, 1)), (Occur::MustNot, term(status, 2))]);
+        assert_eq!(FilterKey::query(&schema, &a), FilterKey::query(&schema, &b));
+        assert_ne!(FilterKey::query(&schema, &a), FilterKey::query(&schema, &c));
+    }
+
+    fn index() -> (Index, Field) {
+        let mut schema_builder = Schema::builder();
+        let status = schema_builder.add_u64_field("status", INDEXED);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..100u64 {
+            index_writer.add_document(doc!(status => i % 3));
+        }
+        index_writer.commit().unwrap();
+        (index, status)
+    }
+
+    fn cached_segments(cache: &FilterCache) -> HashSet<SegmentId> {
+        cache.entries.lock().unwrap().entries.keys().map(|(segment, _)| *segment).collect()
+    }
+
+    #[test]
+    fn test_bitset_is_reused_until_segment_is_gone() {
+        let (index, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let segment = searcher.segment_reader(0);
+        let key = FilterKey::query(searcher.schema(), term(status, 1).as_ref()).unwrap();
+        let build = || {
+            let weight = term(status, 1).weight(&searcher, false)?;
+            Ok(collect_bitset(weight.scorer(segment)?, segment.max_doc()))
+        };
+        let cache = FilterCache::default();
+        let first = cache.bitset(segment, key.clone(), build).unwrap();
+        assert_eq!(first.len(), 33);
+        let second = cache.bitset(segment, key.clone(), || panic!("cached")).unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // reload 之后的 searcher 里没有这个 segment 了
+        let other = Index::create_in_ram(index.schema());
+        other.writer_with_num_threads(1, 10_000_000).unwrap().commit().unwrap();
+        cache.retain(&other.reader().unwrap().searcher());
+        let rebuilt = cache.bitset(segment, key, || Ok(BitSet::with_max_value(1))).unwrap();
+        assert_eq!(rebuilt.max_value(), 1);
+    }
+
+    #[test]
+    fn test_filters_used_once_are_not_materialized() {
+        let (index, status) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let segment = searcher.segment_reader(0);
+        let key = FilterKey::query(searcher.schema(), term(status, 1).as_ref()).unwrap();
+        let cache = FilterCache::default();
+        cache.record(&key);
+        assert!(cache.cached_bitset(segment, &key, || panic!("used once")).unwrap().is_none());
+        cache.record(&key);
+        let bitset = cache.cached_bitset(segment, &key, || Ok(BitSet::with_max_value(7))).unwrap().unwrap();
+        assert_eq!(bitset.max_value(), 7);
+        let cached = cache.cached_bitset(segment, &key, || panic!("cached")).unwrap().unwrap();
+        assert!(Arc::ptr_eq(&bitset, &cached));
+    }
+
+    #[test]
+    fn test_filter_clauses_are_cached() {
+        let (index, _) = index();
+        let searcher = index.reader().unwrap().searcher();
+        let cache = Arc::new(FilterCache::default());
+        let dsl = serde_json::json!({"bool": {"must": {"match_all": {}}, "filter": {"term": {"status": 1}}}});
+        let search = || {
+            let query = parse(&dsl, &index, Rc::new(TimeRangePolicy::default()), Some(cache.clone())).unwrap();
+            searcher.search(query.as_ref(), &Count).unwrap()
+        };
+        assert_eq!(search(), 33);
+        assert!(cached_segments(&cache).is_empty());
+        assert_eq!(search(), 33);
+        let key = FilterKey::query(searcher.schema(), term(index.schema().get_field("status").unwrap(), 1).as_ref()).unwrap();
+        let segment = searcher.segment_reader(0);
+        assert_eq!(cache.bitset(segment, key, || panic!("cached")).unwrap().len(), 33);
+    }
+
+    #[test]
+    fn test_invalidate_on_reload() {
+        let (index, status) = index();
+        let reader = index.reader_builder().reload_policy(ReloadPolicy::Manual).try_into().unwrap();
+        let cache = Arc::new(FilterCache::default());
+        let _handle = cache.invalidate_on_reload(&index, &reader).unwrap();
+        let searcher = reader.searcher();
+        let old = searcher.segment_reader(0).segment_id();
+        let key = FilterKey::query(searcher.schema(), term(status, 1).as_ref()).unwrap();
+        cache.bitset(searcher.segment_reader(0), key, || Ok(BitSet::with_max_value(1))).unwrap();
+        assert!(cached_segments(&cache).contains(&old));
+
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        index_writer.delete_all_documents().unwrap();
+        index_writer.add_document(doc!(status => 1u64));
+        index_writer.commit().unwrap();
+        // 回调在 tantivy 自己的线程里执行
+        let start = Instant::now();
+        while cached_segments(&cache).contains(&old) {
+            assert!(start.elapsed() < Duration::from_secs(10), "segment is still cached after reload");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reader.searcher().num_docs(), 1);
+    }
+}
@@ -0,0 +1,548 @@
+use tantivy::query::{Query, Weight, Scorer, Explanation, RangeQuery};
+use tantivy::schema::{Field, Type};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::{Searcher, TantivyError, SegmentReader, DocSet, DocId, SkipResult, Score, BitSet};
+use std::collections::Bound;
+use super::filter_cache::collect_bitset;
+
+/// `field_value_factor` 的 modifier, 和 es 一样.
+/// `log` 和 `ln` 把小于 1 的值当成 1, 0 的分数是 0 而不是 -inf
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Modifier {
+    None,
+    Log,
+    Log1p,
+    Log2p,
+    Ln,
+    Ln1p,
+    Ln2p,
+    Square,
+    Sqrt,
+    Reciprocal,
+}
+
+impl Modifier {
+    pub fn name(self) -> &'static str {
+        match self {
+            Modifier::None => "none",
+            Modifier::Log => "log",
+            Modifier::Log1p => "log1p",
+            Modifier::Log2p => "log2p",
+            Modifier::Ln => "ln",
+            Modifier::Ln1p => "ln1p",
+            Modifier::Ln2p => "ln2p",
+            Modifier::Square => "square",
+            Modifier::Sqrt => "sqrt",
+            Modifier::Reciprocal => "reciprocal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Modifier> {
+        Some(match name {
+            "none" => Modifier::None,
+            "log" => Modifier::Log,
+            "log1p" => Modifier::Log1p,
+            "log2p" => Modifier::Log2p,
+            "ln" => Modifier::Ln,
+            "ln1p" => Modifier::Ln1p,
+            "ln2p" => Modifier::Ln2p,
+            "square" => Modifier::Square,
+            "sqrt" => Modifier::Sqrt,
+            "reciprocal" => Modifier::Reciprocal,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, v: f64) -> f64 {
+        match self {
+            Modifier::None => v,
+            Modifier::Log => v.max(1.0).log10(),
+            Modifier::Log1p => (v + 1.0).log10(),
+            Modifier::Log2p => (v + 2.0).log10(),
+            Modifier::Ln => v.max(1.0).ln(),
+            Modifier::Ln1p => v.ln_1p(),
+            Modifier::Ln2p => (v + 2.0).ln(),
+            Modifier::Square => v * v,
+            Modifier::Sqrt => v.sqrt(),
+            Modifier::Reciprocal => 1.0 / v,
+        }
+    }
+}
+
+/// 打分函数, 都读 u64 fast field
+#[derive(Clone, Debug)]
+pub enum ScoreFunction {
+    /// `modifier(factor * value)`, 没有值的文档用 `missing`
+    FieldValueFactor {
+        field: Field,
+        factor: f64,
+        modifier: Modifier,
+        missing: Option<u64>,
+    },
+    /// 高斯衰减: 离 `origin` 不超过 `offset` 时是 1, 距离 `offset + scale` 时是 `decay`
+    Gauss {
+        field: Field,
+        origin: u64,
+        scale: u64,
+        offset: u64,
+        decay: f64,
+    },
+}
+
+impl ScoreFunction {
+    fn field(&self) -> Field {
+        match self {
+            ScoreFunction::FieldValueFactor { field, .. } => *field,
+            ScoreFunction::Gauss { field, .. } => *field,
+        }
+    }
+
+    /// 给了 `missing` 时要区分没有值的文档, 返回字段上有 term 的文档的查询
+    fn presence(&self) -> Option<RangeQuery> {
+        match *self {
+            ScoreFunction::FieldValueFactor { field, missing: Some(_), .. } => {
+                Some(RangeQuery::new_term_bounds(field, Type::U64, &Bound::Unbounded, &Bound::Unbounded))
+            }
+            _ => None,
+        }
+    }
+
+    /// 函数作用的值. fast field 里没有值的文档存的也是 0, 所以用 `present` (倒排索引里有 term 的文档) 区分真的 0 和没有值
+    fn value(&self, reader: &FastFieldReader<u64>, present: Option<&BitSet>, doc: DocId) -> u64 {
+        match (self, present) {
+            (ScoreFunction::FieldValueFactor { missing: Some(missing), .. }, Some(present)) if !present.contains(doc) => *missing,
+            _ => reader.get(doc),
+        }
+    }
+
+    /// 结果换成 `Score` 之后是不是有限数
+    fn is_finite(&self, value: u64) -> bool {
+        (self.compute(value) as Score).is_finite()
+    }
+
+    /// 有没有可能在某些值上算出 inf 或者 NaN: reciprocal 遇到 0, 负的 factor 配 sqrt 或 log1p 这类, 或者 square 溢出.
+    /// 除了 reciprocal, 其余的都是单调的, 看两端就够了
+    fn may_be_non_finite(&self) -> bool {
+        match *self {
+            ScoreFunction::FieldValueFactor { modifier: Modifier::Reciprocal, .. } => true,
+            ScoreFunction::FieldValueFactor { .. } => !self.is_finite(0) || !self.is_finite(u64::MAX),
+            ScoreFunction::Gauss { .. } => false,
+        }
+    }
+
+    fn compute(&self, value: u64) -> f64 {
+        match *self {
+            ScoreFunction::FieldValueFactor { factor, modifier, .. } => {
+                modifier.apply(value as f64 * factor)
+            }
+            ScoreFunction::Gauss { origin, scale, offset, decay, .. } => {
+                let distance = value.abs_diff(origin).saturating_sub(offset) as f64;
+                let sigma2 = -(scale as f64).powi(2) / (2.0 * decay.ln());
+                (-distance * distance / (2.0 * sigma2)).exp()
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct WeightedFunction {
+    pub function: ScoreFunction,
+    pub weight: f64,
+}
+
+/// 各个函数的分数怎么合并
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScoreMode {
+    Multiply,
+    Sum,
+    Avg,
+    First,
+    Max,
+    Min,
+}
+
+impl ScoreMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            ScoreMode::Multiply => "multiply",
+            ScoreMode::Sum => "sum",
+            ScoreMode::Avg => "avg",
+            ScoreMode::First => "first",
+            ScoreMode::Max => "max",
+            ScoreMode::Min => "min",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ScoreMode> {
+        Some(match name {
+            "multiply" => ScoreMode::Multiply,
+            "sum" => ScoreMode::Sum,
+            "avg" => ScoreMode::Avg,
+            "first" => ScoreMode::First,
+            "max" => ScoreMode::Max,
+            "min" => ScoreMode::Min,
+            _ => return None,
+        })
+    }
+
+    fn combine(self, scores: &[f64], weights: &[f64]) -> f64 {
+        if scores.is_empty() {
+            return 1.0;
+        }
+        match self {
+            ScoreMode::Multiply => scores.iter().product(),
+            ScoreMode::Sum => scores.iter().sum(),
+            // 和 es 一样按函数的 weight 加权平均
+            ScoreMode::Avg => scores.iter().sum::<f64>() / weights.iter().sum::<f64>(),
+            ScoreMode::First => scores[0],
+            ScoreMode::Max => scores.iter().cloned().fold(f64::MIN, f64::max),
+            ScoreMode::Min => scores.iter().cloned().fold(f64::MAX, f64::min),
+        }
+    }
+}
+
+/// 函数的分数和查询的分数怎么合并
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BoostMode {
+    Multiply,
+    Replace,
+    Sum,
+    Avg,
+    Max,
+    Min,
+}
+
+impl BoostMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            BoostMode::Multiply => "multiply",
+            BoostMode::Replace => "replace",
+            BoostMode::Sum => "sum",
+            BoostMode::Avg => "avg",
+            BoostMode::Max => "max",
+            BoostMode::Min => "min",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BoostMode> {
+        Some(match name {
+            "multiply" => BoostMode::Multiply,
+            "replace" => BoostMode::Replace,
+            "sum" => BoostMode::Sum,
+            "avg" => BoostMode::Avg,
+            "max" => BoostMode::Max,
+            "min" => BoostMode::Min,
+            _ => return None,
+        })
+    }
+
+    fn combine(self, query_score: f64, function_score: f64) -> f64 {
+        match self {
+            BoostMode::Multiply => query_score * function_score,
+            BoostMode::Replace => function_score,
+            BoostMode::Sum => query_score + function_score,
+            BoostMode::Avg => (query_score + function_score) / 2.0,
+            BoostMode::Max => query_score.max(function_score),
+            BoostMode::Min => query_score.min(function_score),
+        }
+    }
+}
+
+/// 匹配 `query` 的文档, 分数由 `functions` 调整
+#[derive(Debug)]
+pub struct FunctionScoreQuery {
+    query: Box<dyn Query>,
+    functions: Vec<WeightedFunction>,
+    score_mode: ScoreMode,
+    boost_mode: BoostMode,
+}
+
+impl Clone for FunctionScoreQuery {
+    fn clone(&self) -> Self {
+        FunctionScoreQuery {
+            query: self.query.box_clone(),
+            functions: self.functions.clone(),
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+        }
+    }
+}
+
+impl FunctionScoreQuery {
+    pub fn new(query: Box<dyn Query>, functions: Vec<WeightedFunction>, score_mode: ScoreMode, boost_mode: BoostMode) -> Self {
+        FunctionScoreQuery {
+            query,
+            functions,
+            score_mode,
+            boost_mode,
+        }
+    }
+
+    pub fn query(&self) -> &dyn Query {
+        self.query.as_ref()
+    }
+
+    pub fn functions(&self) -> &[WeightedFunction] {
+        &self.functions
+    }
+
+    pub fn score_mode(&self) -> ScoreMode {
+        self.score_mode
+    }
+
+    pub fn boost_mode(&self) -> BoostMode {
+        self.boost_mode
+    }
+}
+
+impl Query for FunctionScoreQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>, TantivyError> {
+        let weight = self.query.weight(searcher, scoring_enabled)?;
+        if !scoring_enabled {
+            return Ok(weight);
+        }
+        let schema = searcher.schema();
+        let presence = self.functions.iter()
+            .map(|f| match f.function.presence() {
+                Some(_) if !schema.get_field_entry(f.function.field()).is_indexed() => {
+                    let name = schema.get_field_name(f.function.field()).to_string();
+                    Err(TantivyError::SchemaError(format!("Field {:?} is not indexed, missing values can not be told from 0", name)))
+                }
+                Some(query) => query.weight(searcher, false).map(Some),
+                None => Ok(None),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Box::new(FunctionScoreWeight {
+            weight,
+            presence,
+            functions: self.functions.clone(),
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+        }))
+    }
+}
+
+struct FunctionScoreWeight {
+    weight: Box<dyn Weight>,
+    /// 和 `functions` 一一对应, 给了 `missing` 的函数才有
+    presence: Vec<Option<Box<dyn Weight>>>,
+    functions: Vec<WeightedFunction>,
+    score_mode: ScoreMode,
+    boost_mode: BoostMode,
+}
+
+impl FunctionScoreWeight {
+    fn readers(&self, reader: &SegmentReader) -> Result<Vec<FieldValues>, TantivyError> {
+        self.functions.iter().zip(&self.presence)
+            .map(|(f, presence)| {
+                let field = f.function.field();
+                let values = reader.fast_fields().u64(field).ok_or_else(|| {
+                    let name = reader.schema().get_field_name(field).to_string();
+                    TantivyError::SchemaError(format!("Field {:?} is not a u64 fast field", name))
+                })?;
+                let present = match presence {
+                    Some(weight) => Some(collect_bitset(weight.scorer(reader)?, reader.max_doc())),
+                    None => None,
+                };
+                Ok(FieldValues { values, present })
+            })
+            .collect()
+    }
+
+    /// 和 es 一样, 函数的结果不是有限数时报错, 否则 NaN 会打乱排序.
+    /// 只有可能算出这种结果的函数才检查, 要先把这个 segment 里命中的文档过一遍
+    fn check_finite(&self, reader: &SegmentReader, readers: &[FieldValues]) -> Result<(), TantivyError> {
+        let checked: Vec<_> = self.functions.iter().zip(readers)
+            .filter(|(f, _)| f.function.may_be_non_finite())
+            .collect();
+        if checked.is_empty() {
+            return Ok(());
+        }
+        let mut error = None;
+        self.weight.scorer(reader)?.for_each(&mut |doc, _| {
+            if error.is_some() || reader.is_deleted(doc) {
+                return;
+            }
+            for (f, r) in &checked {
+                let value = r.value(&f.function, doc);
+                if !f.function.is_finite(value) {
+                    error = Some(TantivyError::InvalidArgument(format!("{:?} of value {} is not a finite number for document #({})", f.function, value, doc)));
+                    return;
+                }
+            }
+        });
+        error.map_or(Ok(()), Err)
+    }
+}
+
+/// 一个函数在 segment 上读的值
+struct FieldValues {
+    values: FastFieldReader<u64>,
+    present: Option<BitSet>,
+}
+
+impl FieldValues {
+    fn value(&self, function: &ScoreFunction, doc: DocId) -> u64 {
+        function.value(&self.values, self.present.as_ref(), doc)
+    }
+}
+
+impl Weight for FunctionScoreWeight {
+    fn scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>, TantivyError> {
+        let readers = self.readers(reader)?;
+        self.check_finite(reader, &readers)?;
+        Ok(Box::new(FunctionScoreScorer {
+            scorer: self.weight.scorer(reader)?,
+            readers,
+            functions: self.functions.clone(),
+            score_mode: self.score_mode,
+            boost_mode: self.boost_mode,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation, TantivyError> {
+        let mut scorer = self.scorer(reader)?;
+        if scorer.skip_next(doc) != SkipResult::Reached {
+            return Err(TantivyError::InvalidArgument(format!("Document #({}) does not match", doc)));
+        }
+        let mut explanation = Explanation::new(format!("FunctionScore, {:?} of query and {:?} of functions", self.boost_mode, self.score_mode), scorer.score());
+        explanation.add_detail(self.weight.explain(reader, doc)?);
+        let readers = self.readers(reader)?;
+        for (f, r) in self.functions.iter().zip(readers.iter()) {
+            let value = r.value(&f.function, doc);
+            explanation.add_detail(Explanation::new(format!("{:?} weight {} of value {}", f.function, f.weight, value), (f.function.compute(value) * f.weight) as Score));
+        }
+        Ok(explanation)
+    }
+
+    fn count(&self, reader: &SegmentReader) -> Result<u32, TantivyError> {
+        self.weight.count(reader)
+    }
+}
+
+struct FunctionScoreScorer {
+    scorer: Box<dyn Scorer>,
+    readers: Vec<FieldValues>,
+    functions: Vec<WeightedFunction>,
+    score_mode: ScoreMode,
+    boost_mode: BoostMode,
+}
+
+impl DocSet for FunctionScoreScorer {
+    fn advance(&mut self) -> bool {
+        self.scorer.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        self.scorer.skip_next(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.scorer.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorer.size_hint()
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "FunctionScoreScorer"
+    }
+}
+
+impl Scorer for FunctionScoreScorer {
+    fn score(&mut self) -> Score {
+        let doc = self.scorer.doc();
+        let mut scores = Vec::with_capacity(self.functions.len());
+        let mut weights = Vec::with_capacity(self.functions.len());
+        for (f, r) in self.functions.iter().zip(self.readers.iter()) {
+            let value = r.value(&f.function, doc);
+            scores.push(f.function.compute(value) * f.weight);
+            weights.push(f.weight);
+        }
+        let function_score = self.score_mode.combine(&scores, &weights);
+        self.boost_mode.combine(self.scorer.score() as f64, function_score) as Score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{Schema, FAST, INDEXED};
+    use tantivy::{doc, Index};
+
+    /// 第一篇是真的 0, 最后一篇没有值
+    fn index() -> (Index, Field) {
+        let mut schema_builder = Schema::builder();
+        let likes = schema_builder.add_u64_field("likes", INDEXED | FAST);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for &value in &[0u64, 9, 99] {
+            index_writer.add_document(doc!(likes => value));
+        }
+        index_writer.add_document(doc!());
+        index_writer.commit().unwrap();
+        (index, likes)
+    }
+
+    fn field_value_factor(field: Field, factor: f64, modifier: Modifier, missing: Option<u64>) -> FunctionScoreQuery {
+        let function = ScoreFunction::FieldValueFactor { field, factor, modifier, missing };
+        FunctionScoreQuery::new(Box::new(AllQuery), vec![WeightedFunction { function, weight: 1.0 }], ScoreMode::Multiply, BoostMode::Replace)
+    }
+
+    fn scores(index: &Index, query: &FunctionScoreQuery) -> Result<Vec<Score>, TantivyError> {
+        let searcher = index.reader().unwrap().searcher();
+        let weight = query.weight(&searcher, true)?;
+        let mut scores = vec![];
+        weight.scorer(searcher.segment_reader(0))?.for_each(&mut |_, score| scores.push(score));
+        Ok(scores)
+    }
+
+    #[test]
+    fn test_log_of_zero_is_finite() {
+        let (index, likes) = index();
+        let scores = |modifier| scores(&index, &field_value_factor(likes, 1.0, modifier, None)).unwrap();
+        assert_eq!(scores(Modifier::Log), vec![0.0, 9f32.log10(), 99f32.log10(), 0.0]);
+        assert_eq!(scores(Modifier::Ln), vec![0.0, 9f32.ln(), 99f32.ln(), 0.0]);
+        assert_eq!(scores(Modifier::Log1p), vec![0.0, 1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_missing_is_not_zero() {
+        let (index, likes) = index();
+        let query = field_value_factor(likes, 1.0, Modifier::Log1p, Some(999));
+        assert_eq!(scores(&index, &query).unwrap(), vec![0.0, 1.0, 2.0, 3.0]);
+        let searcher = index.reader().unwrap().searcher();
+        let weight = query.weight(&searcher, true).unwrap();
+        let explanation = weight.explain(searcher.segment_reader(0), 3).unwrap();
+        assert_eq!(explanation.value(), 3.0);
+        let explanation = explanation.to_pretty_json();
+        assert!(explanation.contains("of value 999"), "{}", explanation);
+        // 没建索引的字段分不出没有值的文档
+        let mut schema_builder = Schema::builder();
+        let likes = schema_builder.add_u64_field("likes", FAST);
+        let index = Index::create_in_ram(schema_builder.build());
+        let searcher = index.reader().unwrap().searcher();
+        assert!(field_value_factor(likes, 1.0, Modifier::None, Some(1)).weight(&searcher, true).is_err());
+    }
+
+    #[test]
+    fn test_non_finite_scores_are_errors() {
+        let (index, likes) = index();
+        // 0 的倒数, 负数的平方根和对数
+        assert!(scores(&index, &field_value_factor(likes, 1.0, Modifier::Reciprocal, None)).is_err());
+        assert!(scores(&index, &field_value_factor(likes, 1.0, Modifier::Reciprocal, Some(0))).is_err());
+        assert!(scores(&index, &field_value_factor(likes, -1.0, Modifier::Sqrt, None)).is_err());
+        assert!(scores(&index, &field_value_factor(likes, -1.0, Modifier::Log1p, None)).is_err());
+        assert!(scores(&index, &field_value_factor(likes, 1e30, Modifier::Square, None)).is_err());
+        // 只看命中的文档, 真的 0 和没有值的文档不命中时不报错
+        let function = ScoreFunction::FieldValueFactor { field: likes, factor: 1.0, modifier: Modifier::Reciprocal, missing: Some(1) };
+        let range = RangeQuery::new_u64(likes, 1..100);
+        let query = FunctionScoreQuery::new(Box::new(range), vec![WeightedFunction { function, weight: 1.0 }], ScoreMode::Multiply, BoostMode::Replace);
+        assert_eq!(scores(&index, &query).unwrap(), vec![1.0 / 9.0, 1.0 / 99.0]);
+        // 真的 0 不会被当成没有值
+        assert!(scores(&index, &field_value_factor(likes, 1.0, Modifier::Reciprocal, Some(1))).is_err());
+        assert_eq!(scores(&index, &field_value_factor(likes, -1.0, Modifier::Log, None)).unwrap(), vec![0.0; 4]);
+    }
+}
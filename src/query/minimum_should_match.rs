@@ -0,0 +1,140 @@
+use tantivy::query::{Query, Weight, Scorer, Explanation};
+use tantivy::{Searcher, TantivyError, SegmentReader, DocSet, DocId, SkipResult, Score};
+
+/// 至少命中 `minimum` 个子句的文档, 分数是命中的子句的分数之和
+#[derive(Debug)]
+pub struct MinimumShouldMatchQuery {
+    clauses: Vec<Box<dyn Query>>,
+    minimum: usize,
+}
+
+impl Clone for MinimumShouldMatchQuery {
+    fn clone(&self) -> Self {
+        MinimumShouldMatchQuery {
+            clauses: self.clauses.iter().map(|q| q.box_clone()).collect(),
+            minimum: self.minimum,
+        }
+    }
+}
+
+impl MinimumShouldMatchQuery {
+    pub fn new(clauses: Vec<Box<dyn Query>>, minimum: usize) -> Self {
+        MinimumShouldMatchQuery {
+            clauses,
+            minimum,
+        }
+    }
+
+    pub fn clauses(&self) -> &[Box<dyn Query>] {
+        &self.clauses
+    }
+
+    pub fn minimum(&self) -> usize {
+        self.minimum
+    }
+}
+
+impl Query for MinimumShouldMatchQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>, TantivyError> {
+        let weights = self.clauses.iter()
+            .map(|q| q.weight(searcher, scoring_enabled))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Box::new(MinimumShouldMatchWeight {
+            weights,
+            minimum: self.minimum,
+        }))
+    }
+}
+
+struct MinimumShouldMatchWeight {
+    weights: Vec<Box<dyn Weight>>,
+    minimum: usize,
+}
+
+impl Weight for MinimumShouldMatchWeight {
+    fn scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>, TantivyError> {
+        let mut scorers = vec![];
+        for weight in &self.weights {
+            let mut scorer = weight.scorer(reader)?;
+            if scorer.advance() {
+                scorers.push(scorer);
+            }
+        }
+        Ok(Box::new(MinimumShouldMatchScorer {
+            scorers,
+            minimum: self.minimum.max(1),
+            doc: 0,
+            score: 0f32,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation, TantivyError> {
+        let mut scorer = self.scorer(reader)?;
+        if scorer.skip_next(doc) != SkipResult::Reached {
+            return Err(TantivyError::InvalidArgument(format!("Document #({}) does not match", doc)));
+        }
+        let mut explanation = Explanation::new(format!("MinimumShouldMatch({}). Sum of ...", self.minimum), scorer.score());
+        for weight in &self.weights {
+            if let Ok(child) = weight.explain(reader, doc) {
+                explanation.add_detail(child);
+            }
+        }
+        Ok(explanation)
+    }
+}
+
+/// `scorers` 里的每个 scorer 都停在还没输出过的文档上
+struct MinimumShouldMatchScorer {
+    scorers: Vec<Box<dyn Scorer>>,
+    minimum: usize,
+    doc: DocId,
+    score: Score,
+}
+
+impl DocSet for MinimumShouldMatchScorer {
+    fn advance(&mut self) -> bool {
+        loop {
+            if self.scorers.len() < self.minimum {
+                return false;
+            }
+            let candidate = self.scorers.iter().map(|s| s.doc()).min().expect("scorers is not empty");
+            let mut count = 0;
+            let mut score = 0f32;
+            for scorer in self.scorers.iter_mut().filter(|s| s.doc() == candidate) {
+                count += 1;
+                score += scorer.score();
+            }
+            let mut i = 0;
+            while i < self.scorers.len() {
+                if self.scorers[i].doc() == candidate && !self.scorers[i].advance() {
+                    self.scorers.swap_remove(i);
+                } else {
+                    i += 1;
+                }
+            }
+            if count >= self.minimum {
+                self.doc = candidate;
+                self.score = score;
+                return true;
+            }
+        }
+    }
+
+    fn doc(&self) -> DocId {
+        self.doc
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.scorers.iter().map(|s| s.size_hint()).min().unwrap_or(0)
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "MinimumShouldMatchScorer"
+    }
+}
+
+impl Scorer for MinimumShouldMatchScorer {
+    fn score(&mut self) -> Score {
+        self.score
+    }
+}
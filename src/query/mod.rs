@@ -1,2 +1,13 @@
+mod block_min_max;
+mod filter_cache;
 mod cat_query;
-pub use cat_query::CatQuery;
\ No newline at end of file
+mod constant_score_query;
+mod dis_max_query;
+mod function_score_query;
+mod minimum_should_match;
+pub use cat_query::{CatQuery, CatRange, SortOrder};
+pub use constant_score_query::ConstantScoreQuery;
+pub use dis_max_query::DisMaxQuery;
+pub use filter_cache::FilterCache;
+pub use function_score_query::{FunctionScoreQuery, ScoreFunction, WeightedFunction, Modifier, ScoreMode, BoostMode};
+pub use minimum_should_match::MinimumShouldMatchQuery;
@@ -1,41 +1,232 @@
-use tantivy::schema::{Schema, IndexRecordOption, Field};
-use tantivy::query::{Query, Occur, BooleanQuery, TermQuery, RegexQuery, RangeQuery};
+use tantivy::schema::{Schema, IndexRecordOption, Field, FieldType, Facet, Type, Cardinality};
+use tantivy::query::{Query, Occur, BooleanQuery, TermQuery, RegexQuery, RangeQuery, AllQuery, EmptyQuery, FuzzyTermQuery, PhraseQuery, BoostQuery};
 use std::rc::Rc;
-use tantivy::Term;
+use std::sync::Arc;
+use tantivy::{Term, DateTime, Index, Score, i64_to_u64, f64_to_u64};
+use tantivy::chrono::{self, TimeZone, Utc};
+use serde_json::Value;
 use std::collections::Bound;
-use crate::query::CatQuery;
+use crate::query::{CatQuery, CatRange, ConstantScoreQuery, FilterCache, MinimumShouldMatchQuery};
+use crate::query_parser::ParseErrorReason;
 
+/// bool 的 `minimum_should_match`, 按 should 子句的个数换算成要命中的个数
+#[derive(Clone, Copy, Debug)]
+pub enum MinimumShouldMatch {
+    Count(i64),
+    Percentage(f64),
+}
+impl MinimumShouldMatch {
+    fn resolve(self, should_count: usize) -> usize {
+        let n = should_count as i64;
+        let required = match self {
+            MinimumShouldMatch::Count(c) if c < 0 => n + c,
+            MinimumShouldMatch::Count(c) => c,
+            MinimumShouldMatch::Percentage(p) if p < 0.0 => n - (n as f64 * -p / 100.0).floor() as i64,
+            MinimumShouldMatch::Percentage(p) => (n as f64 * p / 100.0).floor() as i64,
+        };
+        required.max(0) as usize
+    }
+}
+
+/// 一个可以合进 CatQuery 的数值或 date range, 边界都已经换成 term 里保序的 u64 编码:
+/// u64 是原值, i64 用 `i64_to_u64`, f64 用 `f64_to_u64`, date 是秒数再用 `i64_to_u64`.
+/// 比较和 `cardinality` 都按编码算, 和字段类型无关
+pub struct RangeCandidate<'a> {
+    pub name: &'a str,
+    pub left: Bound<u64>,
+    pub right: Bound<u64>,
+    pub occur: Occur,
+    pub boosted: bool,
+    /// 字段是单值的数值 fast field, CatQuery 可以按块的 min/max 过滤它
+    pub fast: bool,
+}
+impl<'a> RangeCandidate<'a> {
+    /// 范围内最多有多少个不同的值
+    pub fn cardinality(&self) -> u64 {
+        let start = match self.left {
+            Bound::Included(v) => v,
+            Bound::Excluded(v) => match v.checked_add(1) {
+                Some(v) => v,
+                None => return 0,
+            },
+            Bound::Unbounded => 0,
+        };
+        let end = match self.right {
+            Bound::Included(v) => v,
+            Bound::Excluded(v) => match v.checked_sub(1) {
+                Some(v) => v,
+                None => return 0,
+            },
+            Bound::Unbounded => u64::MAX,
+        };
+        if start > end {
+            0
+        } else {
+            (end - start).saturating_add(1)
+        }
+    }
+}
+
+/// 决定哪些 range 交给 CatQuery, 没选中的 range 都按普通的 RangeQuery 处理.
+/// 第一个选中的字段是 CatQuery 的主范围
+pub trait PrimaryRangePolicy {
+    fn accept(&self, candidate: &RangeCandidate) -> bool;
+}
+
+/// `TimeRangePolicy` 默认的 `max_cardinality`, 时间按毫秒存时是 31 天
+pub const DEFAULT_MAX_CARDINALITY: u64 = 31 * 24 * 3600 * 1000;
+
+/// 只选 must 的 range: 时间字段上范围内可能的值不能超过 `max_cardinality` 个, 只有下界的范围一般都会超过;
+/// 别的字段在 `dimensions` 里, 或者 `fast_field_dimensions` 打开时是数值 fast field, 就作为 CatQuery 的另一维.
+/// 默认只选时间字段
+pub struct TimeRangePolicy {
+    pub field: String,
+    pub max_cardinality: u64,
+    pub dimensions: Vec<String>,
+    pub fast_field_dimensions: bool,
+}
+impl Default for TimeRangePolicy {
+    fn default() -> Self {
+        TimeRangePolicy {
+            field: "time".to_string(),
+            max_cardinality: DEFAULT_MAX_CARDINALITY,
+            dimensions: vec![],
+            fast_field_dimensions: false,
+        }
+    }
+}
+impl PrimaryRangePolicy for TimeRangePolicy {
+    fn accept(&self, candidate: &RangeCandidate) -> bool {
+        //CatQuery 的分数是常量, 带 boost 的 range 不能合进去
+        if candidate.occur != Occur::Must || candidate.boosted {
+            return false;
+        }
+        if candidate.name == self.field {
+            return candidate.cardinality() <= self.max_cardinality;
+        }
+        (self.fast_field_dimensions && candidate.fast) || self.dimensions.iter().any(|d| d == candidate.name)
+    }
+}
+
+fn constant_score_query(filter: Box<dyn Query>, score: Score, cache: Option<Arc<FilterCache>>) -> Box<dyn Query> {
+    let mut query = ConstantScoreQuery::new(filter, score);
+    query.set_filter_cache(cache);
+    Box::new(query)
+}
+
+/// 没有合进 CatQuery 的范围换回 RangeQuery, 分数和合进去时一样
+fn range_query(range: CatRange, cache: Option<Arc<FilterCache>>) -> Box<dyn Query> {
+    let term = |v: u64| Term::from_field_u64(range.field, v);
+    let query = Box::new(RangeQuery::new_term_bounds(range.field, range.value_type, &range.left.map(term), &range.right.map(term)));
+    if range.score == 1.0 {
+        query
+    } else {
+        constant_score_query(query, range.score, cache)
+    }
+}
+
+/// 两个下界里更紧的那个
+fn max_left(a: Bound<u64>, b: Bound<u64>) -> Bound<u64> {
+    let key = |bound: &Bound<u64>| match *bound {
+        Bound::Unbounded => (0, 0),
+        Bound::Included(v) => (v, 1),
+        Bound::Excluded(v) => (v, 2),
+    };
+    if key(&a) >= key(&b) { a } else { b }
+}
+
+/// 两个上界里更紧的那个
+fn min_right(a: Bound<u64>, b: Bound<u64>) -> Bound<u64> {
+    let key = |bound: &Bound<u64>| match *bound {
+        Bound::Unbounded => (u64::MAX, 2),
+        Bound::Included(v) => (v, 1),
+        Bound::Excluded(v) => (v, 0),
+    };
+    if key(&a) <= key(&b) { a } else { b }
+}
 
 struct CatQueryBuilder {
-    c: Vec<(Occur, Box<Query>)>,
-    left: u64,
-    right: u64,
-    field: Option<Field>,
-    limit : usize
+    c: Vec<(Occur, Box<dyn Query>)>,
+    ranges: Vec<CatRange>,
+    limit : usize,
+    minimum_should_match: Option<MinimumShouldMatch>,
+    adjust_pure_negative: bool,
 }
 impl CatQueryBuilder {
-    fn build(mut self) -> Box<dyn Query>{
-        if let Some(field) = self.field {
-            Box::new(CatQuery::new(BooleanQuery::from(self.c), field, self.left, self.right, self.limit))
-        }else {
-            if self.c.len() == 1 {
-                let (a, b) =  self.c.pop().expect("");
-                return b;
-            }else{
-                Box::new(BooleanQuery::from(self.c))
+    fn new(limit: usize) -> Self {
+        CatQueryBuilder {
+            c: vec![],
+            ranges: vec![],
+            limit,
+            minimum_should_match: None,
+            adjust_pure_negative: true,
+        }
+    }
+    /// 把 should 子句按 minimum_should_match 收拢成一个 Must 子句
+    fn apply_minimum_should_match(&mut self) {
+        let minimum_should_match = match self.minimum_should_match {
+            Some(m) => m,
+            None => return,
+        };
+        let (should, mut c): (Vec<_>, Vec<_>) = self.c.drain(..).partition(|(o, _)| *o == Occur::Should);
+        let has_required = !self.ranges.is_empty() || c.iter().any(|(o, _)| *o == Occur::Must);
+        let required = minimum_should_match.resolve(should.len());
+        if should.is_empty() || required == 0 || (required == 1 && !has_required) {
+            c.extend(should);
+        } else if required > should.len() {
+            c.push((Occur::Must, Box::new(EmptyQuery)));
+        } else if required == should.len() {
+            // 全部要命中时就是 must, 写回 dsl 再解析也是这样
+            c.extend(should.into_iter().map(|(_, q)| (Occur::Must, q)));
+        } else if required == 1 {
+            c.push((Occur::Must, Box::new(BooleanQuery::from(should))));
+        } else {
+            let should = should.into_iter().map(|(_, q)| q).collect();
+            c.push((Occur::Must, Box::new(MinimumShouldMatchQuery::new(should, required))));
+        }
+        self.c = c;
+    }
+    fn build(mut self, cache: Option<Arc<FilterCache>>) -> Box<dyn Query>{
+        self.apply_minimum_should_match();
+        // 没有子句时什么都匹配; 只有 must_not 时和 es 一样按 adjust_pure_negative 补一个 match_all,
+        // 拿进 CatQuery 的 range 也算正向子句, 这时 bool 不是纯否定的
+        let pure_negative = self.c.iter().all(|(o, _)| *o == Occur::MustNot);
+        if self.c.is_empty() || (pure_negative && (self.adjust_pure_negative || !self.ranges.is_empty())) {
+            self.c.insert(0, (Occur::Must, Box::new(AllQuery)));
+        }
+        // range 被拿进 CatQuery 后只剩 should 的话, tantivy 会要求至少命中一个.
+        // es 里有 filter/must 时 should 是可选的, 补一个不打分的 match_all 让它们只参与打分
+        let has_must = self.c.iter().any(|(o, _)| *o == Occur::Must);
+        if !self.ranges.is_empty() && !has_must && self.c.iter().any(|(o, _)| *o == Occur::Should) {
+            self.c.insert(0, (Occur::Must, Box::new(ConstantScoreQuery::new(Box::new(AllQuery), 0.0))));
+        }
+        let mut ranges = self.ranges.into_iter();
+        if let Some(range) = ranges.next() {
+            let mut query = CatQuery::new(BooleanQuery::from(self.c), range, self.limit);
+            for range in ranges {
+                query.add_range(range);
             }
+            query.set_filter_cache(cache);
+            Box::new(query)
+        } else if self.c.len() == 1 && self.c[0].0 != Occur::MustNot {
+            let (_, b) = self.c.pop().expect("one clause");
+            b
+        } else {
+            Box::new(BooleanQuery::from(self.c))
         }
     }
-    fn push(&mut self, c: (Occur, Box<Query>)) {
+    fn push(&mut self, c: (Occur, Box<dyn Query>)) {
         self.c.push(c);
     }
-    fn add_range_query(&mut self, field: Field, left: u64, right: u64, occur: Occur) {
-        if let Some(field) = self.field {
-            self.c.push((occur, Box::new(RangeQuery::new_u64(field, left..right))));
-        }else {
-           self.left = left;
-            self.right = right;
-            self.field = Some(field);
+    /// 同一个字段上的多个范围取交集, 别的字段作为新的一维
+    fn add_range(&mut self, range: CatRange) {
+        match self.ranges.iter_mut().find(|r| r.field == range.field) {
+            Some(r) => {
+                r.left = max_left(r.left, range.left);
+                r.right = min_right(r.right, range.right);
+                r.score += range.score;
+            }
+            None => self.ranges.push(range),
         }
     }
 
@@ -44,46 +235,98 @@ pub struct QueryBuilder {
     c: CatQueryBuilder,
     p: Option<Box<QueryBuilder>>,
     s: Rc<Schema>,
+    i: Rc<Index>,
     o: Occur,
+    b: Option<f32>,
+    /// 当前子句在 filter 里, 只过滤不打分
+    f: bool,
+    r: Rc<dyn PrimaryRangePolicy>,
+    /// 过滤条件用的缓存, 没有时每次都现算
+    k: Option<Arc<FilterCache>>,
 }
 
 impl QueryBuilder {
-    pub fn new(schema: Schema, occur: Occur, size: usize) -> Self {
+    pub fn new(index: &Index, occur: Occur, size: usize) -> Self {
         QueryBuilder {
-            c: CatQueryBuilder {
-                c: vec![],
-                left: 0,
-                right: 0,
-                field: None,
-                limit: size
-            },
+            c: CatQueryBuilder::new(size),
             p: None,
-            s: Rc::new(schema),
-            o: occur
+            s: Rc::new(index.schema()),
+            i: Rc::new(index.clone()),
+            o: occur,
+            b: None,
+            f: false,
+            r: Rc::new(TimeRangePolicy::default()),
+            k: None,
         }
     }
+    /// 子节点会继承父节点的 policy, 要在 down 之前设置
+    pub fn primary_range_policy(mut self, policy: Rc<dyn PrimaryRangePolicy>) -> QueryBuilder {
+        self.r = policy;
+        self
+    }
+    /// 和 policy 一样由子节点继承, 要在 down 之前设置
+    pub fn filter_cache(mut self, cache: Option<Arc<FilterCache>>) -> QueryBuilder {
+        self.k = cache;
+        self
+    }
     pub fn down(self, occur: Occur) -> QueryBuilder {
         QueryBuilder {
-            c: CatQueryBuilder {
-                c: vec![],
-                left: 0,
-                right: 0,
-                field: None,
-                limit : 0
-            },
+            c: CatQueryBuilder::new(0),
             s: Rc::clone(&self.s),
+            i: Rc::clone(&self.i),
+            r: Rc::clone(&self.r),
+            k: self.k.clone(),
             p: Some(Box::new(self)),
-            o: occur
+            o: occur,
+            b: None,
+            f: false,
         }
     }
     pub fn also(mut self, occur: Occur) -> QueryBuilder {
         self.o = occur;
+        self.f = false;
+        self
+    }
+    /// 接下来的子句和 must 一样必须命中, 但分数是 0, 不影响排序; 子句常用时命中的文档会被缓存
+    pub fn filter(mut self) -> QueryBuilder {
+        self.o = Occur::Must;
+        self.f = true;
+        self
+    }
+    /// 只作用于接下来加入的那一个子句, bool 的 boost 要在 down 之前设置
+    pub fn boost(mut self, boost: f32) -> QueryBuilder {
+        self.b = Some(boost);
+        self
+    }
+    fn boosted(&self) -> bool {
+        !self.f && self.b.map(|b| b != 1.0).unwrap_or(false)
+    }
+    fn push(&mut self, query: Box<dyn Query>) {
+        let boost = self.b.take();
+        let query: Box<dyn Query> = match boost {
+            _ if self.f => constant_score_query(query, 0.0, self.k.clone()),
+            Some(b) if b != 1.0 => Box::new(BoostQuery::new(query, b)),
+            _ => query,
+        };
+        self.c.push((self.o, query));
+    }
+
+    pub fn minimum_should_match(mut self, minimum_should_match: MinimumShouldMatch) -> QueryBuilder {
+        self.c.minimum_should_match = Some(minimum_should_match);
+        self
+    }
+    pub fn adjust_pure_negative(mut self, adjust_pure_negative: bool) -> QueryBuilder {
+        self.c.adjust_pure_negative = adjust_pure_negative;
         self
     }
     pub fn up(self)-> QueryBuilder {
+        self.up_map(|q| q)
+    }
+    /// 和 up 一样回到父节点, 但先用 f 把构造好的子查询包一层
+    pub fn up_map<F: FnOnce(Box<dyn Query>) -> Box<dyn Query>>(self, f: F) -> QueryBuilder {
         match self.p {
             Some(mut p) => {
-                p.c.push((p.o, self.c.build()));
+                p.push(f(self.c.build(self.k)));
                 *p
             },
             None => {
@@ -91,88 +334,436 @@ impl QueryBuilder {
             }
         }
     }
-    pub fn add_term_query(mut self, field: &str, value: &str) -> Self {
-        let field= match self.s.get_field(field) {
-            Some(field) => field,
-            None => return self,
-        };
-        let query = TermQuery::new(Term::from_field_text(field, value), IndexRecordOption::Basic);
-        self.c.push((self.o, Box::new(query)));
+    /// 和 up 一样回到父节点, 构造好的子查询作为过滤条件, 分数固定为 `score`
+    pub fn up_constant_score(self, score: Score) -> QueryBuilder {
+        let cache = self.k.clone();
+        self.up_map(|q| constant_score_query(q, score, cache))
+    }
+    /// 子句不组成 bool, 原样交给 f, 给 dis_max 这类自己合并子句的查询用.
+    /// 这里不会组成 CatQuery, 已经拿进来的 range 换回普通的 RangeQuery 一起交给 f
+    pub fn up_clauses<F: FnOnce(Vec<Box<dyn Query>>) -> Box<dyn Query>>(self, f: F) -> QueryBuilder {
+        let cache = self.k;
+        let mut clauses: Vec<Box<dyn Query>> = self.c.c.into_iter().map(|(_, q)| q).collect();
+        clauses.extend(self.c.ranges.into_iter().map(|range| range_query(range, cache.clone())));
+        match self.p {
+            Some(mut p) => {
+                p.push(f(clauses));
+                *p
+            },
+            None => {
+                panic!("exceeding root");
+            }
+        }
+    }
+    fn field(&self, name: &str) -> Result<Field, ParseErrorReason> {
+        self.s.get_field(name).ok_or_else(|| ParseErrorReason::UnknownField(name.to_string()))
+    }
+    pub fn indexed(&self, field: Field) -> bool {
+        self.s.get_field_entry(field).is_indexed()
+    }
+    /// function_score 的打分函数只读 u64 fast field
+    pub fn fast_u64_field(&self, name: &str) -> Result<Field, ParseErrorReason> {
+        let field = self.field(name)?;
+        match self.s.get_field_entry(field).field_type() {
+            FieldType::U64(options) if options.is_fast() => Ok(field),
+            _ => Err(ParseErrorReason::WrongValueType("u64 fast field")),
+        }
+    }
+    /// 按 schema 里字段的类型把 json 值转成 term, 数字类型也接受字符串写法
+    pub fn term(&self, field: Field, value: &Value) -> Result<Term, ParseErrorReason> {
+        match self.s.get_field_entry(field).field_type() {
+            FieldType::Str(_) => Ok(Term::from_field_text(field, &str_value(value)?)),
+            FieldType::U64(_) => Ok(Term::from_field_u64(field, u64_value(value)?)),
+            FieldType::I64(_) => Ok(Term::from_field_i64(field, i64_value(value)?)),
+            FieldType::F64(_) => Ok(Term::from_field_f64(field, f64_value(value)?)),
+            FieldType::Date(_) => match date(value)? {
+                // 索引里的 date 只有秒, 带毫秒的值截断后会匹配到别的文档
+                d if d.timestamp_subsec_nanos() == 0 => Ok(Term::from_field_date(field, &d)),
+                _ => Err(ParseErrorReason::WrongValueType("date in whole seconds")),
+            },
+            FieldType::HierarchicalFacet => match value.as_str() {
+                Some(s) => Ok(Term::from_facet(field, &Facet::from_text(s))),
+                None => Err(ParseErrorReason::WrongValueType("facet")),
+            },
+            FieldType::Bytes => Err(ParseErrorReason::UnsupportedClause("term on bytes field".to_string())),
+        }
+    }
+    pub fn add_all_query(mut self) -> Self {
+        self.push(Box::new(AllQuery));
         self
     }
-    pub fn add_prefix_query(mut self, field: &str, value: &str) -> Self {
-        let field = match self.s.get_field(field) {
-            Some(field) => field,
-            None => return self,
-        };
-        let query = RegexQuery::new(format!(r"{}[u:\x00-u:\xFF]*", value), field);
-        self.c.push((self.o, Box::new(query)));
+    pub fn add_empty_query(mut self) -> Self {
+        self.push(Box::new(EmptyQuery));
         self
     }
-    pub fn build(self) -> Box<dyn Query> {
-        self.c.build()
+    pub fn add_term_query(mut self, field: &str, value: &Value) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        let query = TermQuery::new(self.term(field, value)?, IndexRecordOption::Basic);
+        self.push(Box::new(query));
+        Ok(self)
     }
-    pub fn add_range_query(mut self, field: &str, left: u64, right:u64, include_left:bool, include_right: bool) -> Self {
-        let field = match self.s.get_field(field) {
-            Some(field) => field,
-            None => return self
+    /// 写回 dsl 时是 regexp, 和 regexp 一样只支持文本字段
+    pub fn add_prefix_query(mut self, field: &str, value: &str) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        self.text_field(field, "prefix")?;
+        let query = RegexQuery::new(format!(r"{}[u:\x00-u:\xFF]*", escape_regex(value)), field);
+        self.push(Box::new(query));
+        Ok(self)
+    }
+    /// 多个值之间是 or 的关系, 空列表什么都匹配不到
+    pub fn add_terms_query(mut self, field: &str, values: &[Value]) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        let mut terms = values.iter()
+            .map(|v| Ok((Occur::Should, Box::new(TermQuery::new(self.term(field, v)?, IndexRecordOption::Basic)) as Box<dyn Query>)))
+            .collect::<Result<Vec<_>, ParseErrorReason>>()?;
+        let query: Box<dyn Query> = match terms.len() {
+            0 => Box::new(EmptyQuery),
+            1 => terms.pop().expect("one term").1,
+            _ => Box::new(BooleanQuery::from(terms)),
         };
-        let left = {
-            if !include_left {
-                left + 1
-            }else {
-                left
-            }
+        self.push(query);
+        Ok(self)
+    }
+    /// 字段上有任意一个 term 的文档, 只对建了索引的字段有效
+    pub fn add_exists_query(mut self, field: &str) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        let field_type = self.s.get_field_entry(field).field_type();
+        if !field_type.is_indexed() {
+            return Err(ParseErrorReason::UnsupportedClause(format!("exists on field `{}` which is not indexed", self.s.get_field_name(field))));
+        }
+        let query = RangeQuery::new_term_bounds(field, field_type.value_type(), &Bound::Unbounded, &Bound::Unbounded);
+        self.push(Box::new(query));
+        Ok(self)
+    }
+    pub fn add_regex_query(mut self, field: &str, pattern: String) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        self.text_field(field, "regexp")?;
+        self.push(Box::new(RegexQuery::new(pattern, field)));
+        Ok(self)
+    }
+    /// `*` 匹配任意多个字符, `?` 匹配一个字符, 其余字符按原样匹配
+    pub fn add_wildcard_query(self, field: &str, pattern: &str) -> Result<Self, ParseErrorReason> {
+        let regex = pattern.split('*')
+            .map(|part| part.split('?').map(escape_regex).collect::<Vec<_>>().join("."))
+            .collect::<Vec<_>>()
+            .join(".*");
+        self.add_regex_query(field, regex)
+    }
+    pub fn add_fuzzy_query(mut self, field: &str, value: &str, distance: u8, transpositions: bool) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        self.text_field(field, "fuzzy")?;
+        let query = FuzzyTermQuery::new(Term::from_field_text(field, value), distance, transpositions);
+        self.push(Box::new(query));
+        Ok(self)
+    }
+    /// 用字段自己的 tokenizer 切词, 返回 (position, term), 非文本字段不切词
+    fn tokenize(&self, field: Field, text: &Value) -> Result<Vec<(usize, Term)>, ParseErrorReason> {
+        let tokenizer = match self.i.tokenizer_for_field(field) {
+            Ok(tokenizer) => tokenizer,
+            Err(_) => return Ok(vec![(0, self.term(field, text)?)]),
         };
-        let right = {
-            if !include_right {
-                right - 1
-            }else{
-                right
-            }
+        let text = str_value(text)?;
+        let mut terms = vec![];
+        tokenizer.token_stream(&text).process(&mut |token| {
+            terms.push((token.position, Term::from_field_text(field, &token.text)));
+        });
+        Ok(terms)
+    }
+    /// 和 tantivy QueryParser 一样, 切出来的每个词一个 TermQuery, operator 决定是 and 还是 or
+    pub fn add_match_query(mut self, field: &str, text: &Value, operator: Occur) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        let mut terms = self.tokenize(field, text)?.into_iter()
+            .map(|(_, term)| (operator, Box::new(TermQuery::new(term, IndexRecordOption::WithFreqs)) as Box<dyn Query>))
+            .collect::<Vec<_>>();
+        let query: Box<dyn Query> = match terms.len() {
+            0 => Box::new(EmptyQuery),
+            1 => terms.pop().expect("one term").1,
+            _ => Box::new(BooleanQuery::from(terms)),
         };
-        self.c.add_range_query(field, left, right, self.o);
-        self
+        self.push(query);
+        Ok(self)
     }
-//    pub fn add_range_query(mut self, field: &str, left: u64, right: u64,include_left: bool, include_right: bool) -> Self {
-//        let field = match self.s.get_field(field) {
-//            Some(field) => field,
-//            None => return self
-//        };
-//        let left = {
-//            if include_left { Bound::Included(left)} else {
-//                Bound::Excluded(left)
-//            }
-//        };
-//        let right = {
-//            if include_right { Bound::Included(right) } else {
-//                Bound::Excluded(right)
-//            }
-//        };
-//        let query = RangeQuery::new_u64_bounds(field, left, right);
-//        self.c.push((self.o, Box::new(query)));
-//        self
-//    }
-    pub fn add_term_query_str(mut self,  field: &str, value: &str) -> Self {
-        let field = match self.s.get_field(field) {
-            Some(field) => field,
-            None => return self,
+    pub fn add_match_phrase_query(mut self, field: &str, text: &Value, slop: u32) -> Result<Self, ParseErrorReason> {
+        let field = self.field(field)?;
+        let mut terms = self.tokenize(field, text)?;
+        let query: Box<dyn Query> = match terms.len() {
+            0 => Box::new(EmptyQuery),
+            1 => Box::new(TermQuery::new(terms.pop().expect("one term").1, IndexRecordOption::WithFreqs)),
+            _ => {
+                let has_positions = self.s.get_field_entry(field).field_type()
+                    .get_index_record_option()
+                    .map(IndexRecordOption::has_positions)
+                    .unwrap_or(false);
+                if !has_positions {
+                    return Err(ParseErrorReason::UnsupportedClause(format!("match_phrase on field `{}` which does not have positions indexed", self.s.get_field_name(field))));
+                }
+                let mut query = PhraseQuery::new_with_offset(terms);
+                query.set_slop(slop);
+                Box::new(query)
+            }
         };
-        let query = TermQuery::new(Term::from_field_text(field, value),
-                                   IndexRecordOption::Basic);
-        self.c.push((self.o, Box::new(query)));
-        self
+        self.push(query);
+        Ok(self)
+    }
+    fn text_field(&self, field: Field, clause: &str) -> Result<(), ParseErrorReason> {
+        match self.s.get_field_entry(field).field_type() {
+            FieldType::Str(_) => Ok(()),
+            _ => Err(ParseErrorReason::UnsupportedClause(format!("{} on field `{}` which is not a text field", clause, self.s.get_field_name(field)))),
+        }
+    }
+    pub fn build(self) -> Box<dyn Query> {
+        self.c.build(self.k)
     }
-    pub fn add_term_query_u64(mut self, field: &str, value: u64) -> Self {
-        let field = match self.s.get_field(field) {
-            Some(field) => field,
-            None => return self,
+    /// 范围的类型由字段决定, 被 policy 选中的数值 range 会合进 CatQuery
+    pub fn add_range_query(mut self, name: &str, left: Bound<&Value>, right: Bound<&Value>) -> Result<Self, ParseErrorReason> {
+        let field = self.field(name)?;
+        // 数值和 date 都先换成 term 里保序的 u64 编码, 主范围按编码求交集
+        let (value_type, left, right) = match self.s.get_field_entry(field).field_type() {
+            FieldType::U64(_) => (Type::U64, map_bound(left, u64_value)?, map_bound(right, u64_value)?),
+            FieldType::I64(_) => {
+                let encode = |v: &Value| i64_value(v).map(i64_to_u64);
+                (Type::I64, map_bound(left, encode)?, map_bound(right, encode)?)
+            }
+            FieldType::F64(_) => {
+                let encode = |v: &Value| f64_value(v).map(f64_to_u64);
+                (Type::F64, map_bound(left, encode)?, map_bound(right, encode)?)
+            }
+            FieldType::Date(_) => (Type::Date, date_bound(left, true)?, date_bound(right, false)?),
+            FieldType::Str(_) => {
+                let (left, right) = (map_bound(left, str_value)?, map_bound(right, str_value)?);
+                self.push(Box::new(RangeQuery::new_str_bounds(field, as_str_bound(&left), as_str_bound(&right))));
+                return Ok(self);
+            }
+            FieldType::HierarchicalFacet | FieldType::Bytes => {
+                return Err(ParseErrorReason::UnsupportedClause("range on facet or bytes field".to_string()))
+            }
         };
-        let query = TermQuery::new(
-            Term::from_field_u64(field, value),
-            IndexRecordOption::Basic,
-        );
-        self.c.push((self.o, Box::new(query)));
-        self
+        let fast = match self.s.get_field_entry(field).field_type() {
+            FieldType::U64(options) | FieldType::I64(options) | FieldType::F64(options) => {
+                options.get_fastfield_cardinality() == Some(Cardinality::SingleValue)
+            }
+            _ => false,
+        };
+        let candidate = RangeCandidate {
+            name,
+            left,
+            right,
+            occur: self.o,
+            boosted: self.boosted(),
+            fast,
+        };
+        if self.r.accept(&candidate) {
+            // 和 RangeQuery 一样, must 里的范围命中时加 1 分
+            let score = if self.f { 0.0 } else { 1.0 };
+            self.c.add_range(CatRange { field, value_type, left, right, score });
+            self.b = None;
+            return Ok(self);
+        }
+        let term = |v: u64| Term::from_field_u64(field, v);
+        let (left, right) = (left.map(term), right.map(term));
+        let query = Box::new(RangeQuery::new_term_bounds(field, value_type, &left, &right));
+        self.push(query);
+        Ok(self)
+    }
+}
+
+/// 转义正则里的特殊字符, 其余字符按原样匹配
+fn escape_regex(s: &str) -> String {
+    let mut regex = String::with_capacity(s.len());
+    for c in s.chars() {
+        if let '\\' | '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$' | '#' | '&' | '-' | '~' = c {
+            regex.push('\\');
+        }
+        regex.push(c);
+    }
+    regex
+}
+
+fn map_bound<T, F: Fn(&Value) -> Result<T, ParseErrorReason>>(bound: Bound<&Value>, f: F) -> Result<Bound<T>, ParseErrorReason> {
+    Ok(match bound {
+        Bound::Included(v) => Bound::Included(f(v)?),
+        Bound::Excluded(v) => Bound::Excluded(f(v)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// tantivy 0.10 的 date 在索引里只精确到秒, 编码是秒数.
+/// 不是整秒的边界换成不改变结果的整秒: 下界 `gt`/`gte` 1.5s 都是 `gte` 2s, 上界 `lt`/`lte` 1.5s 都是 `lte` 1s
+fn date_bound(bound: Bound<&Value>, lower: bool) -> Result<Bound<u64>, ParseErrorReason> {
+    let seconds = |v: &Value| date(v).map(|d| (d.timestamp(), d.timestamp_subsec_nanos() == 0));
+    Ok(match bound {
+        Bound::Unbounded => Bound::Unbounded,
+        Bound::Included(v) | Bound::Excluded(v) => match seconds(v)? {
+            (s, true) if matches!(bound, Bound::Included(_)) => Bound::Included(i64_to_u64(s)),
+            (s, true) => Bound::Excluded(i64_to_u64(s)),
+            (s, false) if lower => Bound::Included(i64_to_u64(s + 1)),
+            (s, false) => Bound::Included(i64_to_u64(s)),
+        },
+    })
+}
+
+fn as_str_bound(bound: &Bound<String>) -> Bound<&str> {
+    match bound {
+        Bound::Included(v) => Bound::Included(v),
+        Bound::Excluded(v) => Bound::Excluded(v),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+fn number<T: std::str::FromStr>(value: &Value, as_number: Option<T>, expected: &'static str) -> Result<T, ParseErrorReason> {
+    as_number
+        .or_else(|| value.as_str().and_then(|s| s.parse().ok()))
+        .ok_or(ParseErrorReason::WrongValueType(expected))
+}
+
+fn u64_value(value: &Value) -> Result<u64, ParseErrorReason> {
+    number(value, value.as_u64(), "u64")
+}
+
+fn i64_value(value: &Value) -> Result<i64, ParseErrorReason> {
+    number(value, value.as_i64(), "i64")
+}
+
+fn f64_value(value: &Value) -> Result<f64, ParseErrorReason> {
+    number(value, value.as_f64(), "f64")
+}
+
+fn str_value(value: &Value) -> Result<String, ParseErrorReason> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(n.to_string()),
+        _ => Err(ParseErrorReason::WrongValueType("string")),
+    }
+}
+
+/// date 字段接受 rfc3339 字符串或者 es 一样的毫秒时间戳, 不丢掉秒以下的部分
+fn date(value: &Value) -> Result<DateTime, ParseErrorReason> {
+    if let Some(millis) = value.as_i64() {
+        return Utc.timestamp_millis_opt(millis).single()
+            .ok_or(ParseErrorReason::WrongValueType("date"));
+    }
+    value.as_str()
+        .and_then(|s| chrono::DateTime::parse_from_rfc3339(s).ok())
+        .map(|d| d.with_timezone(&Utc))
+        .ok_or(ParseErrorReason::WrongValueType("date"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query_parser::parse;
+    use serde_json::json;
+    use tantivy::collector::Count;
+    use tantivy::doc;
+    use tantivy::schema::{FAST, INDEXED};
+
+    fn index() -> Index {
+        let mut schema_builder = Schema::builder();
+        let time = schema_builder.add_u64_field("time", INDEXED | FAST);
+        let status = schema_builder.add_u64_field("status", INDEXED);
+        let delta = schema_builder.add_i64_field("delta", INDEXED | FAST);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..100u64 {
+            index_writer.add_document(doc!(time => i, status => i % 10, delta => i as i64 % 20 - 10));
+        }
+        index_writer.commit().unwrap();
+        index
+    }
+
+    fn count(index: &Index, query: Value) -> usize {
+        let query = parse(&query, index, Rc::new(TimeRangePolicy::default()), None).unwrap();
+        index.reader().unwrap().searcher().search(query.as_ref(), &Count).unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_fast_field_ranges_join_one_cat_query() {
+        let index = index();
+        let schema = index.schema();
+        let (time, status, delta) = (schema.get_field("time").unwrap(), schema.get_field("status").unwrap(), schema.get_field("delta").unwrap());
+        let query = json!({"bool": {"filter": [
+            {"range": {"delta": {"gte": -5, "lt": 5}}},
+            {"range": {"time": {"gte": 20, "lt": 1000}}},
+            {"range": {"status": {"lte": 3}}}
+        ]}});
+        let policy = TimeRangePolicy { fast_field_dimensions: true, ..TimeRangePolicy::default() };
+        let parsed = parse(&query, &index, Rc::new(policy), None).unwrap();
+        let mut cat = parsed.downcast_ref::<CatQuery>().expect("CatQuery").clone();
+        assert!(cat.order_by_range(time));
+        assert!(cat.order_by_range(delta));
+        // status 不是 fast field, 留在内层查询里
+        assert!(!cat.order_by_range(status));
+        assert_eq!(count(&index, query), 16);
+
+        // 默认关掉, 只有时间字段和 dimensions 里的字段进 CatQuery
+        let query = json!({"bool": {"filter": [{"range": {"delta": {"gte": -5}}}, {"range": {"time": {"gte": 20, "lt": 1000}}}]}});
+        let parsed = parse(&query, &index, Rc::new(TimeRangePolicy::default()), None).unwrap();
+        let mut cat = parsed.downcast_ref::<CatQuery>().expect("CatQuery").clone();
+        assert!(!cat.order_by_range(delta));
+        let policy = TimeRangePolicy { dimensions: vec!["status".to_string()], ..TimeRangePolicy::default() };
+        let query = json!({"bool": {"filter": [{"range": {"status": {"gte": 5}}}, {"range": {"time": {"gte": 20, "lt": 1000}}}]}});
+        let parsed = parse(&query, &index, Rc::new(policy), None).unwrap();
+        let mut cat = parsed.downcast_ref::<CatQuery>().expect("CatQuery").clone();
+        assert!(cat.order_by_range(status));
+    }
+
+    #[test]
+    fn test_date_range_keeps_milliseconds() {
+        let mut schema_builder = Schema::builder();
+        let time = schema_builder.add_date_field("time", INDEXED);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for i in 0..10 {
+            index_writer.add_document(doc!(time => Utc.timestamp_opt(i, 0).unwrap()));
+        }
+        index_writer.commit().unwrap();
+
+        let range = |params: Value| json!({"range": {"time": params}});
+        let parsed = parse(&range(json!({"gte": 1500, "lt": 4500})), &index, Rc::new(TimeRangePolicy::default()), None).unwrap();
+        assert!(parsed.downcast_ref::<CatQuery>().is_some());
+        // 只有下界的范围超过默认的 max_cardinality, 按普通的 RangeQuery 处理
+        let parsed = parse(&range(json!({"gte": 1500})), &index, Rc::new(TimeRangePolicy::default()), None).unwrap();
+        assert!(parsed.downcast_ref::<CatQuery>().is_none());
+        assert_eq!(count(&index, range(json!({"gte": 1500}))), 8);
+        assert_eq!(count(&index, range(json!({"gte": 1500, "lt": 4500}))), 3);
+        assert_eq!(count(&index, range(json!({"gt": 1500, "lte": 4500}))), 3);
+        assert_eq!(count(&index, range(json!({"gt": 1000, "lte": 3000}))), 2);
+        assert_eq!(count(&index, range(json!({"gte": 1500, "lt": 2000}))), 0);
+        assert_eq!(count(&index, range(json!({"gte": "1970-01-01T00:00:01.001Z", "lte": "1970-01-01T00:00:02.999Z"}))), 1);
+        assert_eq!(count(&index, range(json!({"gte": -500, "lt": 500}))), 1);
+
+        assert_eq!(count(&index, json!({"term": {"time": 2000}})), 1);
+        let error = parse(&json!({"term": {"time": 2500}}), &index, Rc::new(TimeRangePolicy::default()), None).unwrap_err();
+        assert_eq!(error.reason, ParseErrorReason::WrongValueType("date in whole seconds"));
+        // date 没有 fast field, 不能排序
+        let body = r#"{"query": {"range": {"time": {"gte": 0}}}, "sort": [{"time": "desc"}]}"#;
+        assert!(crate::search_request::SearchRequest::parse(body, &index, Rc::new(TimeRangePolicy::default()), None).is_err());
+    }
+
+    #[test]
+    fn test_should_is_optional_next_to_cat_range() {
+        let index = index();
+        let query = json!({"bool": {"filter": [{"range": {"time": {"gte": 0, "lt": 50}}}], "should": [{"term": {"status": 1}}]}});
+        assert_eq!(count(&index, query), 50);
+        let query = json!({"bool": {"must": [{"range": {"time": {"gte": 0, "lt": 50}}}], "should": [{"term": {"status": 1}}], "minimum_should_match": 0}});
+        assert_eq!(count(&index, query), 50);
+        let query = json!({"bool": {"filter": [{"range": {"time": {"gte": 0, "lt": 50}}}], "should": [{"term": {"status": 1}}], "minimum_should_match": 1}});
+        assert_eq!(count(&index, query), 5);
+        // status 不是 fast field, 不进 CatQuery 的 range 结果一样
+        let query = json!({"bool": {"filter": [{"range": {"status": {"gte": 0, "lt": 5}}}], "should": [{"term": {"status": 1}}]}});
+        assert_eq!(count(&index, query), 50);
+    }
+
+    #[test]
+    fn test_range_only_bool_matches_without_adjust_pure_negative() {
+        let index = index();
+        let query = json!({"bool": {"filter": [{"range": {"time": {"gte": 10}}}], "adjust_pure_negative": false}});
+        assert_eq!(count(&index, query), 90);
+        let query = json!({"bool": {"filter": [{"range": {"time": {"gte": 10}}}], "must_not": [{"term": {"status": 1}}], "adjust_pure_negative": false}});
+        assert_eq!(count(&index, query), 81);
+        assert_eq!(count(&index, json!({"bool": {"adjust_pure_negative": false}})), 100);
+        // 只有 must_not 时才看 adjust_pure_negative
+        assert_eq!(count(&index, json!({"bool": {"must_not": [{"term": {"status": 1}}]}})), 90);
+        assert_eq!(count(&index, json!({"bool": {"must_not": [{"term": {"status": 1}}], "adjust_pure_negative": false}})), 0);
+    }
+}
@@ -0,0 +1,458 @@
+use crate::query::{CatQuery, ConstantScoreQuery, DisMaxQuery, FunctionScoreQuery, MinimumShouldMatchQuery, ScoreFunction};
+use serde_json::{json, Map, Value};
+use std::collections::Bound;
+use tantivy::chrono::{TimeZone, Utc};
+use tantivy::query::{AllQuery, BooleanQuery, BoostQuery, EmptyQuery, FuzzyTermQuery, Occur, PhraseQuery, Query, RangeQuery, RegexQuery, TermQuery};
+use tantivy::schema::{Facet, FieldType, IndexRecordOption, Schema};
+use tantivy::tokenizer::TokenizerManager;
+use tantivy::{TantivyError, Term};
+
+/// 把查询写回 json dsl, 结果可以交给 `query_parser::parse` 重新解析
+///
+/// `parse` 得到的查询重新解析后结构也相同; 别的方式构造的查询匹配的文档、打分相同, 但结构不一定相同,
+/// 比如 must 下的 u64 range 会重新变成 CatQuery. 写不回去的查询返回错误
+pub trait ToDsl {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError>;
+}
+
+fn unsupported(what: String) -> TantivyError {
+    TantivyError::InvalidArgument(format!("{} can not be written as DSL", what))
+}
+
+/// f32 直接转 f64 会带上多余的尾数, 按 f32 的最短十进制写法输出
+fn f32_value(v: f32) -> Value {
+    v.to_string().parse::<f64>().map(Value::from).unwrap_or(Value::Null)
+}
+
+fn term_value(schema: &Schema, term: &Term) -> Result<Value, TantivyError> {
+    let field_entry = schema.get_field_entry(term.field());
+    match field_entry.field_type() {
+        FieldType::Str(_) => Ok(Value::from(term.text())),
+        FieldType::U64(_) => Ok(Value::from(term.get_u64())),
+        FieldType::I64(_) => Ok(Value::from(term.get_i64())),
+        FieldType::F64(_) => Ok(Value::from(term.get_f64())),
+        FieldType::Date(_) => Utc.timestamp_opt(term.get_i64(), 0).single()
+            .map(|date| Value::from(date.to_rfc3339()))
+            .ok_or_else(|| unsupported(format!("date {}", term.get_i64()))),
+        FieldType::HierarchicalFacet => Facet::from_encoded(term.value_bytes().to_vec())
+            .map(|facet| Value::from(facet.to_string()))
+            .map_err(|_| unsupported("invalid facet".to_string())),
+        FieldType::Bytes => Err(unsupported(format!("term on bytes field `{}`", field_entry.name()))),
+    }
+}
+
+/// `{"clause": {"field": params}}`
+fn field_clause(schema: &Schema, clause: &str, term: &Term, params: Value) -> Value {
+    json!({ clause: { schema.get_field_name(term.field()): params } })
+}
+
+fn range_params(left: Bound<Value>, right: Bound<Value>) -> Value {
+    let mut params = Map::new();
+    match left {
+        Bound::Included(v) => { params.insert("gte".to_string(), v); }
+        Bound::Excluded(v) => { params.insert("gt".to_string(), v); }
+        Bound::Unbounded => {}
+    }
+    match right {
+        Bound::Included(v) => { params.insert("lte".to_string(), v); }
+        Bound::Excluded(v) => { params.insert("lt".to_string(), v); }
+        Bound::Unbounded => {}
+    }
+    Value::Object(params)
+}
+
+fn map_bound<T, F: Fn(T) -> Result<Value, TantivyError>>(bound: Bound<T>, f: F) -> Result<Bound<Value>, TantivyError> {
+    Ok(match bound {
+        Bound::Included(v) => Bound::Included(f(v)?),
+        Bound::Excluded(v) => Bound::Excluded(f(v)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// boost 写在哪一层要看子句: 单字段子句写在字段参数里, 其余写在子句参数里
+fn with_boost(mut dsl: Value, boost: f32) -> Value {
+    let multiply = |params: &mut Value| {
+        let current = params.get("boost").and_then(Value::as_f64).unwrap_or(1.0) as f32;
+        params["boost"] = f32_value(current * boost);
+    };
+    if let Some((clause, params)) = dsl.as_object_mut().and_then(|m| m.iter_mut().next()) {
+        match clause.as_str() {
+            "term" | "range" | "regexp" | "fuzzy" | "match" | "match_phrase" => {
+                if let Some(fields) = params.as_object_mut() {
+                    fields.values_mut().for_each(multiply);
+                }
+            }
+            _ => multiply(params),
+        }
+    }
+    dsl
+}
+
+fn clauses_dsl(schema: &Schema, clauses: &[(Occur, Box<dyn Query>)]) -> Result<Map<String, Value>, TantivyError> {
+    let mut params = Map::new();
+    for (occur, query) in clauses {
+        // filter 里的子句解析成分数是 0 的 constant_score
+        let (key, query) = match (occur, query.downcast_ref::<ConstantScoreQuery>()) {
+            (Occur::Must, Some(q)) if q.score() == 0.0 => ("filter", q.filter()),
+            (Occur::Must, _) => ("must", query.as_ref()),
+            (Occur::Should, _) => ("should", query.as_ref()),
+            (Occur::MustNot, _) => ("must_not", query.as_ref()),
+        };
+        let list = params.entry(key).or_insert_with(|| Value::Array(vec![]));
+        if let Value::Array(list) = list {
+            list.push(query.to_dsl(schema)?);
+        }
+    }
+    // 原查询里需要的 match_all 已经在子句里了
+    params.insert("adjust_pure_negative".to_string(), Value::from(false));
+    Ok(params)
+}
+
+fn queries_dsl(schema: &Schema, queries: &[Box<dyn Query>]) -> Result<Value, TantivyError> {
+    queries.iter()
+        .map(|q| q.as_ref().to_dsl(schema))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Value::Array)
+}
+
+impl ToDsl for dyn Query {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        if let Some(q) = self.downcast_ref::<TermQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<RangeQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<RegexQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<BooleanQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<PhraseQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<FuzzyTermQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<AllQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<EmptyQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<BoostQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<CatQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<MinimumShouldMatchQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<ConstantScoreQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<DisMaxQuery>() {
+            q.to_dsl(schema)
+        } else if let Some(q) = self.downcast_ref::<FunctionScoreQuery>() {
+            q.to_dsl(schema)
+        } else {
+            Err(unsupported(format!("{:?}", self)))
+        }
+    }
+}
+
+/// 带词频的 term query 来自 match, 写回 match 才能保持打分一致
+impl ToDsl for TermQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        let value = term_value(schema, self.term())?;
+        let text = matches!(schema.get_field_entry(self.term().field()).field_type(), FieldType::Str(_));
+        if text && self.index_record_option() != IndexRecordOption::Basic {
+            Ok(field_clause(schema, "match", self.term(), json!({ "query": value })))
+        } else {
+            Ok(field_clause(schema, "term", self.term(), json!({ "value": value })))
+        }
+    }
+}
+
+/// 两边都不限的 range 是 exists
+impl ToDsl for RangeQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        let name = schema.get_field_name(self.field());
+        let left = map_bound(self.left_bound(), |t| term_value(schema, &t))?;
+        let right = map_bound(self.right_bound(), |t| term_value(schema, &t))?;
+        if let (Bound::Unbounded, Bound::Unbounded) = (&left, &right) {
+            return Ok(json!({ "exists": { "field": name } }));
+        }
+        Ok(json!({ "range": { name: range_params(left, right) } }))
+    }
+}
+
+impl ToDsl for RegexQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "regexp": { schema.get_field_name(self.field()): { "value": self.pattern() } } }))
+    }
+}
+
+impl ToDsl for BooleanQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "bool": clauses_dsl(schema, self.clauses())? }))
+    }
+}
+
+/// 写成 match_phrase 时要重新切词, 切出来的词和相对位置必须和原来一样.
+/// 停用词留下的位置空缺、再切一次会变的词干都写不回去, 只用默认的 tokenizer 检查
+impl ToDsl for PhraseQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        let terms = self.phrase_terms_with_offsets();
+        let text = terms.iter()
+            .map(|(_, t)| t.text().to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let tokenizer = match schema.get_field_entry(self.field()).field_type() {
+            FieldType::Str(options) => options.get_indexing_options()
+                .and_then(|indexing| TokenizerManager::default().get(indexing.tokenizer())),
+            _ => None,
+        };
+        let tokenizer = tokenizer.ok_or_else(|| unsupported(format!("{:?} with a custom tokenizer", self)))?;
+        let mut tokens = vec![];
+        tokenizer.token_stream(&text).process(&mut |token| tokens.push((token.position, token.text.clone())));
+        let first = terms[0].0;
+        let same = tokens.len() == terms.len()
+            && tokens.iter().zip(terms).all(|((position, token), (offset, term))| *position == offset - first && token == term.text());
+        if !same {
+            return Err(unsupported(format!("{:?}", self)));
+        }
+        Ok(json!({ "match_phrase": { schema.get_field_name(self.field()): { "query": text, "slop": self.slop() } } }))
+    }
+}
+
+impl ToDsl for FuzzyTermQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        if self.is_prefix() {
+            return Err(unsupported("prefix fuzzy query".to_string()));
+        }
+        Ok(field_clause(schema, "fuzzy", self.term(), json!({
+            "value": self.term().text(),
+            "fuzziness": self.distance(),
+            "transpositions": self.transposition_cost_one(),
+        })))
+    }
+}
+
+impl ToDsl for AllQuery {
+    fn to_dsl(&self, _schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "match_all": {} }))
+    }
+}
+
+impl ToDsl for EmptyQuery {
+    fn to_dsl(&self, _schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "match_none": {} }))
+    }
+}
+
+impl ToDsl for BoostQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(with_boost(self.query().to_dsl(schema)?, self.boost()))
+    }
+}
+
+/// 各个范围按顺序放在 filter 里, 主范围在第一个, 重新解析时会再次被选作 CatQuery 的范围
+impl ToDsl for CatQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        let (mut must, mut filter) = (vec![], vec![]);
+        for range in self.ranges() {
+            let name = schema.get_field_name(range.field);
+            let value = |v: u64| term_value(schema, &Term::from_field_u64(range.field, v));
+            let left = map_bound(range.left, value)?;
+            let right = map_bound(range.right, value)?;
+            let dsl = json!({ "range": { name: range_params(left, right) } });
+            // 打分的范围来自 must
+            if range.score == 0.0 {
+                filter.push(dsl);
+            } else {
+                must.push(dsl);
+            }
+        }
+        let mut params = clauses_dsl(schema, self.query().clauses())?;
+        for (key, ranges) in [("must", must), ("filter", filter)] {
+            if ranges.is_empty() {
+                continue;
+            }
+            if let Value::Array(list) = params.entry(key).or_insert_with(|| Value::Array(vec![])) {
+                list.extend(ranges);
+            }
+        }
+        Ok(json!({ "bool": params }))
+    }
+}
+
+impl ToDsl for MinimumShouldMatchQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "bool": {
+            "should": queries_dsl(schema, self.clauses())?,
+            "minimum_should_match": self.minimum(),
+        } }))
+    }
+}
+
+impl ToDsl for ConstantScoreQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "constant_score": {
+            "filter": self.filter().to_dsl(schema)?,
+            "boost": f32_value(self.score()),
+        } }))
+    }
+}
+
+impl ToDsl for DisMaxQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        Ok(json!({ "dis_max": {
+            "queries": queries_dsl(schema, self.disjuncts())?,
+            "tie_breaker": f32_value(self.tie_breaker()),
+        } }))
+    }
+}
+
+impl ToDsl for FunctionScoreQuery {
+    fn to_dsl(&self, schema: &Schema) -> Result<Value, TantivyError> {
+        let functions: Vec<Value> = self.functions().iter()
+            .map(|f| {
+                let mut function = match f.function {
+                    ScoreFunction::FieldValueFactor { field, factor, modifier, missing } => {
+                        let mut params = json!({
+                            "field": schema.get_field_name(field),
+                            "factor": factor,
+                            "modifier": modifier.name(),
+                        });
+                        if let Some(missing) = missing {
+                            params["missing"] = Value::from(missing);
+                        }
+                        json!({ "field_value_factor": params })
+                    }
+                    ScoreFunction::Gauss { field, origin, scale, offset, decay } => json!({ "gauss": { schema.get_field_name(field): {
+                        "origin": origin,
+                        "scale": scale,
+                        "offset": offset,
+                        "decay": decay,
+                    } } }),
+                };
+                function["weight"] = Value::from(f.weight);
+                function
+            })
+            .collect();
+        Ok(json!({ "function_score": {
+            "query": self.query().to_dsl(schema)?,
+            "functions": functions,
+            "score_mode": self.score_mode().name(),
+            "boost_mode": self.boost_mode().name(),
+        } }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query_builder::TimeRangePolicy;
+    use crate::query_parser::parse;
+    use std::rc::Rc;
+    use tantivy::schema::{TextFieldIndexing, TextOptions, FAST, INDEXED, STRING, TEXT};
+    use tantivy::Index;
+
+    fn index() -> Index {
+        let mut schema_builder = Schema::builder();
+        schema_builder.add_text_field("title", TEXT);
+        schema_builder.add_text_field("tag", STRING);
+        let stemmed = TextFieldIndexing::default()
+            .set_tokenizer("en_stem")
+            .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+        schema_builder.add_text_field("body", TextOptions::default().set_indexing_options(stemmed));
+        schema_builder.add_u64_field("status", INDEXED);
+        schema_builder.add_u64_field("time", INDEXED | FAST);
+        schema_builder.add_i64_field("delta", INDEXED);
+        schema_builder.add_f64_field("latency", INDEXED);
+        schema_builder.add_date_field("date", INDEXED);
+        Index::create_in_ram(schema_builder.build())
+    }
+
+    fn parse_dsl(index: &Index, dsl: &Value) -> Box<dyn Query> {
+        parse(dsl, index, Rc::new(TimeRangePolicy::default()), None).unwrap()
+    }
+
+    /// parse(to_dsl(q)) 和 q 是同一个查询
+    fn assert_round_trip(index: &Index, dsl: Value) {
+        let query = parse_dsl(index, &dsl);
+        let written = query.to_dsl(&index.schema()).unwrap();
+        let reparsed = parse_dsl(index, &written);
+        assert_eq!(format!("{:?}", reparsed), format!("{:?}", query), "{} => {}", dsl, written);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let index = index();
+        let queries = vec![
+            json!({"match_all": {}}),
+            json!({"match_none": {}}),
+            json!({"term": {"status": 3}}),
+            json!({"term": {"tag": {"value": "a b", "boost": 2}}}),
+            json!({"term": {"date": "2019-08-28T12:00:00Z"}}),
+            json!({"terms": {"status": [1, 2]}}),
+            json!({"exists": {"field": "title"}}),
+            json!({"prefix": {"tag": "ab.c"}}),
+            json!({"wildcard": {"tag": "a*b?c"}}),
+            json!({"regexp": {"tag": "a[bc]+"}}),
+            json!({"fuzzy": {"title": {"value": "quick", "fuzziness": 1, "transpositions": false}}}),
+            json!({"match": {"title": "quick"}}),
+            json!({"match": {"title": {"query": "quick brown fox", "operator": "and"}}}),
+            json!({"match_phrase": {"title": {"query": "quick brown fox", "slop": 2}}}),
+            json!({"match_phrase": {"body": "running dogs"}}),
+            json!({"range": {"status": {"gte": 1, "lt": 5}}}),
+            json!({"range": {"delta": {"gt": -5}}}),
+            json!({"range": {"latency": {"lte": 2.5, "boost": 3}}}),
+            json!({"range": {"date": {"gte": 1500, "lt": "2019-08-28T12:00:00Z"}}}),
+            json!({"range": {"time": {"gte": 10, "lt": 20}}}),
+            json!({"bool": {
+                "must": [{"match": {"title": "quick"}}],
+                "filter": [{"term": {"status": 1}}, {"range": {"time": {"gte": 10}}}, {"range": {"time": {"lt": 20}}}],
+                "should": [{"term": {"tag": "x"}}],
+                "must_not": [{"term": {"status": 2}}]
+            }}),
+            json!({"bool": {"filter": {"range": {"time": {"gte": 10}}}, "should": [{"term": {"status": 1}}]}}),
+            json!({"bool": {"must_not": {"term": {"status": 1}}}}),
+            json!({"bool": {"must_not": {"term": {"status": 1}}, "adjust_pure_negative": false}}),
+            json!({"bool": {"should": [{"term": {"status": 1}}, {"term": {"status": 2}}, {"term": {"status": 3}}], "minimum_should_match": 2}}),
+            json!({"bool": {"must": {"term": {"status": 1}}, "should": [{"term": {"status": 2}}], "minimum_should_match": 1, "boost": 2}}),
+            json!({"constant_score": {"filter": {"term": {"status": 1}}, "boost": 1.5}}),
+            json!({"dis_max": {"queries": [{"match": {"title": "quick"}}, {"term": {"status": 1}}], "tie_breaker": 0.3}}),
+            json!({"function_score": {
+                "query": {"match": {"title": "quick"}},
+                "functions": [
+                    {"field_value_factor": {"field": "time", "factor": 2, "modifier": "log1p", "missing": 1}},
+                    {"gauss": {"time": {"origin": 100, "scale": 10, "offset": 1, "decay": 0.3}}, "weight": 2}
+                ],
+                "score_mode": "sum",
+                "boost_mode": "replace"
+            }}),
+        ];
+        for dsl in queries {
+            assert_round_trip(&index, dsl);
+        }
+    }
+
+    #[test]
+    fn test_phrases_that_do_not_round_trip_are_rejected() {
+        let index = index();
+        let schema = index.schema();
+        // 停用词留下的位置空缺重新切词后就没了
+        let title = schema.get_field("title").unwrap();
+        let terms = vec![(0, Term::from_field_text(title, "quick")), (2, Term::from_field_text(title, "fox"))];
+        let gap: Box<dyn Query> = Box::new(PhraseQuery::new_with_offset(terms));
+        assert!(gap.to_dsl(&schema).is_err());
+        // 相对位置一样就能写回去
+        let terms = vec![(1, Term::from_field_text(title, "quick")), (2, Term::from_field_text(title, "fox"))];
+        let shifted: Box<dyn Query> = Box::new(PhraseQuery::new_with_offset(terms));
+        assert_eq!(shifted.to_dsl(&schema).unwrap(), json!({"match_phrase": {"title": {"query": "quick fox", "slop": 0}}}));
+        // 切词后会变的词, 比如还没取词干的
+        let body = schema.get_field("body").unwrap();
+        let terms = vec![Term::from_field_text(body, "running"), Term::from_field_text(body, "dog")];
+        let stem: Box<dyn Query> = Box::new(PhraseQuery::new(terms));
+        assert!(stem.to_dsl(&schema).is_err());
+    }
+
+    #[test]
+    fn test_prefix_only_on_text_fields() {
+        let index = index();
+        let error = parse(&json!({"prefix": {"status": "1"}}), &index, Rc::new(TimeRangePolicy::default()), None).unwrap_err();
+        assert_eq!(error.path, "/query/prefix/status");
+    }
+}
@@ -1,86 +1,871 @@
-use crate::query_builder::QueryBuilder;
-use serde_json::Value;
-use tantivy::query::{Occur, BooleanQuery, Query};
-use tantivy::schema::Schema;
-use crate::query::CatQuery;
+use crate::query_builder::{QueryBuilder, MinimumShouldMatch, PrimaryRangePolicy};
+use serde_json::{Map, Value};
+use tantivy::query::{Occur, Query};
+use tantivy::Index;
+use crate::query::{DisMaxQuery, FilterCache, FunctionScoreQuery, ScoreFunction, WeightedFunction, Modifier, ScoreMode, BoostMode};
+use std::fmt;
+use std::collections::Bound;
+use std::rc::Rc;
+use std::sync::Arc;
 
-pub fn parse(query: String, schema: Schema, size: usize) -> Box<dyn Query> {
-    let builder = QueryBuilder::new(schema.clone(), Occur::Must, size);
-    let mut query = {
-        if let Some(query) = serde_json::from_str(&query).ok() {
-            builder.parse(&query).build()
-        } else {
-            builder.build()
-        }
-    };
-    match query.downcast_mut::<CatQuery>() {
-        Some(c) => {
-            c.set_limit(size);
+/// dsl 的节点不能转成查询的原因
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseErrorReason {
+    /// 请求体不是合法的 json
+    InvalidJson(String),
+    /// schema 里没有这个字段
+    UnknownField(String),
+    /// 值的类型不是字段或者子句要的
+    WrongValueType(&'static str),
+    /// 不支持的子句
+    UnsupportedClause(String),
+    /// 子句不支持的参数
+    UnsupportedParameter(String),
+    /// `range` 缺少需要的边界
+    MissingBound(&'static str),
+    /// 子句缺少它作用的值
+    MissingValue,
+    /// `from + size` 超过了最大的结果窗口
+    ResultWindowTooLarge { from: usize, size: usize, max: usize },
+}
+
+impl fmt::Display for ParseErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorReason::InvalidJson(e) => write!(f, "invalid json: {}", e),
+            ParseErrorReason::UnknownField(field) => write!(f, "unknown field `{}`", field),
+            ParseErrorReason::WrongValueType(expected) => write!(f, "wrong value type, expected {}", expected),
+            ParseErrorReason::UnsupportedClause(clause) => write!(f, "unsupported clause `{}`", clause),
+            ParseErrorReason::UnsupportedParameter(param) => write!(f, "unsupported parameter `{}`", param),
+            ParseErrorReason::MissingBound(bound) => write!(f, "missing bound `{}`", bound),
+            ParseErrorReason::MissingValue => write!(f, "missing value"),
+            ParseErrorReason::ResultWindowTooLarge { from, size, max } =>
+                write!(f, "result window is too large, from + size ({} + {}) must be less than or equal to {}", from, size, max),
         }
-        _ => {}
     }
-    query
 }
-impl QueryBuilder {
-    pub fn parse(self, v: &Value) -> Self {
-        if let Some(v) = v.get("query") {
-            return self.parse(v)
+
+/// `parse` 返回的错误, `path` 是出错节点的 json pointer
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryParseError {
+    pub path: String,
+    pub reason: ParseErrorReason,
+}
+
+impl QueryParseError {
+    pub fn new(path: &str, reason: ParseErrorReason) -> Self {
+        QueryParseError {
+            path: path.to_string(),
+            reason,
         }
+    }
+}
 
-        if let Some(v) = v.get("bool") {
-            if let Some(v) = v.get("filter") {
-                return self.down(Occur::Must).parse(v).up()
-            }
-            if let Some(v) = v.get("should") {
-                return self.down(Occur::Should).parse(v).up()
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at `{}`", self.reason, self.path)
+    }
+}
+
+impl std::error::Error for QueryParseError {}
+
+/// 把 key 转义后拼到 json pointer 后面
+pub fn pointer(path: &str, key: &str) -> String {
+    format!("{}/{}", path, key.replace('~', "~0").replace('/', "~1"))
+}
+
+/// 解析一个查询节点, 即请求体里 `query` 的值, `to_dsl` 的输出可以原样传进来
+///
+/// `policy` 决定哪个 range 走 CatQuery, 一般用 `TimeRangePolicy`;
+/// `cache` 是这个索引的过滤缓存, 只执行一次的查询可以不给
+pub fn parse(query: &Value, index: &Index, policy: Rc<dyn PrimaryRangePolicy>, cache: Option<Arc<FilterCache>>) -> Result<Box<dyn Query>, QueryParseError> {
+    let builder = QueryBuilder::new(index, Occur::Must, 0)
+        .primary_range_policy(policy)
+        .filter_cache(cache)
+        .parse(query, "/query")?;
+    Ok(builder.build())
+}
+
+/// bool 子句与 tantivy `Occur` 的对应关系, filter 和 must 一样必须命中, 但不参与打分
+const BOOL_CLAUSES: &[(&str, Occur)] = &[
+    ("must", Occur::Must),
+    ("should", Occur::Should),
+    ("must_not", Occur::MustNot),
+];
+
+const RANGE_PARAMS: &[&str] = &["gt", "gte", "lt", "lte", "from", "to", "include_lower", "include_upper", "boost"];
+
+/// 支持 `2`, `-1`, `"2"`, `"75%"`, `"-25%"` 这几种写法
+fn parse_minimum_should_match(v: &Value) -> Option<MinimumShouldMatch> {
+    if let Some(n) = v.as_i64() {
+        return Some(MinimumShouldMatch::Count(n));
+    }
+    let s = v.as_str()?.trim();
+    match s.strip_suffix('%') {
+        Some(p) => p.trim().parse().ok().map(MinimumShouldMatch::Percentage),
+        None => s.parse().ok().map(MinimumShouldMatch::Count),
+    }
+}
+
+/// 单字段子句的参数, 支持 `{"field": {"value": v}}` 和简写 `{"field": v}`, 简写的值放在 `allowed[0]` 下
+fn field_params(v: &Value, path: &str, allowed: &[&str]) -> Result<Map<String, Value>, QueryParseError> {
+    match v.as_object() {
+        Some(params) => {
+            for key in params.keys() {
+                if !allowed.contains(&key.as_str()) {
+                    return Err(QueryParseError::new(&pointer(path, key), ParseErrorReason::UnsupportedParameter(key.clone())));
+                }
             }
+            Ok(params.clone())
         }
+        None => {
+            let mut params = Map::new();
+            params.insert(allowed[0].to_string(), v.clone());
+            Ok(params)
+        }
+    }
+}
+
+/// 取第一个存在的 key 对应的字符串
+fn string_param<'a>(params: &'a Map<String, Value>, path: &str, keys: &[&str]) -> Result<&'a str, QueryParseError> {
+    let (key, v) = keys.iter()
+        .find_map(|key| params.get(*key).map(|v| (*key, v)))
+        .ok_or_else(|| QueryParseError::new(path, ParseErrorReason::MissingValue))?;
+    v.as_str().ok_or_else(|| QueryParseError::new(&pointer(path, key), ParseErrorReason::WrongValueType("string")))
+}
+
+fn auto_fuzziness(value: &str) -> u8 {
+    match value.chars().count() {
+        0..=2 => 0,
+        3..=5 => 1,
+        _ => 2,
+    }
+}
+
+fn boost_param(params: &Map<String, Value>, path: &str) -> Result<f32, QueryParseError> {
+    match params.get("boost") {
+        None => Ok(1.0),
+        Some(v) => v.as_f64()
+            .map(|boost| boost as f32)
+            .ok_or_else(|| QueryParseError::new(&pointer(path, "boost"), ParseErrorReason::WrongValueType("number"))),
+    }
+}
+
+fn f64_param(params: &Map<String, Value>, path: &str, key: &str, default: f64) -> Result<f64, QueryParseError> {
+    match params.get(key) {
+        None => Ok(default),
+        Some(v) => v.as_f64()
+            .ok_or_else(|| QueryParseError::new(&pointer(path, key), ParseErrorReason::WrongValueType("number"))),
+    }
+}
+
+/// 数字也接受字符串写法
+fn u64_param(params: &Map<String, Value>, path: &str, key: &str) -> Result<Option<u64>, QueryParseError> {
+    match params.get(key) {
+        None => Ok(None),
+        Some(v) => v.as_u64()
+            .or_else(|| v.as_str().and_then(|s| s.parse().ok()))
+            .map(Some)
+            .ok_or_else(|| QueryParseError::new(&pointer(path, key), ParseErrorReason::WrongValueType("u64"))),
+    }
+}
+
+/// 取 `{"name": value}` 形式的枚举参数, 缺省时用 default
+fn enum_param<T>(params: &Map<String, Value>, path: &str, key: &str, default: T, expected: &'static str, from_name: fn(&str) -> Option<T>) -> Result<T, QueryParseError> {
+    match params.get(key) {
+        None => Ok(default),
+        Some(v) => v.as_str()
+            .and_then(from_name)
+            .ok_or_else(|| QueryParseError::new(&pointer(path, key), ParseErrorReason::WrongValueType(expected))),
+    }
+}
+
+fn as_object<'a>(v: &'a Value, path: &str) -> Result<&'a Map<String, Value>, QueryParseError> {
+    v.as_object().ok_or_else(|| QueryParseError::new(path, ParseErrorReason::WrongValueType("object")))
+}
+
+impl QueryBuilder {
+    pub fn parse(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
         if let Some(v) = v.as_array() {
-            return v.iter().fold(self, |t,v| {
-                t.parse(v)
+            return v.iter().enumerate().try_fold(self, |t, (i, v)| {
+                t.parse(v, &pointer(path, &i.to_string()))
             })
         }
-        if let Some(v) = v.get("prefix") {
-            if let Some(v) = v.as_object() {
-                return v.iter().fold(self, |t, (k,v)| {
-                    if let Some(v) = v["value"].as_str() {
-                        return t.add_prefix_query(k, v)
-                    }
-                    t
-                })
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            match k.as_str() {
+                "bool" => t.parse_bool(v, &path),
+                "match_all" => t.parse_match_all(v, &path, true),
+                "match_none" => t.parse_match_all(v, &path, false),
+                "term" => t.parse_term(v, &path),
+                "prefix" => t.parse_prefix(v, &path),
+                "range" => t.parse_range(v, &path),
+                "terms" => t.parse_terms(v, &path),
+                "exists" => t.parse_exists(v, &path),
+                "ids" => t.parse_ids(v, &path),
+                "wildcard" => t.parse_wildcard(v, &path),
+                "regexp" => t.parse_regexp(v, &path),
+                "fuzzy" => t.parse_fuzzy(v, &path),
+                "match" => t.parse_match(v, &path),
+                "match_phrase" => t.parse_match_phrase(v, &path),
+                "constant_score" => t.parse_constant_score(v, &path),
+                "dis_max" => t.parse_dis_max(v, &path),
+                "function_score" => t.parse_function_score(v, &path),
+                _ => Err(QueryParseError::new(&path, ParseErrorReason::UnsupportedClause(k.clone()))),
+            }
+        })
+    }
+
+    fn parse_bool(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = as_object(v, path)?;
+        let mut t = self.boost(boost_param(params, path)?).down(Occur::Must);
+        for (k, v) in params {
+            let path = pointer(path, k);
+            if let Some(&(_, occur)) = BOOL_CLAUSES.iter().find(|(key, _)| key == k) {
+                t = t.also(occur).parse(v, &path)?;
+                continue;
             }
+            t = match k.as_str() {
+                "filter" => t.filter().parse(v, &path)?,
+                "minimum_should_match" => {
+                    let m = parse_minimum_should_match(v)
+                        .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::WrongValueType("integer or percentage")))?;
+                    t.minimum_should_match(m)
+                }
+                "adjust_pure_negative" => {
+                    let adjust = v.as_bool()
+                        .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::WrongValueType("boolean")))?;
+                    t.adjust_pure_negative(adjust)
+                }
+                "boost" => t,
+                _ => return Err(QueryParseError::new(&path, ParseErrorReason::UnsupportedParameter(k.clone()))),
+            };
         }
-        if let Some(v) = v.get("term") {
-            if let Some(v) = v.as_object() {
-                return v.iter().fold(self, |t, (k, v)| {
-                    if let Some(v) = v["value"].as_u64() {
-                        return t.add_term_query_u64(k, v)
-                    }
-                    if let Some(v) = v["value"].as_str() {
-                        return t.add_term_query_str(k, v)
+        Ok(t.up())
+    }
+
+    /// match_all 和 match_none 只有 boost 一个参数
+    fn parse_match_all(self, v: &Value, path: &str, all: bool) -> Result<Self, QueryParseError> {
+        let params = as_object(v, path)?;
+        if let Some(k) = params.keys().find(|k| k.as_str() != "boost") {
+            return Err(QueryParseError::new(&pointer(path, k), ParseErrorReason::UnsupportedParameter(k.clone())));
+        }
+        let t = self.boost(boost_param(params, path)?);
+        Ok(if all { t.add_all_query() } else { t.add_empty_query() })
+    }
+
+    fn parse_term(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["value", "boost"])?;
+            let value = params.get("value")
+                .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::MissingValue))?;
+            t.boost(boost_param(&params, &path)?).add_term_query(k, value).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    fn parse_prefix(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["value", "boost"])?;
+            let value = params.get("value")
+                .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::MissingValue))?
+                .as_str()
+                .ok_or_else(|| QueryParseError::new(&pointer(&path, "value"), ParseErrorReason::WrongValueType("string")))?;
+            t.boost(boost_param(&params, &path)?).add_prefix_query(k, value).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    /// `{"terms": {"field": [v1, v2], "boost": 1}}`
+    fn parse_terms(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = as_object(v, path)?;
+        let boost = boost_param(params, path)?;
+        params.iter().filter(|(k, _)| k.as_str() != "boost").try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let values = v.as_array()
+                .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::WrongValueType("array")))?;
+            t.boost(boost).add_terms_query(k, values).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    fn parse_exists(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = field_params(v, path, &["field", "boost"])?;
+        let boost = boost_param(&params, path)?;
+        let path = pointer(path, "field");
+        let field = params.get("field")
+            .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::MissingValue))?
+            .as_str()
+            .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::WrongValueType("string")))?;
+        self.boost(boost).add_exists_query(field).map_err(|reason| QueryParseError::new(&path, reason))
+    }
+
+    /// tantivy 没有 `_id`, 这里要求 schema 里有一个叫 `_id` 的字段
+    fn parse_ids(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = field_params(v, path, &["values", "boost"])?;
+        let boost = boost_param(&params, path)?;
+        let path = pointer(path, "values");
+        let values = params.get("values")
+            .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::MissingValue))?
+            .as_array()
+            .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::WrongValueType("array")))?;
+        self.boost(boost).add_terms_query("_id", values).map_err(|reason| QueryParseError::new(&path, reason))
+    }
+
+    fn parse_wildcard(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["value", "wildcard", "boost"])?;
+            let value = string_param(&params, &path, &["value", "wildcard"])?;
+            t.boost(boost_param(&params, &path)?).add_wildcard_query(k, value).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    fn parse_regexp(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["value", "boost"])?;
+            let value = string_param(&params, &path, &["value"])?;
+            t.boost(boost_param(&params, &path)?).add_regex_query(k, value.to_string()).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    /// fuzziness 只支持 0, 1, 2 和 AUTO, 和 es 一样 AUTO 按词长决定编辑距离
+    fn parse_fuzzy(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["value", "fuzziness", "transpositions", "boost"])?;
+            let value = string_param(&params, &path, &["value"])?;
+            let distance = match params.get("fuzziness") {
+                None => auto_fuzziness(value),
+                Some(Value::String(s)) if s.eq_ignore_ascii_case("auto") => auto_fuzziness(value),
+                Some(v) => match v.as_u64().or_else(|| v.as_str().and_then(|s| s.parse().ok())) {
+                    Some(d) if d <= 2 => d as u8,
+                    _ => return Err(QueryParseError::new(&pointer(&path, "fuzziness"), ParseErrorReason::WrongValueType("0, 1, 2 or AUTO"))),
+                },
+            };
+            let transpositions = match params.get("transpositions") {
+                None => true,
+                Some(v) => v.as_bool()
+                    .ok_or_else(|| QueryParseError::new(&pointer(&path, "transpositions"), ParseErrorReason::WrongValueType("boolean")))?,
+            };
+            t.boost(boost_param(&params, &path)?).add_fuzzy_query(k, value, distance, transpositions).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    fn parse_match(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["query", "operator", "boost"])?;
+            let query = params.get("query")
+                .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::MissingValue))?;
+            let operator = match params.get("operator").map(|v| v.as_str().map(str::to_lowercase)) {
+                None => Occur::Should,
+                Some(Some(ref op)) if op == "or" => Occur::Should,
+                Some(Some(ref op)) if op == "and" => Occur::Must,
+                Some(_) => return Err(QueryParseError::new(&pointer(&path, "operator"), ParseErrorReason::WrongValueType("`and` or `or`"))),
+            };
+            t.boost(boost_param(&params, &path)?).add_match_query(k, query, operator).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    fn parse_match_phrase(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = field_params(v, &path, &["query", "slop", "boost"])?;
+            let query = params.get("query")
+                .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::MissingValue))?;
+            let slop = match params.get("slop") {
+                None => 0,
+                Some(v) => v.as_u64()
+                    .ok_or_else(|| QueryParseError::new(&pointer(&path, "slop"), ParseErrorReason::WrongValueType("u32")))? as u32,
+            };
+            t.boost(boost_param(&params, &path)?).add_match_phrase_query(k, query, slop).map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+
+    /// 分数固定为 boost, 所以 boost 不再包一层 BoostQuery
+    fn parse_constant_score(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = as_object(v, path)?;
+        if let Some(k) = params.keys().find(|k| k.as_str() != "filter" && k.as_str() != "boost") {
+            return Err(QueryParseError::new(&pointer(path, k), ParseErrorReason::UnsupportedParameter(k.clone())));
+        }
+        let score = boost_param(params, path)?;
+        let filter = params.get("filter")
+            .ok_or_else(|| QueryParseError::new(&pointer(path, "filter"), ParseErrorReason::MissingValue))?;
+        let t = self.down(Occur::Must).parse(filter, &pointer(path, "filter"))?;
+        Ok(t.up_constant_score(score))
+    }
+
+    fn parse_dis_max(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = as_object(v, path)?;
+        if let Some(k) = params.keys().find(|k| !["queries", "tie_breaker", "boost"].contains(&k.as_str())) {
+            return Err(QueryParseError::new(&pointer(path, k), ParseErrorReason::UnsupportedParameter(k.clone())));
+        }
+        let tie_breaker = f64_param(params, path, "tie_breaker", 0.0)? as f32;
+        let queries_path = pointer(path, "queries");
+        let queries = params.get("queries")
+            .ok_or_else(|| QueryParseError::new(&queries_path, ParseErrorReason::MissingValue))?
+            .as_array()
+            .ok_or_else(|| QueryParseError::new(&queries_path, ParseErrorReason::WrongValueType("array")))?;
+        let mut t = self.boost(boost_param(params, path)?).down(Occur::Should);
+        for (i, v) in queries.iter().enumerate() {
+            t = t.parse(v, &pointer(&queries_path, &i.to_string()))?;
+        }
+        Ok(t.up_clauses(|queries| Box::new(DisMaxQuery::new(queries, tie_breaker))))
+    }
+
+    /// 只支持 field_value_factor 和 gauss 两种函数, 没有 query 时和 es 一样匹配全部文档
+    fn parse_function_score(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        let params = as_object(v, path)?;
+        let mut functions = vec![];
+        for (k, v) in params {
+            match k.as_str() {
+                "query" | "score_mode" | "boost_mode" | "boost" => {}
+                "functions" => {
+                    let path = pointer(path, k);
+                    let list = v.as_array()
+                        .ok_or_else(|| QueryParseError::new(&path, ParseErrorReason::WrongValueType("array")))?;
+                    for (i, v) in list.iter().enumerate() {
+                        functions.push(self.parse_score_function(v, &pointer(&path, &i.to_string()))?);
                     }
-                    t
-                })
+                }
+                // 单个函数的简写, 函数直接写在 function_score 下
+                "field_value_factor" | "gauss" | "weight" => {}
+                _ => return Err(QueryParseError::new(&pointer(path, k), ParseErrorReason::UnsupportedParameter(k.clone()))),
             }
         }
-        if let Some(v) = v.get("range") {
-            if let Some(v) = v.as_object() {
-                return v.iter().fold(self, |t,(k,v )| {
-                    //需要4个参数都有才行
-                    if let Some(left) = v["from"].as_u64() {
-                        if let Some(right) = v["to"].as_u64() {
-                            if let Some(include_left) = v["include_lower"].as_bool() {
-                                if let Some(include_right) = v["include_upper"].as_bool() {
-                                    return t.add_range_query(k, left, right, include_left, include_right);
-                                }
-                            }
-                        }
-                    }
-                    t
-                })
+        if params.contains_key("field_value_factor") || params.contains_key("gauss") {
+            let mut shorthand = params.clone();
+            shorthand.retain(|k, _| k == "field_value_factor" || k == "gauss" || k == "weight");
+            functions.push(self.parse_score_function(&Value::Object(shorthand), path)?);
+        }
+        let score_mode = enum_param(params, path, "score_mode", ScoreMode::Multiply,
+                                    "multiply, sum, avg, first, max or min", ScoreMode::from_name)?;
+        let boost_mode = enum_param(params, path, "boost_mode", BoostMode::Multiply,
+                                    "multiply, replace, sum, avg, max or min", BoostMode::from_name)?;
+        let mut t = self.boost(boost_param(params, path)?).down(Occur::Must);
+        if let Some(query) = params.get("query") {
+            t = t.parse(query, &pointer(path, "query"))?;
+        }
+        Ok(t.up_map(|q| Box::new(FunctionScoreQuery::new(q, functions, score_mode, boost_mode))))
+    }
+
+    fn parse_score_function(&self, v: &Value, path: &str) -> Result<WeightedFunction, QueryParseError> {
+        let params = as_object(v, path)?;
+        let weight = f64_param(params, path, "weight", 1.0)?;
+        let mut function = None;
+        for (k, v) in params {
+            let path = pointer(path, k);
+            function = match k.as_str() {
+                "weight" => continue,
+                "field_value_factor" if function.is_none() => Some(self.parse_field_value_factor(v, &path)?),
+                "gauss" if function.is_none() => Some(self.parse_gauss(v, &path)?),
+                _ => return Err(QueryParseError::new(&path, ParseErrorReason::UnsupportedParameter(k.clone()))),
+            };
+        }
+        let function = function
+            .ok_or_else(|| QueryParseError::new(path, ParseErrorReason::MissingValue))?;
+        Ok(WeightedFunction { function, weight })
+    }
+
+    fn parse_field_value_factor(&self, v: &Value, path: &str) -> Result<ScoreFunction, QueryParseError> {
+        let params = field_params(v, path, &["field", "factor", "modifier", "missing"])?;
+        let field_path = pointer(path, "field");
+        let field = params.get("field")
+            .ok_or_else(|| QueryParseError::new(&field_path, ParseErrorReason::MissingValue))?
+            .as_str()
+            .ok_or_else(|| QueryParseError::new(&field_path, ParseErrorReason::WrongValueType("string")))?;
+        let name = field;
+        let field = self.fast_u64_field(field).map_err(|reason| QueryParseError::new(&field_path, reason))?;
+        let missing = u64_param(&params, path, "missing")?;
+        // fast field 里没有值的文档存的也是 0, 只有建了索引的字段才能分出来哪些文档没有值
+        if missing.is_some() && !self.indexed(field) {
+            let reason = ParseErrorReason::UnsupportedClause(format!("missing on field `{}` which is not indexed", name));
+            return Err(QueryParseError::new(&pointer(path, "missing"), reason));
+        }
+        Ok(ScoreFunction::FieldValueFactor {
+            field,
+            factor: f64_param(&params, path, "factor", 1.0)?,
+            modifier: enum_param(&params, path, "modifier", Modifier::None,
+                                 "none, log, log1p, log2p, ln, ln1p, ln2p, square, sqrt or reciprocal", Modifier::from_name)?,
+            missing,
+        })
+    }
+
+    /// `{"gauss": {"time": {"origin": 1567000000000, "scale": 3600000, "offset": 0, "decay": 0.5}}}`
+    fn parse_gauss(&self, v: &Value, path: &str) -> Result<ScoreFunction, QueryParseError> {
+        let params = as_object(v, path)?;
+        if params.len() != 1 {
+            return Err(QueryParseError::new(path, ParseErrorReason::WrongValueType("object with a single field")));
+        }
+        let (k, v) = params.iter().next().expect("params has one entry");
+        let path = pointer(path, k);
+        let field = self.fast_u64_field(k).map_err(|reason| QueryParseError::new(&path, reason))?;
+        let params = field_params(v, &path, &["origin", "scale", "offset", "decay"])?;
+        let origin = u64_param(&params, &path, "origin")?
+            .ok_or_else(|| QueryParseError::new(&pointer(&path, "origin"), ParseErrorReason::MissingValue))?;
+        let scale = match u64_param(&params, &path, "scale")? {
+            Some(scale) if scale > 0 => scale,
+            Some(_) => return Err(QueryParseError::new(&pointer(&path, "scale"), ParseErrorReason::WrongValueType("positive u64"))),
+            None => return Err(QueryParseError::new(&pointer(&path, "scale"), ParseErrorReason::MissingValue)),
+        };
+        let decay = f64_param(&params, &path, "decay", 0.5)?;
+        if !(decay > 0.0 && decay < 1.0) {
+            return Err(QueryParseError::new(&pointer(&path, "decay"), ParseErrorReason::WrongValueType("number between 0 and 1")));
+        }
+        Ok(ScoreFunction::Gauss {
+            field,
+            origin,
+            scale,
+            offset: u64_param(&params, &path, "offset")?.unwrap_or(0),
+            decay,
+        })
+    }
+
+    /// gt/gte/lt/lte 任意一边可以省略, 也兼容 from/to + include_lower/include_upper 的老写法
+    fn parse_range(self, v: &Value, path: &str) -> Result<Self, QueryParseError> {
+        as_object(v, path)?.iter().try_fold(self, |t, (k, v)| {
+            let path = pointer(path, k);
+            let params = as_object(v, &path)?;
+            for key in params.keys() {
+                if !RANGE_PARAMS.contains(&key.as_str()) {
+                    return Err(QueryParseError::new(&pointer(&path, key), ParseErrorReason::UnsupportedParameter(key.clone())));
+                }
             }
+            let include = |key: &str| -> Result<bool, QueryParseError> {
+                match params.get(key) {
+                    Some(v) => v.as_bool()
+                        .ok_or_else(|| QueryParseError::new(&pointer(&path, key), ParseErrorReason::WrongValueType("boolean"))),
+                    None => Ok(true),
+                }
+            };
+            let bound = |legacy: &str, include_legacy: &str, exclusive: &str, inclusive: &str| -> Result<Bound<&Value>, QueryParseError> {
+                if let Some(v) = params.get(exclusive) {
+                    return Ok(Bound::Excluded(v));
+                }
+                if let Some(v) = params.get(inclusive) {
+                    return Ok(Bound::Included(v));
+                }
+                match params.get(legacy) {
+                    Some(Value::Null) | None => Ok(Bound::Unbounded),
+                    Some(v) if include(include_legacy)? => Ok(Bound::Included(v)),
+                    Some(v) => Ok(Bound::Excluded(v)),
+                }
+            };
+            let left = bound("from", "include_lower", "gt", "gte")?;
+            let right = bound("to", "include_upper", "lt", "lte")?;
+            if let (Bound::Unbounded, Bound::Unbounded) = (left, right) {
+                return Err(QueryParseError::new(&path, ParseErrorReason::MissingBound("gt, gte, lt or lte")));
+            }
+            t.boost(boost_param(params, &path)?).add_range_query(k, left, right)
+                .map_err(|reason| QueryParseError::new(&path, reason))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::CatQuery;
+    use crate::query_builder::TimeRangePolicy;
+    use serde_json::json;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{Schema, FAST, INDEXED, TEXT};
+    use tantivy::{doc, DocId, Score};
+
+    fn index() -> Index {
+        let mut schema_builder = Schema::builder();
+        let title = schema_builder.add_text_field("title", TEXT);
+        let status = schema_builder.add_u64_field("status", INDEXED);
+        let time = schema_builder.add_u64_field("time", INDEXED | FAST);
+        schema_builder.add_u64_field("likes", FAST);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        let docs = [
+            ("quick brown fox", 1u64),
+            ("quick fox", 2),
+            ("lazy brown dog", 1),
+            ("quick quick dog", 3),
+            ("brown cow", 2),
+        ];
+        for (i, &(text, s)) in docs.iter().enumerate() {
+            index_writer.add_document(doc!(title => text, status => s, time => 10 * (i as u64 + 1)));
         }
+        index_writer.commit().unwrap();
+        index
+    }
 
-        self
+    fn query(index: &Index, query: Value) -> Box<dyn Query> {
+        parse(&query, index, Rc::new(TimeRangePolicy::default()), None).unwrap()
     }
-}
\ No newline at end of file
+
+    /// 命中的文档和分数, 按分数降序, 分数相同时按文档号
+    fn hits(index: &Index, q: Value) -> Vec<(DocId, Score)> {
+        let searcher = index.reader().unwrap().searcher();
+        let mut hits: Vec<(DocId, Score)> = searcher.search(query(index, q).as_ref(), &TopDocs::with_limit(10)).unwrap()
+            .into_iter()
+            .map(|(score, address)| (address.1, score))
+            .collect();
+        hits.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+        hits
+    }
+
+    fn docs(index: &Index, q: Value) -> Vec<DocId> {
+        hits(index, q).into_iter().map(|(doc, _)| doc).collect()
+    }
+
+    fn sorted_docs(index: &Index, q: Value) -> Vec<DocId> {
+        let mut docs = docs(index, q);
+        docs.sort_unstable();
+        docs
+    }
+
+    #[test]
+    fn test_term_and_match() {
+        let index = index();
+        assert_eq!(docs(&index, json!({"term": {"status": 1}})), vec![0, 2]);
+        assert_eq!(docs(&index, json!({"term": {"status": {"value": "2"}}})), vec![1, 4]);
+        let or = docs(&index, json!({"match": {"title": "quick dog"}}));
+        assert_eq!(or[0], 3);
+        assert_eq!(sorted_docs(&index, json!({"match": {"title": "quick dog"}})), vec![0, 1, 2, 3]);
+        assert_eq!(docs(&index, json!({"match": {"title": {"query": "Quick DOG", "operator": "and"}}})), vec![3]);
+        assert!(docs(&index, json!({"match": {"title": "cat"}})).is_empty());
+    }
+
+    #[test]
+    fn test_match_phrase_slop() {
+        let mut schema_builder = Schema::builder();
+        let text = schema_builder.add_text_field("text", TEXT);
+        let index = Index::create_in_ram(schema_builder.build());
+        let mut index_writer = index.writer_with_num_threads(1, 10_000_000).unwrap();
+        for s in &["a b c", "a x b c", "a x b x c", "b a c", "c b a", "a"] {
+            index_writer.add_document(doc!(text => *s));
+        }
+        index_writer.commit().unwrap();
+        let phrase = |q: &str, slop: u32| sorted_docs(&index, json!({"match_phrase": {"text": {"query": q, "slop": slop}}}));
+        assert_eq!(phrase("a b c", 0), vec![0]);
+        assert_eq!(phrase("a b c", 1), vec![0, 1]);
+        // 和 es 一样 slop 是所有词挪动距离的总和, 交换两个词要 2
+        assert_eq!(phrase("a b c", 2), vec![0, 1, 2, 3]);
+        assert_eq!(phrase("b a", 1), vec![3, 4]);
+        assert_eq!(phrase("a b", 1), vec![0, 1, 2]);
+        assert_eq!(phrase("a b", 2), vec![0, 1, 2, 3, 4]);
+        assert_eq!(phrase("a b c", 3), vec![0, 1, 2, 3]);
+        assert_eq!(phrase("a b c", 4), vec![0, 1, 2, 3, 4]);
+        assert!(phrase("a a", 3).is_empty());
+    }
+
+    #[test]
+    fn test_bool() {
+        let index = index();
+        // should 只影响分数: status 2 的 doc 1 排到前面
+        let q = json!({"bool": {
+            "must": [{"match": {"title": "quick"}}],
+            "must_not": [{"term": {"status": 3}}],
+            "should": [{"term": {"status": 2}}]
+        }});
+        assert_eq!(docs(&index, q), vec![1, 0]);
+        assert_eq!(sorted_docs(&index, json!({"bool": {"should": [{"term": {"status": 1}}, {"term": {"status": 3}}]}})), vec![0, 2, 3]);
+        assert_eq!(sorted_docs(&index, json!({"bool": {"must_not": [{"match": {"title": "brown"}}]}})), vec![1, 3]);
+
+        // filter 只过滤, 分数和没有 filter 时一样
+        let matched = hits(&index, json!({"match": {"title": "brown"}}));
+        let filtered = hits(&index, json!({"bool": {"must": [{"match": {"title": "brown"}}], "filter": [{"term": {"status": 1}}]}}));
+        assert_eq!(filtered.len(), 2);
+        for hit in &filtered {
+            assert!(matched.contains(hit), "{:?} {:?}", filtered, matched);
+        }
+        let filter_only = hits(&index, json!({"bool": {"filter": [{"match": {"title": "quick"}}, {"term": {"status": 2}}]}}));
+        assert_eq!(filter_only, vec![(1, 0.0)]);
+        // filter 里的 boost 也不参与打分
+        let boosted = hits(&index, json!({"bool": {"filter": {"term": {"status": {"value": 2, "boost": 5}}}}}));
+        assert_eq!(boosted, vec![(1, 0.0), (4, 0.0)]);
+    }
+
+    #[test]
+    fn test_minimum_should_match() {
+        let index = index();
+        let should = |msm: Value| json!({"bool": {
+            "should": [{"term": {"title": "quick"}}, {"term": {"title": "brown"}}, {"term": {"title": "dog"}}],
+            "minimum_should_match": msm
+        }});
+        assert_eq!(sorted_docs(&index, should(json!(1))), vec![0, 1, 2, 3, 4]);
+        assert_eq!(sorted_docs(&index, should(json!(2))), vec![0, 2, 3]);
+        assert_eq!(sorted_docs(&index, should(json!("-1"))), vec![0, 2, 3]);
+        assert_eq!(sorted_docs(&index, should(json!("67%"))), vec![0, 2, 3]);
+        assert!(sorted_docs(&index, should(json!("100%"))).is_empty());
+        assert!(sorted_docs(&index, should(json!(4))).is_empty());
+        // 有 must 时 should 默认是可选的, 设置了 minimum_should_match 就必须命中
+        let q = json!({"bool": {"must": [{"term": {"status": 1}}], "should": [{"term": {"title": "dog"}}]}});
+        assert_eq!(docs(&index, q), vec![2, 0]);
+        let q = json!({"bool": {"must": [{"term": {"status": 1}}], "should": [{"term": {"title": "dog"}}], "minimum_should_match": 1}});
+        assert_eq!(docs(&index, q), vec![2]);
+    }
+
+    #[test]
+    fn test_dis_max() {
+        let index = index();
+        let score = |q: Value, doc: DocId| hits(&index, q).into_iter().find(|&(d, _)| d == doc).map(|(_, s)| s).unwrap();
+        let quick = score(json!({"match": {"title": "quick"}}), 0);
+        let brown = score(json!({"match": {"title": "brown"}}), 0);
+        let dis_max = |tie_breaker: f64| json!({"dis_max": {
+            "queries": [{"match": {"title": "quick"}}, {"match": {"title": "brown"}}],
+            "tie_breaker": tie_breaker
+        }});
+        assert_eq!(sorted_docs(&index, dis_max(0.0)), vec![0, 1, 2, 3, 4]);
+        assert!((score(dis_max(0.0), 0) - quick.max(brown)).abs() < 1e-5);
+        assert!((score(dis_max(0.5), 0) - (quick.max(brown) + 0.5 * quick.min(brown))).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_constant_score() {
+        let index = index();
+        let q = json!({"constant_score": {"filter": {"match": {"title": "quick"}}, "boost": 2.5}});
+        assert_eq!(hits(&index, q), vec![(0, 2.5), (1, 2.5), (3, 2.5)]);
+        let q = json!({"constant_score": {"filter": {"bool": {"must_not": {"term": {"status": 1}}}}}});
+        assert_eq!(hits(&index, q), vec![(1, 1.0), (3, 1.0), (4, 1.0)]);
+    }
+
+    #[test]
+    fn test_function_score() {
+        let index = index();
+        // 分数换成 time, 越新越靠前
+        let q = json!({"function_score": {"field_value_factor": {"field": "time"}, "boost_mode": "replace"}});
+        assert_eq!(hits(&index, q), vec![(4, 50.0), (3, 40.0), (2, 30.0), (1, 20.0), (0, 10.0)]);
+        let q = json!({"function_score": {
+            "query": {"term": {"status": 2}},
+            "functions": [
+                {"field_value_factor": {"field": "time", "factor": 0.1}},
+                {"gauss": {"time": {"origin": 20, "scale": 10}}, "weight": 4}
+            ],
+            "score_mode": "sum",
+            "boost_mode": "replace"
+        }});
+        let hits = hits(&index, q);
+        assert_eq!(hits.iter().map(|&(doc, _)| doc).collect::<Vec<_>>(), vec![1, 4]);
+        assert!((hits[0].1 - 6.0).abs() < 1e-5, "{:?}", hits);
+
+        // fast field 里没有值也是 0, 没建索引时不能用 missing
+        let q = json!({"function_score": {"field_value_factor": {"field": "likes", "missing": 1}}});
+        let error = parse(&q, &index, Rc::new(TimeRangePolicy::default()), None).unwrap_err();
+        assert_eq!(error.path, "/query/function_score/field_value_factor/missing");
+        assert_eq!(error.reason, ParseErrorReason::UnsupportedClause("missing on field `likes` which is not indexed".to_string()));
+        let q = json!({"function_score": {"field_value_factor": {"field": "time", "missing": 1}}});
+        assert!(parse(&q, &index, Rc::new(TimeRangePolicy::default()), None).is_ok());
+        // 结果不是有限数时和 es 一样报错
+        let q = json!({"function_score": {"field_value_factor": {"field": "time", "factor": 0, "modifier": "reciprocal"}}});
+        let searcher = index.reader().unwrap().searcher();
+        assert!(searcher.search(query(&index, q).as_ref(), &TopDocs::with_limit(10)).is_err());
+    }
+
+    #[test]
+    fn test_ranges_are_unified_into_one_cat_query() {
+        let index = index();
+        // 同一字段上的多个 range 取交集, 嵌套 bool 里的 range 不和外层合并
+        let q = json!({"bool": {
+            "must": [{"match": {"title": "quick"}}],
+            "filter": [{"range": {"time": {"gte": 20}}}, {"range": {"time": {"lt": 50}}}]
+        }});
+        assert!(query(&index, q.clone()).downcast_ref::<CatQuery>().is_some());
+        assert_eq!(sorted_docs(&index, q), vec![1, 3]);
+        let q = json!({"bool": {
+            "filter": [{"range": {"time": {"gt": 10}}}, {"bool": {"should": [{"range": {"time": {"lte": 20}}}, {"term": {"status": 3}}]}}]
+        }});
+        assert_eq!(sorted_docs(&index, q), vec![1, 3]);
+        // 带 boost 的 range 要打分, 不进 CatQuery
+        let q = json!({"bool": {"must": [{"range": {"time": {"gte": 30, "boost": 2}}}]}});
+        assert!(query(&index, q.clone()).downcast_ref::<CatQuery>().is_none());
+        assert_eq!(hits(&index, q), vec![(2, 2.0), (3, 2.0), (4, 2.0)]);
+    }
+
+    /// 不把 range 合进 CatQuery
+    struct NoCatQuery;
+    impl PrimaryRangePolicy for NoCatQuery {
+        fn accept(&self, _: &crate::query_builder::RangeCandidate) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_cat_query_keeps_scores() {
+        let index = index();
+        let searcher = index.reader().unwrap().searcher();
+        let search = |q: &Value, policy: Rc<dyn PrimaryRangePolicy>| {
+            let query = parse(q, &index, policy, None).unwrap();
+            let mut hits: Vec<(DocId, Score)> = searcher.search(query.as_ref(), &TopDocs::with_limit(10)).unwrap()
+                .into_iter()
+                .map(|(score, address)| (address.1, score))
+                .collect();
+            hits.sort_by_key(|&(doc, _)| doc);
+            hits
+        };
+        let assert_same_scores = |q: Value| {
+            let cat = search(&q, Rc::new(TimeRangePolicy::default()));
+            let plain = search(&q, Rc::new(NoCatQuery));
+            assert!(!cat.is_empty());
+            assert_eq!(cat.len(), plain.len(), "{}", q);
+            for (a, b) in cat.iter().zip(&plain) {
+                assert_eq!(a.0, b.0, "{}", q);
+                assert!((a.1 - b.1).abs() < 1e-5, "{}: {:?} {:?}", q, cat, plain);
+            }
+        };
+        let quick = json!({"match": {"title": "quick"}});
+        let without_range = search(&quick, Rc::new(TimeRangePolicy::default()));
+        let q = json!({"bool": {"must": quick, "filter": {"range": {"time": {"gte": 0, "lt": 1000}}}}});
+        assert!(query(&index, q.clone()).downcast_ref::<CatQuery>().is_some());
+        assert_eq!(search(&q, Rc::new(TimeRangePolicy::default())), without_range);
+        assert_same_scores(q);
+        assert_same_scores(json!({"bool": {"must": [quick, {"range": {"time": {"lt": 50}}}]}}));
+        assert_same_scores(json!({"bool": {
+            "should": [{"term": {"title": {"value": "dog", "boost": 3}}}, {"match": {"title": "brown"}}],
+            "filter": [{"range": {"time": {"gte": 20, "lt": 1000}}}, {"range": {"time": {"lte": 50}}}]
+        }}));
+        // 分数和 dsl 里的一样, 有 must 的 range 时每篇加 1 分
+        let must = search(&json!({"bool": {"must": [quick, {"range": {"time": {"gte": 0, "lt": 1000}}}]}}), Rc::new(TimeRangePolicy::default()));
+        for (a, b) in must.iter().zip(&without_range) {
+            assert!((a.1 - b.1 - 1.0).abs() < 1e-5);
+        }
+    }
+
+    /// 什么 range 都合进 CatQuery, 包括 should 里的
+    struct AnyRange;
+    impl PrimaryRangePolicy for AnyRange {
+        fn accept(&self, _: &crate::query_builder::RangeCandidate) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_dis_max_keeps_range_disjuncts() {
+        let index = index();
+        let searcher = index.reader().unwrap().searcher();
+        let search = |policy: Rc<dyn PrimaryRangePolicy>| {
+            let q = json!({"dis_max": {"queries": [{"match": {"title": "dog"}}, {"range": {"time": {"lte": 20}}}]}});
+            let query = parse(&q, &index, policy, None).unwrap();
+            let mut hits: Vec<(DocId, Score)> = searcher.search(query.as_ref(), &TopDocs::with_limit(10)).unwrap()
+                .into_iter()
+                .map(|(score, address)| (address.1, score))
+                .collect();
+            hits.sort_by_key(|&(doc, _)| doc);
+            hits
+        };
+        let plain = search(Rc::new(NoCatQuery));
+        assert_eq!(plain.iter().map(|&(doc, _)| doc).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_eq!(plain[0].1, 1.0);
+        assert_eq!(search(Rc::new(TimeRangePolicy::default())), plain);
+        assert_eq!(search(Rc::new(AnyRange)), plain);
+    }
+
+    #[test]
+    fn test_result_window() {
+        use crate::search_request::{SearchRequest, MAX_RESULT_WINDOW};
+        let index = index();
+        let parse = |body: &str| SearchRequest::parse(body, &index, Rc::new(TimeRangePolicy::default()), None).map(|_| ());
+        let error = parse(r#"{"size": 18446744073709551615, "from": 1}"#).unwrap_err();
+        assert_eq!(error.reason, ParseErrorReason::ResultWindowTooLarge { from: 1, size: usize::MAX, max: MAX_RESULT_WINDOW });
+        let error = parse(r#"{"size": 100000000000}"#).unwrap_err();
+        assert_eq!(error.reason, ParseErrorReason::ResultWindowTooLarge { from: 0, size: 100_000_000_000, max: MAX_RESULT_WINDOW });
+        assert!(parse(r#"{"size": 9990, "from": 10}"#).is_ok());
+        assert!(parse(r#"{"size": 9990, "from": 11}"#).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::BufRead;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde_json::{json, Value};
+use tantivy::collector::Count;
+use tantivy::{Index, IndexReader};
+use crate::query::FilterCache;
+use crate::query_builder::TimeRangePolicy;
+use crate::query_parser;
+
+/// 一行请求的结果, `line` 从 1 开始
+pub struct Outcome {
+    pub line: usize,
+    pub result: Result<(usize, Duration), String>,
+}
+
+impl Outcome {
+    pub fn to_json(&self) -> Value {
+        match self.result {
+            Ok((hits, took)) => json!({ "line": self.line, "hits": hits, "took_ms": millis(took) }),
+            Err(ref e) => json!({ "line": self.line, "error": e }),
+        }
+    }
+}
+
+pub struct Report {
+    /// 按行号排好序
+    pub outcomes: Vec<Outcome>,
+    pub took: Duration,
+    pub threads: usize,
+}
+
+impl Report {
+    /// 只统计成功的请求的耗时
+    pub fn summary(&self) -> Value {
+        let mut latencies: Vec<Duration> = self.outcomes.iter()
+            .filter_map(|o| o.result.as_ref().ok().map(|&(_, took)| took))
+            .collect();
+        latencies.sort_unstable();
+        let percentile = |p: usize| match latencies.len() {
+            0 => Value::Null,
+            n => json!(millis(latencies[((n * p).div_ceil(100)).max(1) - 1])),
+        };
+        json!({
+            "requests": self.outcomes.len(),
+            "errors": self.outcomes.len() - latencies.len(),
+            "threads": self.threads,
+            "took_ms": millis(self.took),
+            "qps": match self.took.as_secs_f64() {
+                secs if secs > 0.0 => json!(self.outcomes.len() as f64 / secs),
+                _ => Value::Null,
+            },
+            "p50_ms": percentile(50),
+            "p95_ms": percentile(95),
+            "p99_ms": percentile(99),
+            "max_ms": latencies.last().map(|&took| millis(took)),
+        })
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// 每行是一个请求体, 有 `query` 时只取它, 否则整行当作查询. 空行跳过
+fn run_line(index: &Index, reader: &IndexReader, cache: &Arc<FilterCache>, line: &str) -> Result<(usize, Duration), String> {
+    let start = Instant::now();
+    let v: Value = serde_json::from_str(line).map_err(|e| format!("invalid json: {}", e))?;
+    let query = v.get("query").unwrap_or(&v);
+    let query = query_parser::parse(query, index, Rc::new(TimeRangePolicy::default()), Some(Arc::clone(cache))).map_err(|e| e.to_string())?;
+    let hits = reader.searcher().search(query.as_ref(), &Count).map_err(|e| e.to_string())?;
+    Ok((hits, start.elapsed()))
+}
+
+/// `threads` 个线程共用一个 `IndexReader` 和它的过滤缓存, 边读边执行 `input` 里的请求
+pub fn replay<R: BufRead + Send>(index: &Index, reader: &IndexReader, input: R, threads: usize) -> Report {
+    let threads = threads.max(1);
+    let cache = Arc::new(FilterCache::default());
+    // 目录不支持 watch 时不清理, 回放期间合并掉的 segment 留在缓存里直到回放结束
+    let _invalidate = cache.invalidate_on_reload(index, reader).ok();
+    let lines = Mutex::new(input.lines().enumerate());
+    let outcomes = Mutex::new(vec![]);
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|| loop {
+                let (line, text) = match lines.lock().expect("replay input").next() {
+                    Some((i, text)) => (i + 1, text),
+                    None => break,
+                };
+                let result = match text {
+                    Ok(ref text) if text.trim().is_empty() => continue,
+                    Ok(text) => run_line(index, reader, &cache, &text),
+                    Err(e) => Err(format!("read: {}", e)),
+                };
+                outcomes.lock().expect("replay outcomes").push(Outcome { line, result });
+            });
+        }
+    });
+    let mut outcomes = outcomes.into_inner().expect("replay outcomes");
+    outcomes.sort_by_key(|o| o.line);
+    Report {
+        outcomes,
+        took: start.elapsed(),
+        threads,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(latencies: &[u64], errors: usize) -> Report {
+        let mut outcomes: Vec<Outcome> = latencies.iter().enumerate()
+            .map(|(i, &ms)| Outcome { line: i + 1, result: Ok((1, Duration::from_millis(ms))) })
+            .collect();
+        for i in 0..errors {
+            outcomes.push(Outcome { line: latencies.len() + i + 1, result: Err("invalid json".to_string()) });
+        }
+        Report { outcomes, took: Duration::from_secs(2), threads: 4 }
+    }
+
+    fn assert_ms(summary: &Value, key: &str, expected: f64) {
+        let actual = summary[key].as_f64().unwrap_or_else(|| panic!("{} is {}", key, summary[key]));
+        assert!((actual - expected).abs() < 1e-9, "{} is {}, expected {}", key, actual, expected);
+    }
+
+    #[test]
+    fn test_summary() {
+        // 打乱顺序, 分位数按排好序的耗时取
+        let latencies: Vec<u64> = (1..=100).map(|i| i * 37 % 101).collect();
+        let summary = report(&latencies, 3).summary();
+        assert_eq!(summary["requests"], 103);
+        assert_eq!(summary["errors"], 3);
+        assert_eq!(summary["threads"], 4);
+        assert_ms(&summary, "took_ms", 2000.0);
+        assert_ms(&summary, "qps", 51.5);
+        assert_ms(&summary, "p50_ms", 50.0);
+        assert_ms(&summary, "p95_ms", 95.0);
+        assert_ms(&summary, "p99_ms", 99.0);
+        assert_ms(&summary, "max_ms", 100.0);
+
+        let summary = report(&[7, 3], 0).summary();
+        assert_ms(&summary, "p50_ms", 3.0);
+        assert_ms(&summary, "p99_ms", 7.0);
+        assert_ms(&summary, "max_ms", 7.0);
+    }
+
+    #[test]
+    fn test_summary_without_successes() {
+        let summary = report(&[], 2).summary();
+        assert_eq!(summary["requests"], 2);
+        assert_eq!(summary["errors"], 2);
+        assert!(summary["p50_ms"].is_null());
+        assert!(summary["p99_ms"].is_null());
+        assert!(summary["max_ms"].is_null());
+    }
+}
@@ -0,0 +1,510 @@
+use crate::query::{CatQuery, FilterCache, SortOrder};
+use crate::query_builder::PrimaryRangePolicy;
+use crate::query_parser::{self, pointer, ParseErrorReason, QueryParseError};
+use serde_json::{json, Value};
+use std::cmp::Ordering;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tantivy::collector::{Count, MultiCollector, ScoreSegmentTweaker, ScoreTweaker, TopDocs};
+use tantivy::fastfield::FastFieldReader;
+use tantivy::query::{AllQuery, Query};
+use tantivy::schema::{Cardinality, Field, FieldType, Schema, Type};
+use tantivy::{u64_to_f64, u64_to_i64, DocAddress, DocId, Index, Score, Searcher, SegmentReader, TantivyError};
+
+/// es 的默认值, 命中数超过它之后只报告下限
+const DEFAULT_TRACK_TOTAL_HITS: usize = 10000;
+const DEFAULT_SIZE: usize = 10;
+/// es 的 index.max_result_window, `from + size` 不能超过它
+pub const MAX_RESULT_WINDOW: usize = 10000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortTarget {
+    Score,
+    /// 段内按文档号排序, 段与段之间不保证顺序
+    Doc,
+    Field(Field),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SortField {
+    pub target: SortTarget,
+    pub order: SortOrder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TrackTotalHits {
+    Disabled,
+    Exact,
+    UpTo(usize),
+}
+
+/// `_source` 的 includes/excludes, 支持 `*` 通配
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceFilter {
+    pub enabled: bool,
+    pub includes: Vec<String>,
+    pub excludes: Vec<String>,
+}
+
+impl SourceFilter {
+    fn all() -> Self {
+        SourceFilter {
+            enabled: true,
+            includes: vec![],
+            excludes: vec![],
+        }
+    }
+
+    pub fn accepts(&self, field: &str) -> bool {
+        (self.includes.is_empty() || self.includes.iter().any(|p| wildcard_match(p, field)))
+            && !self.excludes.iter().any(|p| wildcard_match(p, field))
+    }
+}
+
+fn wildcard_match(pattern: &str, s: &str) -> bool {
+    match pattern.find('*') {
+        None => pattern == s,
+        Some(i) => {
+            let (prefix, rest) = (&pattern[..i], &pattern[i + 1..]);
+            s.starts_with(prefix) && (i..=s.len()).any(|j| s.is_char_boundary(j) && wildcard_match(rest, &s[j..]))
+        }
+    }
+}
+
+pub struct SearchRequest {
+    pub query: Box<dyn Query>,
+    pub size: usize,
+    pub from: usize,
+    pub sort: Vec<SortField>,
+    pub source: SourceFilter,
+    pub track_total_hits: TrackTotalHits,
+}
+
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum SortValue {
+    Score(Score),
+    Doc(u64),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+}
+
+impl SortValue {
+    fn to_json(&self) -> Value {
+        match *self {
+            SortValue::Score(v) => Value::from(v),
+            SortValue::Doc(v) | SortValue::U64(v) => Value::from(v),
+            SortValue::I64(v) => Value::from(v),
+            SortValue::F64(v) => Value::from(v),
+        }
+    }
+}
+
+/// 一篇文档在所有排序键上的值, 比较时 asc 的键取反, 这样 top collector 保留"最大"的就是排在前面的
+#[derive(Clone, Debug, PartialEq)]
+struct SortKey(Vec<(SortValue, SortOrder)>);
+
+impl PartialOrd for SortKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        for ((a, order), (b, _)) in self.0.iter().zip(other.0.iter()) {
+            let ord = a.partial_cmp(b).unwrap_or(Ordering::Equal);
+            let ord = match order {
+                SortOrder::Desc => ord,
+                SortOrder::Asc => ord.reverse(),
+            };
+            if ord != Ordering::Equal {
+                return Some(ord);
+            }
+        }
+        Some(Ordering::Equal)
+    }
+}
+
+enum SortReader {
+    Score,
+    Doc,
+    U64(FastFieldReader<u64>),
+    I64(FastFieldReader<i64>),
+    F64(FastFieldReader<f64>),
+}
+
+struct SortTweaker {
+    sort: Vec<SortField>,
+}
+
+struct SortSegmentTweaker {
+    readers: Vec<(SortReader, SortOrder)>,
+}
+
+impl ScoreTweaker<SortKey> for SortTweaker {
+    type Child = SortSegmentTweaker;
+
+    fn segment_tweaker(&self, reader: &SegmentReader) -> tantivy::Result<SortSegmentTweaker> {
+        let readers = self.sort.iter()
+            .map(|s| {
+                let sort_reader = match s.target {
+                    SortTarget::Score => SortReader::Score,
+                    SortTarget::Doc => SortReader::Doc,
+                    SortTarget::Field(field) => {
+                        let fast_fields = reader.fast_fields();
+                        fast_fields.u64(field).map(SortReader::U64)
+                            .or_else(|| fast_fields.i64(field).map(SortReader::I64))
+                            .or_else(|| fast_fields.f64(field).map(SortReader::F64))
+                            .ok_or_else(|| {
+                                let name = reader.schema().get_field_name(field).to_string();
+                                TantivyError::SchemaError(format!("Field {:?} is not a fast field", name))
+                            })?
+                    }
+                };
+                Ok((sort_reader, s.order))
+            })
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        Ok(SortSegmentTweaker { readers })
+    }
+}
+
+impl ScoreSegmentTweaker<SortKey> for SortSegmentTweaker {
+    fn score(&self, doc: DocId, score: Score) -> SortKey {
+        SortKey(self.readers.iter()
+            .map(|(r, order)| {
+                let value = match r {
+                    SortReader::Score => SortValue::Score(score),
+                    SortReader::Doc => SortValue::Doc(u64::from(doc)),
+                    SortReader::U64(r) => SortValue::U64(r.get(doc)),
+                    SortReader::I64(r) => SortValue::I64(r.get(doc)),
+                    SortReader::F64(r) => SortValue::F64(r.get(doc)),
+                };
+                (value, *order)
+            })
+            .collect())
+    }
+}
+
+pub struct Hit {
+    pub address: DocAddress,
+    pub score: Option<Score>,
+    pub sort: Vec<SortValue>,
+    pub source: Option<Value>,
+}
+
+pub struct SearchResponse {
+    pub took: Duration,
+    /// 命中总数和它是否精确, 没有统计时为 None
+    pub total: Option<(usize, bool)>,
+    pub hits: Vec<Hit>,
+}
+
+impl SearchResponse {
+    /// 输出 es 格式的响应
+    pub fn to_json(&self) -> Value {
+        let hits: Vec<Value> = self.hits.iter()
+            .map(|hit| {
+                let mut v = json!({
+                    "_segment": hit.address.segment_ord(),
+                    "_doc": hit.address.doc(),
+                    "_score": hit.score,
+                });
+                if !hit.sort.is_empty() {
+                    v["sort"] = Value::Array(hit.sort.iter().map(SortValue::to_json).collect());
+                }
+                if let Some(ref source) = hit.source {
+                    v["_source"] = source.clone();
+                }
+                v
+            })
+            .collect();
+        let mut response = json!({
+            "took": self.took.as_millis() as u64,
+            "hits": { "hits": hits },
+        });
+        if let Some((value, exact)) = self.total {
+            response["hits"]["total"] = json!({
+                "value": value,
+                "relation": if exact { "eq" } else { "gte" },
+            });
+        }
+        response
+    }
+}
+
+fn usize_param(v: &Value, path: &str) -> Result<usize, QueryParseError> {
+    v.as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| QueryParseError::new(path, ParseErrorReason::WrongValueType("non-negative integer")))
+}
+
+fn string_list(v: &Value, path: &str) -> Result<Vec<String>, QueryParseError> {
+    match v {
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Array(values) => values.iter().enumerate()
+            .map(|(i, v)| v.as_str()
+                .map(str::to_string)
+                .ok_or_else(|| QueryParseError::new(&pointer(path, &i.to_string()), ParseErrorReason::WrongValueType("string"))))
+            .collect(),
+        _ => Err(QueryParseError::new(path, ParseErrorReason::WrongValueType("string or array of strings"))),
+    }
+}
+
+/// `false`, `"field"`, `["a", "b*"]` 或 `{"includes": [..], "excludes": [..]}`
+fn parse_source(v: &Value, path: &str) -> Result<SourceFilter, QueryParseError> {
+    let mut source = SourceFilter::all();
+    match v {
+        Value::Bool(enabled) => source.enabled = *enabled,
+        Value::Object(params) => {
+            for (k, v) in params {
+                let path = pointer(path, k);
+                match k.as_str() {
+                    "includes" | "include" => source.includes = string_list(v, &path)?,
+                    "excludes" | "exclude" => source.excludes = string_list(v, &path)?,
+                    _ => return Err(QueryParseError::new(&path, ParseErrorReason::UnsupportedParameter(k.clone()))),
+                }
+            }
+        }
+        _ => source.includes = string_list(v, path)?,
+    }
+    Ok(source)
+}
+
+fn parse_track_total_hits(v: &Value, path: &str) -> Result<TrackTotalHits, QueryParseError> {
+    match v {
+        Value::Bool(true) => Ok(TrackTotalHits::Exact),
+        Value::Bool(false) => Ok(TrackTotalHits::Disabled),
+        _ => usize_param(v, path).map(TrackTotalHits::UpTo)
+            .map_err(|_| QueryParseError::new(path, ParseErrorReason::WrongValueType("boolean or non-negative integer"))),
+    }
+}
+
+fn parse_order(v: &Value, path: &str) -> Result<SortOrder, QueryParseError> {
+    match v.as_str().map(str::to_lowercase) {
+        Some(ref o) if o == "asc" => Ok(SortOrder::Asc),
+        Some(ref o) if o == "desc" => Ok(SortOrder::Desc),
+        _ => Err(QueryParseError::new(path, ParseErrorReason::WrongValueType("`asc` or `desc`"))),
+    }
+}
+
+/// 和 es 一样 `_score` 默认降序, 其余默认升序; 字段必须是单值的数值 fast field.
+/// tantivy 0.10 的 date 字段没有 fast field, 不能排序, date 类型的 time 范围也就走不到 `CatTopDocs`,
+/// 要按时间取最新的几篇, time 得用 u64/i64 存秒或毫秒
+fn parse_sort_field(schema: &Schema, name: &str, order: Option<&Value>, path: &str) -> Result<SortField, QueryParseError> {
+    let target = match name {
+        "_score" => SortTarget::Score,
+        "_doc" => SortTarget::Doc,
+        _ => {
+            let field = schema.get_field(name)
+                .ok_or_else(|| QueryParseError::new(path, ParseErrorReason::UnknownField(name.to_string())))?;
+            match schema.get_field_entry(field).field_type() {
+                FieldType::U64(options) | FieldType::I64(options) | FieldType::F64(options)
+                    if options.get_fastfield_cardinality() == Some(Cardinality::SingleValue) => SortTarget::Field(field),
+                _ => return Err(QueryParseError::new(path, ParseErrorReason::WrongValueType("single-valued numeric fast field"))),
+            }
+        }
+    };
+    let order = match order {
+        Some(v) => parse_order(v, &pointer(path, "order"))?,
+        None if target == SortTarget::Score => SortOrder::Desc,
+        None => SortOrder::Asc,
+    };
+    Ok(SortField { target, order })
+}
+
+/// `"time"`, `{"time": "desc"}`, `{"time": {"order": "desc"}}` 以及它们组成的数组
+fn parse_sort(schema: &Schema, v: &Value, path: &str) -> Result<Vec<SortField>, QueryParseError> {
+    match v {
+        Value::Array(keys) => {
+            let mut sort = vec![];
+            for (i, v) in keys.iter().enumerate() {
+                sort.extend(parse_sort(schema, v, &pointer(path, &i.to_string()))?);
+            }
+            Ok(sort)
+        }
+        Value::String(name) => Ok(vec![parse_sort_field(schema, name, None, path)?]),
+        Value::Object(keys) => keys.iter()
+            .map(|(name, v)| {
+                let path = pointer(path, name);
+                match v {
+                    Value::String(_) => parse_sort_field(schema, name, Some(v), &path)
+                        .map_err(|e| QueryParseError { path: e.path.trim_end_matches("/order").to_string(), ..e }),
+                    Value::Object(params) => {
+                        if let Some(k) = params.keys().find(|k| k.as_str() != "order") {
+                            return Err(QueryParseError::new(&pointer(&path, k), ParseErrorReason::UnsupportedParameter(k.clone())));
+                        }
+                        parse_sort_field(schema, name, params.get("order"), &path)
+                    }
+                    _ => Err(QueryParseError::new(&path, ParseErrorReason::WrongValueType("string or object"))),
+                }
+            })
+            .collect(),
+        _ => Err(QueryParseError::new(path, ParseErrorReason::WrongValueType("string, object or array"))),
+    }
+}
+
+impl SearchRequest {
+    /// 解析 `_search` 的完整请求体
+    pub fn parse(body: &str, index: &Index, policy: Rc<dyn PrimaryRangePolicy>, cache: Option<Arc<FilterCache>>) -> Result<SearchRequest, QueryParseError> {
+        let v: Value = serde_json::from_str(body)
+            .map_err(|e| QueryParseError::new("", ParseErrorReason::InvalidJson(e.to_string())))?;
+        let params = v.as_object()
+            .ok_or_else(|| QueryParseError::new("", ParseErrorReason::WrongValueType("object")))?;
+        let schema = index.schema();
+        let mut request = SearchRequest {
+            query: match params.get("query") {
+                Some(v) => query_parser::parse(v, index, policy, cache)?,
+                None => Box::new(AllQuery),
+            },
+            size: DEFAULT_SIZE,
+            from: 0,
+            sort: vec![],
+            source: SourceFilter::all(),
+            track_total_hits: TrackTotalHits::UpTo(DEFAULT_TRACK_TOTAL_HITS),
+        };
+        for (k, v) in params {
+            let path = pointer("", k);
+            match k.as_str() {
+                "query" => {}
+                "size" => request.size = usize_param(v, &path)?,
+                "from" => request.from = usize_param(v, &path)?,
+                "sort" => request.sort = parse_sort(&schema, v, &path)?,
+                "_source" => request.source = parse_source(v, &path)?,
+                "track_total_hits" => request.track_total_hits = parse_track_total_hits(v, &path)?,
+                _ => return Err(QueryParseError::new(&path, ParseErrorReason::UnsupportedParameter(k.clone()))),
+            }
+        }
+        // 结果窗口要在这里挡住, 否则 execute 里的 `from + size` 会溢出, 或者让 TopDocs 按它分配内存
+        match request.from.checked_add(request.size) {
+            Some(window) if window <= MAX_RESULT_WINDOW => {}
+            _ => return Err(QueryParseError::new("", ParseErrorReason::ResultWindowTooLarge {
+                from: request.from,
+                size: request.size,
+                max: MAX_RESULT_WINDOW,
+            })),
+        }
+        Ok(request)
+    }
+
+    /// 没有指定 sort 时按 `_score` 降序
+    fn sort_fields(&self) -> Vec<SortField> {
+        if self.sort.is_empty() {
+            vec![SortField { target: SortTarget::Score, order: SortOrder::Desc }]
+        } else {
+            self.sort.clone()
+        }
+    }
+
+    /// 第一个排序键是 CatQuery 某个范围的字段时, 把它换成主范围, 让它按这个字段的顺序只收集前 `limit` 篇, 命中数也由它统计
+    fn top_cat_query(&self, limit: usize, sort: &[SortField]) -> Option<CatQuery> {
+        let cat = self.query.downcast_ref::<CatQuery>()?;
+        match sort.first() {
+            Some(&SortField { target: SortTarget::Field(field), order }) if limit > 0 => {
+                let mut cat = cat.clone();
+                if !cat.order_by_range(field) {
+                    return None;
+                }
+                cat.set_limit(limit);
+                cat.set_order(order);
+                cat.set_track_total_hits(match self.track_total_hits {
+                    TrackTotalHits::Disabled => 0,
+                    TrackTotalHits::Exact => usize::MAX,
+                    TrackTotalHits::UpTo(n) => n,
+                });
+                Some(cat)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn execute(&self, searcher: &Searcher) -> tantivy::Result<SearchResponse> {
+        let start = SystemTime::now();
+        let sort = self.sort_fields();
+        let limit = self.from + self.size;
+        let top_cat_query = self.top_cat_query(limit, &sort);
+        let query: &dyn Query = match top_cat_query {
+            Some(ref cat) => cat,
+            None => self.query.as_ref(),
+        };
+        // 只按 CatQuery 的字段排序时, 它给出的文档已经有序, 不用再经过 TopDocs 排序
+        let cat_handler = match top_cat_query {
+            Some(ref cat) if sort.len() == 1 => cat.top_docs(),
+            _ => None,
+        };
+        let mut collectors = MultiCollector::new();
+        let cat_handler = cat_handler.map(|top_docs| collectors.add_collector(top_docs));
+        let top_handler = if limit > 0 && cat_handler.is_none() {
+            let top_docs = TopDocs::with_limit(limit).tweak_score(SortTweaker { sort: sort.clone() });
+            Some(collectors.add_collector(top_docs))
+        } else {
+            None
+        };
+        let count_handler = if top_cat_query.is_none() && self.track_total_hits != TrackTotalHits::Disabled {
+            Some(collectors.add_collector(Count))
+        } else {
+            None
+        };
+        let mut fruits = searcher.search(query, &collectors)?;
+        let top_docs = match (cat_handler, &top_cat_query) {
+            (Some(h), Some(cat)) => h.extract(&mut fruits).into_iter()
+                .map(|(value, address)| {
+                    // CatTopDocs 给出的是 term 里的编码
+                    let value = match cat.value_type() {
+                        Type::I64 => SortValue::I64(u64_to_i64(value)),
+                        Type::F64 => SortValue::F64(u64_to_f64(value)),
+                        _ => SortValue::U64(value),
+                    };
+                    (SortKey(vec![(value, sort[0].order)]), address)
+                })
+                .collect(),
+            _ => top_handler.map(|h| h.extract(&mut fruits)).unwrap_or_default(),
+        };
+        let total = match top_cat_query {
+            Some(ref cat) if self.track_total_hits != TrackTotalHits::Disabled => cat.total_hits(),
+            _ => count_handler.map(|h| {
+                let count = h.extract(&mut fruits);
+                match self.track_total_hits {
+                    TrackTotalHits::UpTo(n) if count > n => (n, false),
+                    _ => (count, true),
+                }
+            }),
+        };
+        let schema = searcher.schema();
+        let score_index = sort.iter().position(|s| s.target == SortTarget::Score);
+        let mut hits = vec![];
+        for (key, address) in top_docs.into_iter().skip(self.from) {
+            let mut values: Vec<SortValue> = key.0.into_iter().map(|(v, _)| v).collect();
+            // tweaker 拿不到 segment 的序号, 这里再把它补到高 32 位
+            for v in values.iter_mut() {
+                if let SortValue::Doc(doc) = v {
+                    *doc |= u64::from(address.segment_ord()) << 32;
+                }
+            }
+            let score = score_index.map(|i| match values[i] {
+                SortValue::Score(s) => s,
+                _ => unreachable!("the score sort key holds a score"),
+            });
+            let source = if self.source.enabled {
+                Some(self.source(schema, &searcher.doc(address)?))
+            } else {
+                None
+            };
+            hits.push(Hit {
+                address,
+                score,
+                // 默认按分数排序时 es 不返回 sort
+                sort: if self.sort.is_empty() { vec![] } else { values },
+                source,
+            });
+        }
+        Ok(SearchResponse {
+            took: SystemTime::now().duration_since(start).unwrap_or_default(),
+            total,
+            hits,
+        })
+    }
+
+    fn source(&self, schema: &Schema, doc: &tantivy::Document) -> Value {
+        let named_doc = schema.to_named_doc(doc);
+        Value::Object(named_doc.0.into_iter()
+            .filter(|(name, _)| self.source.accepts(name))
+            .map(|(name, values)| (name, serde_json::to_value(values).unwrap_or(Value::Null)))
+            .collect())
+    }
+}
@@ -3,7 +3,7 @@ use crate::DocId;
 use crate::Result;
 use crate::SegmentLocalId;
 use crate::SegmentReader;
-use serde::export::PhantomData;
+use std::marker::PhantomData;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 
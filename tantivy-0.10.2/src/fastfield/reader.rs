@@ -50,7 +50,9 @@ impl<Item: FastValue> FastFieldReader<Item> {
         }
     }
 
-    pub(crate) fn into_u64_reader(self) -> FastFieldReader<u64> {
+    /// Returns a reader over the same column that yields the
+    /// order-preserving `u64` representation of the values.
+    pub fn into_u64_reader(self) -> FastFieldReader<u64> {
         FastFieldReader {
             bit_unpacker: self.bit_unpacker,
             min_value_u64: self.min_value_u64,
@@ -0,0 +1,192 @@
+use crate::common::BitSet;
+use crate::core::Searcher;
+use crate::core::SegmentReader;
+use crate::docset::{DocSet, SkipResult};
+use crate::query::{Explanation, Query, Scorer, Weight};
+use crate::DocId;
+use crate::Result;
+use crate::Score;
+use crate::Term;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// `BoostQuery` is a wrapper over a query used to boost its score.
+///
+/// The document set matched by the `BoostQuery` is strictly the same as the underlying query.
+/// The score of each document, is the score of the underlying query multiplied by the `boost`
+/// factor.
+pub struct BoostQuery {
+    query: Box<dyn Query>,
+    boost: Score,
+}
+
+impl BoostQuery {
+    /// Builds a boost query.
+    pub fn new(query: Box<dyn Query>, boost: Score) -> BoostQuery {
+        BoostQuery { query, boost }
+    }
+
+    /// Returns the underlying query.
+    pub fn query(&self) -> &dyn Query {
+        self.query.as_ref()
+    }
+
+    /// Returns the boost factor.
+    pub fn boost(&self) -> Score {
+        self.boost
+    }
+}
+
+impl Clone for BoostQuery {
+    fn clone(&self) -> Self {
+        BoostQuery {
+            query: self.query.box_clone(),
+            boost: self.boost,
+        }
+    }
+}
+
+impl fmt::Debug for BoostQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Boost(query={:?}, boost={})", self.query, self.boost)
+    }
+}
+
+impl Query for BoostQuery {
+    fn weight(&self, searcher: &Searcher, scoring_enabled: bool) -> Result<Box<dyn Weight>> {
+        let weight_without_boost = self.query.weight(searcher, scoring_enabled)?;
+        let boosted_weight = if scoring_enabled {
+            Box::new(BoostWeight::new(weight_without_boost, self.boost))
+        } else {
+            weight_without_boost
+        };
+        Ok(boosted_weight)
+    }
+
+    fn query_terms(&self, term_set: &mut BTreeSet<Term>) {
+        self.query.query_terms(term_set)
+    }
+}
+
+/// Weight associated to the `BoostQuery`.
+pub struct BoostWeight {
+    weight: Box<dyn Weight>,
+    boost: Score,
+}
+
+impl BoostWeight {
+    /// Creates a new boost weight.
+    pub fn new(weight: Box<dyn Weight>, boost: Score) -> Self {
+        BoostWeight { weight, boost }
+    }
+}
+
+impl Weight for BoostWeight {
+    fn scorer(&self, reader: &SegmentReader) -> Result<Box<dyn Scorer>> {
+        self.weight.scorer(reader).map(|scorer| {
+            let boost_scorer: Box<dyn Scorer> = Box::new(BoostScorer::new(scorer, self.boost));
+            boost_scorer
+        })
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> Result<Explanation> {
+        let underlying_explanation = self.weight.explain(reader, doc)?;
+        let score = underlying_explanation.value() * self.boost;
+        let mut explanation = Explanation::new(format!("Boost x{} of ...", self.boost), score);
+        explanation.add_detail(underlying_explanation);
+        Ok(explanation)
+    }
+
+    fn count(&self, reader: &SegmentReader) -> Result<u32> {
+        self.weight.count(reader)
+    }
+}
+
+/// Scorer associated to the `BoostQuery`.
+pub struct BoostScorer<S: Scorer> {
+    underlying: S,
+    boost: Score,
+}
+
+impl<S: Scorer> BoostScorer<S> {
+    /// Creates a new boost scorer.
+    pub fn new(underlying: S, boost: Score) -> BoostScorer<S> {
+        BoostScorer { underlying, boost }
+    }
+}
+
+impl<S: Scorer> DocSet for BoostScorer<S> {
+    fn advance(&mut self) -> bool {
+        self.underlying.advance()
+    }
+
+    fn skip_next(&mut self, target: DocId) -> SkipResult {
+        self.underlying.skip_next(target)
+    }
+
+    fn fill_buffer(&mut self, buffer: &mut [DocId]) -> usize {
+        self.underlying.fill_buffer(buffer)
+    }
+
+    fn doc(&self) -> DocId {
+        self.underlying.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.underlying.size_hint()
+    }
+
+    fn append_to_bitset(&mut self, bitset: &mut BitSet) {
+        self.underlying.append_to_bitset(bitset)
+    }
+
+    fn get_name(&mut self) -> &'static str {
+        "BoostScorer<S>"
+    }
+}
+
+impl<S: Scorer> Scorer for BoostScorer<S> {
+    fn score(&mut self) -> Score {
+        self.underlying.score() * self.boost
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoostQuery;
+    use crate::query::{AllQuery, Query};
+    use crate::schema::Schema;
+    use crate::{DocAddress, Document, Index};
+
+    #[test]
+    fn test_boost_query_explain() {
+        let schema = Schema::builder().build();
+        let index = Index::create_in_ram(schema);
+        let mut index_writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        index_writer.add_document(Document::new());
+        assert!(index_writer.commit().is_ok());
+        let reader = index.reader().unwrap();
+        let query = BoostQuery::new(Box::new(AllQuery), 0.2);
+        let explanation = query.explain(&reader.searcher(), DocAddress(0, 0u32)).unwrap();
+        assert_eq!(
+            explanation.to_pretty_json(),
+            "{\n  \"value\": 0.2,\n  \"description\": \"Boost x0.2 of ...\",\n  \"details\": [\n    {\n      \"value\": 1.0,\n      \"description\": \"AllQuery\"\n    }\n  ]\n}"
+        )
+    }
+
+    #[test]
+    fn test_boost_query_score() {
+        let schema = Schema::builder().build();
+        let index = Index::create_in_ram(schema);
+        let mut index_writer = index.writer_with_num_threads(1, 3_000_000).unwrap();
+        index_writer.add_document(Document::new());
+        assert!(index_writer.commit().is_ok());
+        let searcher = index.reader().unwrap().searcher();
+        let query = BoostQuery::new(Box::new(AllQuery), 3.0);
+        let top_docs = searcher
+            .search(&query, &crate::collector::TopDocs::with_limit(1))
+            .unwrap();
+        assert_eq!(top_docs[0].0, 3.0);
+        assert_eq!(query.count(&searcher).unwrap(), 1);
+    }
+}
@@ -99,6 +99,26 @@ impl FuzzyTermQuery {
         }
     }
 
+    /// Returns the term the query is built around.
+    pub fn term(&self) -> &Term {
+        &self.term
+    }
+
+    /// Returns the maximum Levenshtein distance.
+    pub fn distance(&self) -> u8 {
+        self.distance
+    }
+
+    /// Returns true if a transposition costs one edit rather than two.
+    pub fn transposition_cost_one(&self) -> bool {
+        self.transposition_cost_one
+    }
+
+    /// Returns true if the term is matched as a prefix.
+    pub fn is_prefix(&self) -> bool {
+        self.prefix
+    }
+
     fn specialized_weight(&self) -> Result<AutomatonWeight<DFA>> {
         let automaton = LEV_BUILDER.get(&(self.distance, false))
             .unwrap() // TODO return an error
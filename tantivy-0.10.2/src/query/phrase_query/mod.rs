@@ -68,6 +68,39 @@ mod tests {
         assert!(test_query(vec!["g", "a"]).is_empty());
     }
 
+    #[test]
+    pub fn test_phrase_query_slop() {
+        let index = create_index(&["a b c", "a x b c", "a x y z b c", "b a c"]);
+        let schema = index.schema();
+        let text_field = schema.get_field("text").unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let test_query = |texts: Vec<&str>, slop: u32| {
+            let terms: Vec<Term> = texts
+                .iter()
+                .map(|text| Term::from_field_text(text_field, text))
+                .collect();
+            let mut phrase_query = PhraseQuery::new(terms);
+            phrase_query.set_slop(slop);
+            let test_fruits = searcher
+                .search(&phrase_query, &TEST_COLLECTOR_WITH_SCORE)
+                .expect("search should succeed");
+            test_fruits
+                .docs()
+                .iter()
+                .map(|docaddr| docaddr.1)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(test_query(vec!["a", "b", "c"], 0), vec![0]);
+        assert_eq!(test_query(vec!["a", "b", "c"], 1), vec![0, 1]);
+        assert_eq!(test_query(vec!["a", "b", "c"], 2), vec![0, 1, 3]);
+        assert_eq!(test_query(vec!["a", "b", "c"], 3), vec![0, 1, 2, 3]);
+        assert_eq!(test_query(vec!["a", "c"], 1), vec![0, 3]);
+        assert!(test_query(vec!["c", "a"], 1).is_empty());
+        assert_eq!(test_query(vec!["c", "a"], 3), vec![0, 3]);
+        assert_eq!(test_query(vec!["x", "b"], 1), vec![1]);
+        assert_eq!(test_query(vec!["x", "b"], 2), vec![1, 2]);
+    }
+
     #[test]
     pub fn test_phrase_query_no_score() {
         let index = create_index(&[
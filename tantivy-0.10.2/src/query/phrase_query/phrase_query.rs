@@ -27,6 +27,7 @@ use std::collections::BTreeSet;
 pub struct PhraseQuery {
     field: Field,
     phrase_terms: Vec<(usize, Term)>,
+    slop: u32,
 }
 
 impl PhraseQuery {
@@ -57,9 +58,24 @@ impl PhraseQuery {
         PhraseQuery {
             field,
             phrase_terms: terms,
+            slop: 0,
         }
     }
 
+    /// Slop allowed for the phrase.
+    ///
+    /// As in Lucene, the query will match if its terms can be moved into the phrase
+    /// with at most `slop` position moves in total. Transposing two terms costs 2.
+    /// By default the slop is 0 meaning query terms need to be adjacent.
+    pub fn set_slop(&mut self, value: u32) {
+        self.slop = value;
+    }
+
+    /// Slop allowed for the phrase.
+    pub fn slop(&self) -> u32 {
+        self.slop
+    }
+
     /// The `Field` this `PhraseQuery` is targeting.
     pub fn field(&self) -> Field {
         self.field
@@ -73,6 +89,11 @@ impl PhraseQuery {
             .collect::<Vec<Term>>()
     }
 
+    /// `Term`s in the phrase with their offsets, sorted by offset.
+    pub fn phrase_terms_with_offsets(&self) -> &[(usize, Term)] {
+        &self.phrase_terms
+    }
+
     /// Returns the `PhraseWeight` for the given phrase query given a specific `searcher`.  
     ///
     /// This function is the same as `.weight(...)` except it returns
@@ -98,11 +119,11 @@ impl PhraseQuery {
         }
         let terms = self.phrase_terms();
         let bm25_weight = BM25Weight::for_terms(searcher, &terms);
-        Ok(PhraseWeight::new(
-            self.phrase_terms.clone(),
-            bm25_weight,
-            scoring_enabled,
-        ))
+        let mut weight = PhraseWeight::new(self.phrase_terms.clone(), bm25_weight, scoring_enabled);
+        if self.slop > 0 {
+            weight.slop(self.slop);
+        }
+        Ok(weight)
     }
 }
 
@@ -54,6 +54,11 @@ pub struct PhraseScorer<TPostings: Postings> {
     fieldnorm_reader: FieldNormReader,
     similarity_weight: BM25Weight,
     score_needed: bool,
+    slop: u32,
+    // Offset added to the positions of each docset of `intersection_docset`.
+    offsets: Vec<u32>,
+    // Positions of each docset, used when `slop > 0`.
+    sloppy_positions: Vec<Vec<u32>>,
 }
 
 /// Returns true iff the two sorted array contain a common element
@@ -121,12 +126,56 @@ fn intersection(left: &mut [u32], right: &[u32]) -> usize {
     count
 }
 
+/// Counts the sloppy matches of a phrase, the same way Lucene's sloppy phrase matcher does.
+///
+/// `positions[i]` holds the sorted positions of the i-th term of the phrase, shifted by
+/// `offsets[i]` so that the terms of an exact match all land on the same value.
+/// A match picks one position for each term, and its distance is the spread of these
+/// shifted positions: the total number of moves needed to turn it into the exact phrase.
+/// Moving a term past its neighbour costs 2, so a transposition needs a slop of 2.
+///
+/// Two terms of a match cannot be found at the same position of the document.
+fn sloppy_phrase_count(positions: &[Vec<u32>], offsets: &[u32], slop: u32) -> u32 {
+    if positions.iter().any(Vec::is_empty) {
+        return 0;
+    }
+    let mut cursors = vec![0usize; positions.len()];
+    let mut count = 0;
+    loop {
+        let mut first = 0;
+        let mut end = 0;
+        for (term, &cursor) in cursors.iter().enumerate() {
+            let position = positions[term][cursor];
+            if position < positions[first][cursors[first]] {
+                first = term;
+            }
+            end = end.max(position);
+        }
+        let start = positions[first][cursors[first]];
+        if end - start <= slop && distinct_positions(positions, offsets, &cursors) {
+            count += 1;
+        }
+        // Moving the first term is the only way to get a tighter match.
+        cursors[first] += 1;
+        if cursors[first] == positions[first].len() {
+            return count;
+        }
+    }
+}
+
+/// Returns true iff the positions picked by `cursors` are at distinct positions in the document.
+fn distinct_positions(positions: &[Vec<u32>], offsets: &[u32], cursors: &[usize]) -> bool {
+    let position = |term: usize| positions[term][cursors[term]] - offsets[term];
+    (0..cursors.len()).all(|i| (i + 1..cursors.len()).all(|j| position(i) != position(j)))
+}
+
 impl<TPostings: Postings> PhraseScorer<TPostings> {
     pub fn new(
         term_postings: Vec<(usize, TPostings)>,
         similarity_weight: BM25Weight,
         fieldnorm_reader: FieldNormReader,
         score_needed: bool,
+        slop: u32,
     ) -> PhraseScorer<TPostings> {
         let max_offset = term_postings
             .iter()
@@ -140,8 +189,12 @@ impl<TPostings: Postings> PhraseScorer<TPostings> {
                 PostingsWithOffset::new(postings, (max_offset - offset) as u32)
             })
             .collect::<Vec<_>>();
+        let mut intersection_docset = Intersection::new(postings_with_offsets);
+        let offsets = (0..num_docsets)
+            .map(|i| intersection_docset.docset_mut_specialized(i).offset)
+            .collect();
         PhraseScorer {
-            intersection_docset: Intersection::new(postings_with_offsets),
+            intersection_docset,
             num_terms: num_docsets,
             left: Vec::with_capacity(100),
             right: Vec::with_capacity(100),
@@ -149,6 +202,9 @@ impl<TPostings: Postings> PhraseScorer<TPostings> {
             similarity_weight,
             fieldnorm_reader,
             score_needed,
+            slop,
+            offsets,
+            sloppy_positions: vec![Vec::with_capacity(100); num_docsets],
         }
     }
 
@@ -157,6 +213,11 @@ impl<TPostings: Postings> PhraseScorer<TPostings> {
     }
 
     fn phrase_match(&mut self) -> bool {
+        if self.slop > 0 {
+            let count = self.compute_sloppy_phrase_count();
+            self.phrase_count = count;
+            return count > 0u32;
+        }
         if self.score_needed {
             let count = self.compute_phrase_count();
             self.phrase_count = count;
@@ -215,6 +276,17 @@ impl<TPostings: Postings> PhraseScorer<TPostings> {
     }
 }
 
+impl<TPostings: Postings> PhraseScorer<TPostings> {
+    fn compute_sloppy_phrase_count(&mut self) -> u32 {
+        for (i, positions) in self.sloppy_positions.iter_mut().enumerate() {
+            self.intersection_docset
+                .docset_mut_specialized(i)
+                .positions(positions);
+        }
+        sloppy_phrase_count(&self.sloppy_positions, &self.offsets, self.slop)
+    }
+}
+
 impl<TPostings: Postings> DocSet for PhraseScorer<TPostings> {
     fn advance(&mut self) -> bool {
         while self.intersection_docset.advance() {
@@ -268,7 +340,7 @@ impl<TPostings: Postings> Scorer for PhraseScorer<TPostings> {
 #[cfg(test)]
 mod tests {
 
-    use super::{intersection, intersection_count};
+    use super::{intersection, intersection_count, sloppy_phrase_count};
 
     fn test_intersection_sym(left: &[u32], right: &[u32], expected: &[u32]) {
         test_intersection_aux(left, right, expected);
@@ -291,6 +363,41 @@ mod tests {
         test_intersection_sym(&[5, 7], &[1, 5, 10, 12], &[5]);
         test_intersection_sym(&[1, 5, 6, 9, 10, 12], &[6, 8, 9, 12], &[6, 9, 12]);
     }
+
+    fn test_sloppy_aux(positions: &[&[u32]], slop: u32, expected: u32) {
+        // shift the positions of the i-th term as the scorer does
+        let max_offset = positions.len() as u32 - 1;
+        let offsets: Vec<u32> = (0..positions.len() as u32).map(|i| max_offset - i).collect();
+        let positions: Vec<Vec<u32>> = positions
+            .iter()
+            .zip(&offsets)
+            .map(|(positions, offset)| positions.iter().map(|p| p + offset).collect())
+            .collect();
+        assert_eq!(sloppy_phrase_count(&positions, &offsets, slop), expected);
+    }
+
+    #[test]
+    fn test_sloppy_phrase_count() {
+        // "a b" in "a b"
+        test_sloppy_aux(&[&[0], &[1]], 0, 1);
+        // "a b" in "a x b"
+        test_sloppy_aux(&[&[0], &[2]], 0, 0);
+        test_sloppy_aux(&[&[0], &[2]], 1, 1);
+        // "a b c" in "a x b x c": the gaps add up
+        test_sloppy_aux(&[&[0], &[2], &[4]], 1, 0);
+        test_sloppy_aux(&[&[0], &[2], &[4]], 2, 1);
+        // "a b" in "b a": a transposition costs 2
+        test_sloppy_aux(&[&[1], &[0]], 1, 0);
+        test_sloppy_aux(&[&[1], &[0]], 2, 1);
+        // "a b c" in "c b a"
+        test_sloppy_aux(&[&[2], &[1], &[0]], 3, 0);
+        test_sloppy_aux(&[&[2], &[1], &[0]], 4, 1);
+        // "a b" in "a b x a b"
+        test_sloppy_aux(&[&[0, 3], &[1, 4]], 1, 2);
+        // "a a" in "a" and in "a x a"
+        test_sloppy_aux(&[&[0], &[0]], 3, 0);
+        test_sloppy_aux(&[&[0, 2], &[0, 2]], 1, 1);
+    }
 }
 
 #[cfg(all(test, feature = "unstable"))]
@@ -16,6 +16,7 @@ pub struct PhraseWeight {
     phrase_terms: Vec<(usize, Term)>,
     similarity_weight: BM25Weight,
     score_needed: bool,
+    slop: u32,
 }
 
 impl PhraseWeight {
@@ -29,9 +30,15 @@ impl PhraseWeight {
             phrase_terms,
             similarity_weight,
             score_needed,
+            slop: 0,
         }
     }
 
+    /// Sets the slop allowed between the terms of the phrase.
+    pub fn slop(&mut self, slop: u32) {
+        self.slop = slop;
+    }
+
     fn fieldnorm_reader(&self, reader: &SegmentReader) -> FieldNormReader {
         let field = self.phrase_terms[0].1.field();
         reader.get_fieldnorms_reader(field)
@@ -60,6 +67,7 @@ impl PhraseWeight {
                 similarity_weight,
                 fieldnorm_reader,
                 self.score_needed,
+                self.slop,
             )))
         } else {
             let mut term_postings_list = Vec::new();
@@ -78,6 +86,7 @@ impl PhraseWeight {
                 similarity_weight,
                 fieldnorm_reader,
                 self.score_needed,
+                self.slop,
             )))
         }
     }
@@ -81,6 +81,11 @@ impl TermQuery {
         &self.term
     }
 
+    /// The `IndexRecordOption` the postings are read with.
+    pub fn index_record_option(&self) -> IndexRecordOption {
+        self.index_record_option
+    }
+
     /// Returns a weight object.
     ///
     /// While `.weight(...)` returns a boxed trait object,